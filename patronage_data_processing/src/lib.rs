@@ -0,0 +1,44 @@
+use std::time::{Duration, Instant};
+use duckdb::{Connection, Result};
+
+pub struct ConversionTimings {
+    pub execute: Duration,
+    pub export: Duration,
+}
+
+// Converts the raw PTV passenger count CSV to parquet, keeping only rows for the given mode (e.g. "Metro") that have
+// boardings or alightings. If date is given (YYYY-MM-DD), only that business date is kept.
+pub fn convert(csv_filepath: &str, output_path: &str, mode: &str, date: Option<&str>) -> Result<ConversionTimings> {
+    let conn = Connection::open_in_memory()?;
+
+    let execute_start = Instant::now();
+
+    // Grab CSV and extract relevant columns and rows.
+    let date_filter = if date.is_some() { "AND Business_Date = CAST(? AS DATE)" } else { "" };
+    let sql = format!(r#"
+        CREATE TABLE tbl AS 
+        SELECT Business_Date, Station_Name, Arrival_Time_Scheduled, Departure_Time_Scheduled, Passenger_Boardings, Passenger_Alightings 
+        FROM read_csv(?, types={{
+            'Passenger_Boardings': 'UInt16',
+            'Passenger_Alightings': 'UInt16'
+        }})
+        WHERE Mode = ? AND (Passenger_Boardings != 0 OR Passenger_Alightings != 0) {date_filter};
+    "#);
+    match date {
+        Some(date) => conn.execute(&sql, [csv_filepath, mode, date])?,
+        None => conn.execute(&sql, [csv_filepath, mode])?,
+    };
+
+    let execute_end = Instant::now();
+
+    // Export to parquet (couldn't get SQL param to work with output path).
+    let output_path = output_path.replace('\'', "''");
+    conn.execute(&format!("COPY tbl TO \'{output_path}\' (FORMAT PARQUET);"), [])?;
+
+    let export_end = Instant::now();
+
+    Ok(ConversionTimings {
+        execute: execute_end - execute_start,
+        export: export_end - execute_end,
+    })
+}
@@ -1,49 +1,89 @@
+use std::io::IsTerminal;
 use std::path::Path;
-use std::time::Instant;
-use duckdb::{Connection, Result};
+use duckdb::Result;
+
+use patronage_data_processing::convert;
+
+const USAGE: &str = "Usage: patronage_data_processing [--input <csv>] [--output <parquet>] [--mode <mode>] [--date <YYYY-MM-DD>]";
+
+struct Args {
+    input: Option<String>,
+    output: Option<String>,
+    mode: String,
+    date: Option<String>,
+}
+
+fn parse_args() -> Args {
+    let mut args = Args { input: None, output: None, mode: "Metro".to_owned(), date: None };
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        let mut value = || argv.next().unwrap_or_else(|| {
+            eprintln!("Missing value for {arg}\n{USAGE}");
+            std::process::exit(2);
+        });
+        match arg.as_str() {
+            "--input" => args.input = Some(value()),
+            "--output" => args.output = Some(value()),
+            "--mode" => args.mode = value(),
+            "--date" => args.date = Some(value()),
+            "--help" | "-h" => {
+                println!("{USAGE}");
+                std::process::exit(0);
+            }
+            _ => {
+                eprintln!("Unknown argument {arg}\n{USAGE}");
+                std::process::exit(2);
+            }
+        }
+    }
+    args
+}
 
 fn main() -> Result<()> {
-    let csv_filepath = loop {
-        println!("Input data file path:");
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input).unwrap();
-        input = input.replace("\"", "").trim().to_owned();
-        
-        if Path::new(&input).exists() {
-            break input;
-        } else {
-            println!("File does not exist. Please try again.");
+    let args = parse_args();
+
+    // Fall back to asking for the input file if it wasn't given on the command line. There's no one to ask in a
+    // pipeline, so give the usage instead.
+    if args.input.is_none() && !std::io::stdin().is_terminal() {
+        eprintln!("Missing --input\n{USAGE}");
+        std::process::exit(2);
+    }
+    let csv_filepath = match args.input {
+        Some(input) => {
+            if !Path::new(&input).exists() {
+                eprintln!("Input file {input} does not exist.");
+                std::process::exit(1);
+            }
+            input
         }
+        None => loop {
+            println!("Input data file path:");
+            let mut input = String::new();
+            // Stop asking at the end of input (e.g. Ctrl-D).
+            if !matches!(std::io::stdin().read_line(&mut input), Ok(read) if read > 0) {
+                eprintln!("\n{USAGE}");
+                std::process::exit(2);
+            }
+            input = input.replace("\"", "").trim().to_owned();
+
+            if Path::new(&input).exists() {
+                break input;
+            } else {
+                println!("File does not exist. Please try again.");
+            }
+        },
     };
-    
-    let mut output_path = Path::new(&csv_filepath).to_path_buf();
-    output_path.set_extension("parquet");
-    let output_path = format!("{}", output_path.to_str().unwrap());
+
+    let output_path = args.output.unwrap_or_else(|| {
+        let mut output_path = Path::new(&csv_filepath).to_path_buf();
+        output_path.set_extension("parquet");
+        format!("{}", output_path.to_str().unwrap())
+    });
     println!("\nOutput: {output_path}");
-    
-    let conn = Connection::open_in_memory()?;
-
-    let execute_start = Instant::now();
-    
-    // Grab CSV and extract relevant columns and rows.
-    conn.execute(r#"
-        CREATE TABLE tbl AS 
-        SELECT Business_Date, Station_Name, Arrival_Time_Scheduled, Departure_Time_Scheduled, Passenger_Boardings, Passenger_Alightings 
-        FROM read_csv(?, types={
-            'Passenger_Boardings': 'UInt16',
-            'Passenger_Alightings': 'UInt16'
-        })
-        WHERE Mode = 'Metro' AND (Passenger_Boardings != 0 OR Passenger_Alightings != 0);
-    "#, [csv_filepath])?;
-
-    let execute_end = Instant::now();
-    
-    // Export to parquet (couldn't get SQL param to work with output path).
-    conn.execute(&format!("COPY tbl TO \'{output_path}\' (FORMAT PARQUET);"), [])?;
-
-    let export_end = Instant::now();
-
-    println!("Execute: {:?}, Export: {:?}", execute_end - execute_start, export_end - execute_end);
-    
+
+    let timings = convert(&csv_filepath, &output_path, &args.mode, args.date.as_deref())?;
+
+    println!("Execute: {:?}, Export: {:?}", timings.execute, timings.export);
+
     Ok(())
 }
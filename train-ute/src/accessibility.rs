@@ -0,0 +1,74 @@
+use std::fs::File;
+use std::io::BufWriter;
+
+use rayon::prelude::*;
+use serde_json::json;
+
+use raptor::{Network, raptor_query};
+use raptor::network::{StopIndex, Timestamp};
+use raptor::utils::get_time_str;
+
+use crate::data_export::DataExportError;
+use crate::simulation::{crowding_costs, CrowdingCost, SimulationParams, SimulationResult};
+
+// The earliest arrival time at every stop in the network from a single origin.
+pub struct Accessibility {
+    pub origin: StopIndex,
+    pub departure_time: Timestamp,
+    // Indexed by stop, None if the stop can't be reached.
+    pub arrival_times: Vec<Option<Timestamp>>,
+}
+
+// Finds the earliest arrival at every stop from the origin stop. If a simulation result is given, route choice takes
+// crowding into account.
+pub fn calculate_accessibility<T: SimulationParams>(network: &Network, origin: StopIndex, departure_time: Timestamp, simulation_result: Option<&SimulationResult>, params: &T) -> Accessibility {
+    let trip_stops_cost = match simulation_result {
+        Some(simulation_result) => crowding_costs(network, simulation_result, params),
+        None => vec![0 as CrowdingCost; network.stop_times.len()],
+    };
+
+    // raptor only has point-to-point queries, so this runs one query per destination (in parallel). A one-to-all
+    // RAPTOR pass would label every stop at once, but would mean reimplementing the rounds here.
+    let arrival_times = (0..network.num_stops() as StopIndex).into_par_iter().map(|destination| {
+        if destination == origin {
+            return Some(departure_time);
+        }
+        let query = raptor_query(network, origin, departure_time, destination, &trip_stops_cost);
+        // The arrival time is the arrival of the final leg.
+        query.legs.last().map(|leg| network.get_arrival_time(leg.route_idx as usize, leg.trip_idx as usize, leg.arrival_stop_order as usize))
+    }).collect();
+
+    Accessibility { origin, departure_time, arrival_times }
+}
+
+// Exports the arrival and travel time to each stop as a csv file.
+pub fn export_accessibility_csv(path: &str, network: &Network, accessibility: &Accessibility) -> Result<(), DataExportError> {
+    let mut csv_writer = csv::Writer::from_path(path)?;
+    csv_writer.write_record(["stop", "latitude", "longitude", "arrival_time", "travel_time_mins"])?;
+    for (stop_idx, arrival_time) in accessibility.arrival_times.iter().enumerate() {
+        let point = network.stop_points[stop_idx];
+        let (arrival_time, travel_time) = match arrival_time {
+            Some(arrival_time) => (get_time_str(*arrival_time), ((arrival_time - accessibility.departure_time) as f32 / 60.).to_string()),
+            None => (String::new(), String::new()),
+        };
+        csv_writer.write_record([&*network.stops[stop_idx].name, &point.latitude.to_string(), &point.longitude.to_string(), &arrival_time, &travel_time])?;
+    }
+
+    Ok(())
+}
+
+// Exports the travel time to each stop as a GeoJSON point feature collection, for mapping.
+pub fn export_accessibility_geojson(path: &str, network: &Network, accessibility: &Accessibility) -> Result<(), DataExportError> {
+    let features = accessibility.arrival_times.iter().enumerate().map(|(stop_idx, arrival_time)| {
+        let point = network.stop_points[stop_idx];
+        let travel_time = arrival_time.map(|arrival_time| (arrival_time - accessibility.departure_time) as f32 / 60.);
+        json!({
+            "type": "Feature",
+            "geometry": { "type": "Point", "coordinates": [point.longitude, point.latitude] },
+            "properties": { "stop": &*network.stops[stop_idx].name, "origin": stop_idx == accessibility.origin as usize, "travel_time_mins": travel_time },
+        })
+    }).collect::<Vec<_>>();
+    serde_json::to_writer(BufWriter::new(File::create(path)?), &json!({ "type": "FeatureCollection", "features": features }))?;
+
+    Ok(())
+}
@@ -0,0 +1,155 @@
+use gtfs_structures::Gtfs;
+
+use raptor::Network;
+
+use crate::simulation::AgentCount;
+use crate::utils::glob_match;
+
+// What a row of the trip capacities CSV applies to.
+#[derive(Clone, Debug)]
+pub enum CapacityMatcher {
+    TripId(String),
+    // Glob over trip ids, where * matches any run of characters and ? matches a single character.
+    TripPattern(String),
+    RouteId(String),
+    // GTFS route short name.
+    RouteName(String),
+}
+
+impl CapacityMatcher {
+    // When several rules match a trip, the most specific one is used.
+    fn precedence(&self) -> u8 {
+        match self {
+            CapacityMatcher::TripId(_) => 3,
+            CapacityMatcher::TripPattern(_) => 2,
+            CapacityMatcher::RouteId(_) => 1,
+            CapacityMatcher::RouteName(_) => 0,
+        }
+    }
+
+    fn matches(&self, trip_id: &str, route_id: Option<&str>, route_name: Option<&str>) -> bool {
+        match self {
+            CapacityMatcher::TripId(id) => id == trip_id,
+            CapacityMatcher::TripPattern(pattern) => glob_match(pattern, trip_id),
+            CapacityMatcher::RouteId(id) => route_id == Some(id.as_str()),
+            CapacityMatcher::RouteName(name) => route_name == Some(name.as_str()),
+        }
+    }
+}
+
+pub struct CapacityRule {
+    pub matcher: CapacityMatcher,
+    pub capacity: AgentCount,
+    // Row in the source CSV (1-based, excluding the header), for tracing where a capacity came from.
+    pub source_row: usize,
+}
+
+// Capacity rules, resolved against a network when a simulation is set up.
+#[derive(Default)]
+pub struct TripCapacities {
+    pub rules: Vec<CapacityRule>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ResolvedCapacity {
+    pub capacity: AgentCount,
+    // Index of the rule that set this capacity, or None if the default was used.
+    pub rule_idx: Option<usize>,
+}
+
+// The capacity of every trip in a network.
+pub struct ResolvedTripCapacities {
+    // Indexed by route, then trip.
+    trips: Vec<Vec<ResolvedCapacity>>,
+}
+
+impl ResolvedTripCapacities {
+    pub fn get(&self, route_idx: usize, trip_idx: usize) -> ResolvedCapacity {
+        self.trips[route_idx][trip_idx]
+    }
+}
+
+impl TripCapacities {
+    // Works out the capacity of every trip. Trips no rule matches get the default capacity.
+    // Later rules take precedence over earlier rules of the same specificity.
+    pub fn resolve(&self, network: &Network, gtfs: &Gtfs, default: AgentCount) -> ResolvedTripCapacities {
+        let trips = network.routes.iter().map(|route| {
+            (0..route.num_trips as usize).map(|trip| {
+                let trip_id: &str = &route.trip_ids[trip];
+                let route_id = gtfs.trips.get(trip_id).map(|gtfs_trip| gtfs_trip.route_id.as_str());
+                let route_name = route_id.and_then(|route_id| gtfs.routes.get(route_id)).and_then(|gtfs_route| gtfs_route.short_name.as_deref());
+
+                self.rules.iter().enumerate()
+                    .filter(|(_, rule)| rule.matcher.matches(trip_id, route_id, route_name))
+                    .max_by_key(|&(rule_idx, rule)| (rule.matcher.precedence(), rule_idx))
+                    .map_or(ResolvedCapacity { capacity: default, rule_idx: None }, |(rule_idx, rule)| ResolvedCapacity { capacity: rule.capacity, rule_idx: Some(rule_idx) })
+            }).collect()
+        }).collect();
+
+        ResolvedTripCapacities { trips }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{test_network, trip};
+
+    fn rule(matcher: CapacityMatcher, capacity: AgentCount) -> CapacityRule {
+        CapacityRule { matcher, capacity, source_row: 0 }
+    }
+
+    #[test]
+    fn most_specific_rule_wins() {
+        let (gtfs, network) = test_network();
+        let trip_capacities = TripCapacities { rules: vec![
+            rule(CapacityMatcher::RouteName("A".to_owned()), 100),
+            rule(CapacityMatcher::TripId("A1".to_owned()), 400),
+            rule(CapacityMatcher::TripPattern("A*".to_owned()), 300),
+            rule(CapacityMatcher::RouteId("A".to_owned()), 200),
+            rule(CapacityMatcher::RouteName("B".to_owned()), 500),
+        ] };
+        let resolved = trip_capacities.resolve(&network, &gtfs, 50);
+        let get = |trip_id| {
+            let (route_idx, trip_idx) = trip(&network, trip_id);
+            resolved.get(route_idx, trip_idx)
+        };
+        assert_eq!((get("A1").capacity, get("A1").rule_idx), (400, Some(1)));
+        assert_eq!((get("A2").capacity, get("A2").rule_idx), (300, Some(2)));
+        assert_eq!((get("B1").capacity, get("B1").rule_idx), (500, Some(4)));
+
+        // Route id beats route name.
+        let trip_capacities = TripCapacities { rules: vec![
+            rule(CapacityMatcher::RouteId("A".to_owned()), 200),
+            rule(CapacityMatcher::RouteName("A".to_owned()), 100),
+        ] };
+        let resolved = trip_capacities.resolve(&network, &gtfs, 50);
+        let (route_idx, trip_idx) = trip(&network, "A1");
+        assert_eq!(resolved.get(route_idx, trip_idx).capacity, 200);
+    }
+
+    #[test]
+    fn later_rule_wins_ties() {
+        let (gtfs, network) = test_network();
+        let trip_capacities = TripCapacities { rules: vec![
+            rule(CapacityMatcher::TripPattern("A*".to_owned()), 300),
+            rule(CapacityMatcher::TripPattern("A?".to_owned()), 350),
+            rule(CapacityMatcher::RouteName("B".to_owned()), 500),
+            rule(CapacityMatcher::RouteName("B".to_owned()), 600),
+        ] };
+        let resolved = trip_capacities.resolve(&network, &gtfs, 50);
+        let (route_idx, trip_idx) = trip(&network, "A2");
+        assert_eq!((resolved.get(route_idx, trip_idx).capacity, resolved.get(route_idx, trip_idx).rule_idx), (350, Some(1)));
+        let (route_idx, trip_idx) = trip(&network, "B1");
+        assert_eq!((resolved.get(route_idx, trip_idx).capacity, resolved.get(route_idx, trip_idx).rule_idx), (600, Some(3)));
+    }
+
+    #[test]
+    fn unmatched_trips_get_the_default() {
+        let (gtfs, network) = test_network();
+        let trip_capacities = TripCapacities { rules: vec![rule(CapacityMatcher::TripId("X1".to_owned()), 400)] };
+        let resolved = trip_capacities.resolve(&network, &gtfs, 50);
+        let (route_idx, trip_idx) = trip(&network, "A1");
+        assert_eq!((resolved.get(route_idx, trip_idx).capacity, resolved.get(route_idx, trip_idx).rule_idx), (50, None));
+    }
+}
@@ -0,0 +1,42 @@
+use std::path::Path;
+
+use object_store::{parse_url_opts, ObjectStore};
+use thiserror::Error;
+use url::Url;
+
+#[derive(Error, Debug)]
+pub enum CloudExportError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid URL: {0}")]
+    Url(#[from] url::ParseError),
+    #[error("Object store error: {0}")]
+    ObjectStore(#[from] object_store::Error),
+}
+
+// Credentials and settings for the object store are read from the usual environment variables (e.g.
+// AWS_ACCESS_KEY_ID, GOOGLE_SERVICE_ACCOUNT, AZURE_STORAGE_ACCOUNT_NAME).
+fn store_options() -> Vec<(String, String)> {
+    std::env::vars()
+        .filter(|(key, _)| key.starts_with("AWS_") || key.starts_with("GOOGLE_") || key.starts_with("AZURE_"))
+        .map(|(key, value)| (key.to_ascii_lowercase(), value))
+        .collect()
+}
+
+// Uploads local export files to an s3://, gs:// or az:// URI, keeping their file names, so results can go straight
+// to cloud storage. E.g. "../data/counts.parquet" with base "s3://bucket/run-1" goes to s3://bucket/run-1/counts.parquet.
+pub fn upload_exports(paths: &[String], base_url: &str) -> Result<(), CloudExportError> {
+    let (store, base_path) = parse_url_opts(&Url::parse(base_url)?, store_options())?;
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+
+    for path in paths {
+        let file_name = Path::new(path).file_name().map_or_else(|| path.clone(), |name| name.to_string_lossy().into_owned());
+        let object_path = base_path.child(file_name.as_str());
+        let data = std::fs::read(path)?;
+        runtime.block_on(store.put(&object_path, data.into()))?;
+        // The object path already includes the base URL's path, so the URL is rebuilt from the base and file name.
+        println!("Uploaded {path} to {}/{file_name}", base_url.trim_end_matches('/'));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use raptor::Network;
+
+use crate::data_export::DataExportError;
+use crate::simulation::{query_journey_legs, AgentJourney};
+
+// GEH above this is conventionally a poor match between modelled and observed counts.
+pub const GEH_THRESHOLD: f64 = 5.;
+
+// An observed boarding count for a trip at one of its stops.
+pub struct ObservedBoarding {
+    pub route_idx: usize,
+    pub trip_idx: usize,
+    pub stop_order: usize,
+    pub count: u32,
+}
+
+pub struct BoardingComparison {
+    pub route_idx: usize,
+    pub trip_idx: usize,
+    pub stop_order: usize,
+    pub observed: u32,
+    pub simulated: u32,
+    pub geh: f64,
+}
+
+// The GEH statistic, sqrt(2 (M - C)^2 / (M + C)), which is like a chi-squared test that tolerates larger absolute
+// differences on larger counts.
+pub fn geh(simulated: f64, observed: f64) -> f64 {
+    if simulated + observed == 0. {
+        return 0.;
+    }
+    (2. * (simulated - observed).powi(2) / (simulated + observed)).sqrt()
+}
+
+// Agents boarding at each trip stop, indexed like network.stop_times.
+pub fn simulated_boardings(network: &Network, simulation_steps: &[AgentJourney]) -> Vec<u32> {
+    let journey_legs = query_journey_legs(network, simulation_steps, None);
+
+    let mut boardings = vec![0u32; network.stop_times.len()];
+    for (journey, legs) in simulation_steps.iter().zip(journey_legs.iter()) {
+        for leg in legs {
+            let trip_range = network.routes[leg.route_idx].get_trip_range(leg.trip_idx);
+            boardings[trip_range.start + leg.boarded_stop_order] += journey.count as u32;
+        }
+    }
+    boardings
+}
+
+// Compares simulated boardings against observed counts, worst match (highest GEH) first, as a calibration worklist.
+pub fn compare_boardings(network: &Network, simulation_steps: &[AgentJourney], observed: &[ObservedBoarding]) -> Vec<BoardingComparison> {
+    let boardings = simulated_boardings(network, simulation_steps);
+
+    let mut comparisons = observed.iter().map(|observation| {
+        let trip_range = network.routes[observation.route_idx].get_trip_range(observation.trip_idx);
+        let simulated = boardings[trip_range.start + observation.stop_order];
+        BoardingComparison {
+            route_idx: observation.route_idx,
+            trip_idx: observation.trip_idx,
+            stop_order: observation.stop_order,
+            observed: observation.count,
+            simulated,
+            geh: geh(simulated as f64, observation.count as f64),
+        }
+    }).collect::<Vec<_>>();
+    comparisons.sort_by(|a, b| b.geh.total_cmp(&a.geh));
+    comparisons
+}
+
+// Prints the share of observations matched within the GEH threshold.
+pub fn print_comparison_summary(comparisons: &[BoardingComparison]) {
+    let num_within = comparisons.iter().filter(|comparison| comparison.geh <= GEH_THRESHOLD).count();
+    let percent_within = if comparisons.is_empty() { 100. } else { num_within as f64 / comparisons.len() as f64 * 100. };
+    println!("{num_within} of {} observed boardings within GEH {GEH_THRESHOLD} ({percent_within:.1}%).", comparisons.len());
+}
+
+pub fn export_boardings_comparison(path: &str, network: &Network, comparisons: &[BoardingComparison]) -> Result<(), DataExportError> {
+    let mut csv_writer = csv::Writer::from_path(path)?;
+    csv_writer.write_record(["trip_id", "stop", "observed", "simulated", "difference", "geh", "flagged"])?;
+    for comparison in comparisons {
+        let stop_idx = network.get_stop_in_route(comparison.route_idx, comparison.stop_order);
+        csv_writer.write_record(&[
+            network.routes[comparison.route_idx].trip_ids[comparison.trip_idx].to_string(),
+            network.stops[stop_idx as usize].name.to_string(),
+            comparison.observed.to_string(),
+            comparison.simulated.to_string(),
+            (comparison.simulated as i64 - comparison.observed as i64).to_string(),
+            format!("{:.2}", comparison.geh),
+            (comparison.geh > GEH_THRESHOLD).to_string(),
+        ])?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn geh_of_matching_counts_is_zero() {
+        assert_eq!(geh(0., 0.), 0.);
+        assert_eq!(geh(120., 120.), 0.);
+    }
+
+    #[test]
+    fn geh_values() {
+        assert!((geh(150., 100.) - 20f64.sqrt()).abs() < 1e-9);
+        assert_eq!(geh(0., 50.), 10.);
+        assert_eq!(geh(150., 100.), geh(100., 150.));
+        // The same difference matters less on larger counts.
+        assert!(geh(1050., 1000.) < GEH_THRESHOLD);
+    }
+}
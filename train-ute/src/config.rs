@@ -0,0 +1,314 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use serde::{Deserialize, Deserializer};
+use thiserror::Error;
+
+use raptor::network::Timestamp;
+
+use crate::crowding::CrowdingFunction;
+use crate::data_export::{OccupancyFormat, ParquetCompression, TimeBand};
+use crate::data_import::parse_time_seconds;
+use crate::demand::{DemandProvider, GravityDemand, OdWindowsCsv, RandomDemand};
+use crate::emissions::EnergyModel;
+use crate::perturbation::NoiseModel;
+use crate::simulation::{AgentCount, CrowdingCost, DemandFilter};
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid config: {0}")]
+    Invalid(String),
+}
+
+// Options for a run, read from a JSON file so features can be switched on without editing main.
+// Missing fields keep their defaults, which give the standard run.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RunConfig {
+    // The GTFS feed to read.
+    pub gtfs_path: String,
+    // A URL to download the feed from instead, with the gtfs-download feature (see gtfs_download::download_gtfs).
+    pub gtfs_url: Option<String>,
+    // Or the latest published PTV feed for a mode, 1 to 6 (see gtfs_download::ptv_feed).
+    pub ptv_feed_mode: Option<u8>,
+    // Formats to export (see exporters::available_formats).
+    pub export_formats: Vec<String>,
+    pub parquet_compression: ParquetCompression,
+    // Columns to leave out of the counts and journeys exports, e.g. ["trip_id", "crowding_cost"].
+    pub excluded_columns: Vec<String>,
+    // Layout of the occupancy export, "long" or "wide".
+    pub occupancy_format: OccupancyFormat,
+    // Print the trips running on each day of the feed, to pick a representative weekday.
+    pub print_service_days: bool,
+    // Hypothetical lines to add to the feed (see line_injection::inject_line).
+    pub injected_lines: Vec<InjectedLineConfig>,
+    // Cut the feed down to these routes, by id, short name or long name (see sub_network::extract_sub_network).
+    pub sub_network_routes: Vec<String>,
+    // Merge platform-level stops into their parent stations (see stations::collapse_to_parent_stations).
+    pub collapse_to_parent_stations: bool,
+    // Raw PTV passenger counts to convert to parquet (see data_import::import_patronage_csv).
+    pub patronage_csv: Option<PatronageCsvConfig>,
+    // Where the simulation steps come from.
+    pub demand: DemandConfig,
+    // Restricts the demand to a departure window and/or lines, e.g. the AM peak.
+    pub demand_filter: DemandFilter,
+    // Fraction of the demand to simulate, for quick iteration (see simulation::sample_simulation_steps).
+    pub sample_fraction: Option<f64>,
+    // Size of the global thread pool. 0 uses one thread per core.
+    pub num_threads: usize,
+    // Name of the crowding model preset to use instead of the default model (see crowding::CROWDING_PRESETS).
+    pub crowding_preset: Option<String>,
+    // Charge crowding per passenger-km rather than per segment.
+    pub crowding_per_km: bool,
+    // Export the cost curves of the crowding presets (see crowding::export_cost_curves).
+    pub export_cost_curves: bool,
+    // Export every journey's legs, which re-queries every journey so is slow for large demand
+    // (see data_export::export_agent_journeys).
+    pub export_journeys: bool,
+    // Journeys to export animation traces of (see data_export::export_agent_traces).
+    pub agent_traces: Option<AgentTracesConfig>,
+    // Named time bands (e.g. peaks) for the exports aggregated by time band, alongside the time_bins.
+    pub time_bands: Vec<TimeBand>,
+    pub time_bins: Option<TimeBinsConfig>,
+    // Export counts aggregated to the time bands (see data_export::export_agent_counts_by_time_band).
+    pub export_counts_by_time_band: bool,
+    // Export originating, terminating and interchanging agents per station and time band
+    // (see data_export::export_station_interchanges).
+    pub export_station_interchanges: bool,
+    // Export line-to-line transfer volumes (see data_export::export_line_transfers), per time band or for the whole day.
+    pub export_line_transfers: bool,
+    pub line_transfers_by_time_band: bool,
+    // Give identical results on any number of threads, and check that they are (see SimulationParams::deterministic).
+    pub deterministic: bool,
+    // Export station entries and exits per bin of this many seconds (see data_export::export_gate_flows).
+    pub gate_flow_bin_size: Option<Timestamp>,
+    // Export traction energy and CO2 per line and time band with this model (see emissions::trip_energy).
+    pub energy_model: Option<EnergyModel>,
+    // Default train capacities to recompute the run's crowding costs with (see sweep::run_cost_sweep).
+    pub capacity_sweep: Vec<AgentCount>,
+    // Exponents b of the exponential crowding function to recompute the run's crowding costs with.
+    pub crowding_exponent_sweep: Vec<CrowdingCost>,
+    // Estimate the run's memory and time from a calibration sample of this many steps first (see simulation::estimate_run).
+    pub estimate_sample_size: Option<usize>,
+    // Monte Carlo replications with perturbed run and dwell times (see perturbation::run_perturbed_replications).
+    pub perturbation: Option<PerturbationConfig>,
+    // A journey to print the legs of after the run (see simulation::debug_journey).
+    pub debug_journey: Option<DebugJourneyConfig>,
+    // An origin to export travel times to every stop from (see accessibility::calculate_accessibility).
+    pub accessibility: Option<AccessibilityConfig>,
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        Self {
+            gtfs_path: "../gtfs/2/google_transit.zip".to_owned(),
+            gtfs_url: None,
+            ptv_feed_mode: None,
+            export_formats: ["parquet", "csv", "arrow", "bin"].map(String::from).to_vec(),
+            parquet_compression: ParquetCompression::default(),
+            excluded_columns: Vec::new(),
+            occupancy_format: OccupancyFormat::default(),
+            print_service_days: false,
+            injected_lines: Vec::new(),
+            sub_network_routes: Vec::new(),
+            collapse_to_parent_stations: false,
+            patronage_csv: None,
+            demand: DemandConfig::Random(RandomDemand { number: None, seed: Some(0) }),
+            demand_filter: DemandFilter::default(),
+            sample_fraction: None,
+            num_threads: 40,
+            crowding_preset: None,
+            crowding_per_km: false,
+            export_cost_curves: false,
+            export_journeys: false,
+            agent_traces: None,
+            time_bands: vec![TimeBand::new("AM peak", 7 * 60 * 60, 9 * 60 * 60), TimeBand::new("PM peak", 16 * 60 * 60, 18 * 60 * 60)],
+            time_bins: Some(TimeBinsConfig { start: 4 * 60 * 60, end: 24 * 60 * 60, size: 15 * 60 }),
+            export_counts_by_time_band: false,
+            export_station_interchanges: false,
+            export_line_transfers: false,
+            line_transfers_by_time_band: false,
+            deterministic: false,
+            gate_flow_bin_size: None,
+            energy_model: None,
+            capacity_sweep: Vec::new(),
+            crowding_exponent_sweep: Vec::new(),
+            estimate_sample_size: None,
+            perturbation: None,
+            debug_journey: None,
+            accessibility: None,
+        }
+    }
+}
+
+impl RunConfig {
+    // Reads the config from a JSON file, or uses the defaults if there's no file.
+    pub fn load(path: Option<&str>) -> Result<Self, ConfigError> {
+        match path {
+            Some(path) => {
+                let config: Self = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+                config.validate()?;
+                Ok(config)
+            }
+            None => Ok(Self::default()),
+        }
+    }
+
+    // Checks values that parse but can't be used.
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.ptv_feed_mode.is_some_and(|mode| !(1..=6).contains(&mode)) {
+            return Err(ConfigError::Invalid("ptv_feed_mode must be from 1 to 6".to_owned()));
+        }
+        if let Err(error) = self.parquet_compression.to_parquet() {
+            return Err(ConfigError::Invalid(format!("parquet_compression: {error}")));
+        }
+        if let Some(line) = self.injected_lines.iter().find(|line| line.headway == 0) {
+            return Err(ConfigError::Invalid(format!("injected line {} headway must be more than zero", line.name)));
+        }
+        if self.time_bins.as_ref().is_some_and(|time_bins| time_bins.size == 0) {
+            return Err(ConfigError::Invalid("time_bins size must be more than zero".to_owned()));
+        }
+        if self.agent_traces.as_ref().is_some_and(|agent_traces| agent_traces.time_step == 0) {
+            return Err(ConfigError::Invalid("agent_traces time_step must be more than zero".to_owned()));
+        }
+        if self.gate_flow_bin_size == Some(0) {
+            return Err(ConfigError::Invalid("gate_flow_bin_size must be more than zero".to_owned()));
+        }
+        for &exponent in self.crowding_exponent_sweep.iter() {
+            CrowdingFunction::Exponential(exponent).validate().map_err(|error| ConfigError::Invalid(format!("crowding_exponent_sweep: {error}")))?;
+        }
+        Ok(())
+    }
+
+    // The named time bands followed by the time bins.
+    pub fn time_bands(&self) -> Vec<TimeBand> {
+        let mut time_bands = self.time_bands.clone();
+        if let Some(time_bins) = &self.time_bins {
+            time_bands.extend(TimeBand::bins(time_bins.start, time_bins.end, time_bins.size));
+        }
+        time_bands
+    }
+}
+
+// Tagged by "type", e.g. {"type": "od_windows", "path": "../data/od_windows.csv"}.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DemandConfig {
+    Random(RandomDemand),
+    OdWindows(OdWindowsCsv),
+    Gravity(GravityDemand),
+}
+
+impl DemandConfig {
+    pub fn provider(&self) -> &dyn DemandProvider {
+        match self {
+            DemandConfig::Random(demand) => demand,
+            DemandConfig::OdWindows(demand) => demand,
+            DemandConfig::Gravity(demand) => demand,
+        }
+    }
+
+    // The seed of random or synthesised demand, for the run manifest.
+    pub fn seed(&self) -> Option<u64> {
+        match self {
+            DemandConfig::Random(demand) => demand.seed,
+            DemandConfig::OdWindows(_) => None,
+            DemandConfig::Gravity(demand) => Some(demand.seed),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct InjectedLineConfig {
+    pub name: String,
+    // CSV of the line's stops (see data_import::import_line_stops).
+    pub stops_path: String,
+    #[serde(deserialize_with = "time_of_day")]
+    pub first_departure: Timestamp,
+    #[serde(deserialize_with = "time_of_day")]
+    pub last_departure: Timestamp,
+    // Seconds.
+    pub headway: Timestamp,
+    pub dwell_time: Timestamp,
+    pub capacity: AgentCount,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PatronageCsvConfig {
+    pub path: String,
+    // Rows of other modes are dropped, e.g. "Metro".
+    pub mode: String,
+}
+
+// Fixed-size time bands covering [start, end), named by their start time.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TimeBinsConfig {
+    #[serde(deserialize_with = "time_of_day")]
+    pub start: Timestamp,
+    #[serde(deserialize_with = "time_of_day")]
+    pub end: Timestamp,
+    // Seconds.
+    pub size: Timestamp,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AgentTracesConfig {
+    // Indices into the simulation steps (after expansion, in a sampled run).
+    pub journey_indices: Vec<usize>,
+    // Seconds between trace points.
+    pub time_step: Timestamp,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PerturbationConfig {
+    pub noise: NoiseModel,
+    pub num_replications: usize,
+    #[serde(default)]
+    pub seed: u64,
+}
+
+// A single journey query, with stops given by name.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DebugJourneyConfig {
+    pub origin: String,
+    pub destination: String,
+    #[serde(deserialize_with = "time_of_day")]
+    pub departure_time: Timestamp,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AccessibilityConfig {
+    pub origin: String,
+    #[serde(deserialize_with = "time_of_day")]
+    pub departure_time: Timestamp,
+}
+
+fn parse_time<E: serde::de::Error>(time: &str) -> Result<Timestamp, E> {
+    parse_time_seconds(time)
+        .and_then(|seconds| Timestamp::try_from(seconds).ok())
+        .ok_or_else(|| E::custom(format!("invalid time '{time}', expected HH:MM[:SS]")))
+}
+
+// Reads a time of day given as "HH:MM[:SS]".
+pub fn time_of_day<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Timestamp, D::Error> {
+    parse_time(&String::deserialize(deserializer)?)
+}
+
+// Reads an optional [start, end] pair of times of day.
+pub fn time_window<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<(Timestamp, Timestamp)>, D::Error> {
+    match Option::<(String, String)>::deserialize(deserializer)? {
+        Some((start, end)) => Ok(Some((parse_time(&start)?, parse_time(&end)?))),
+        None => Ok(None),
+    }
+}
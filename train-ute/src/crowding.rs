@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+
+use gtfs_structures::Gtfs;
+use serde::Serialize;
+use thiserror::Error;
+
+use raptor::Network;
+
+use crate::data_export::DataExportError;
+use crate::simulation::{AgentCount, CrowdingCost, PopulationCount, SimulationParams};
+
+// Shape of the crowding cost curve, as a function of the proportion of capacity used (0 to 1 at capacity).
+// Each is scaled so the cost is 0 when empty and 1 at capacity.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CrowdingFunction {
+    Linear,
+    Quadratic,
+    // (e^(bx) - 1) / (e^b - 1), which gets steeper as b increases.
+    Exponential(CrowdingCost),
+}
+
+impl CrowdingFunction {
+    pub fn eval(&self, x: CrowdingCost) -> CrowdingCost {
+        match *self {
+            CrowdingFunction::Linear => x,
+            CrowdingFunction::Quadratic => x * x,
+            CrowdingFunction::Exponential(b) => {
+                let bx = b * x;
+                let ebx = bx.exp();
+                (ebx - 1.) / (b.exp() - 1.)
+            }
+        }
+    }
+}
+
+// A crowding function that gives unusable costs somewhere between empty and capacity, with the offending range of
+// proportions of capacity.
+#[derive(Error, Debug)]
+pub enum CrowdingFunctionError {
+    #[error("{function:?} is not finite for proportions {start:.2} to {end:.2}")]
+    NotFinite { function: CrowdingFunction, start: CrowdingCost, end: CrowdingCost },
+    #[error("{function:?} is negative for proportions {start:.2} to {end:.2}")]
+    Negative { function: CrowdingFunction, start: CrowdingCost, end: CrowdingCost },
+    #[error("{function:?} decreases from proportion {start:.2} to {end:.2}")]
+    Decreasing { function: CrowdingFunction, start: CrowdingCost, end: CrowdingCost },
+}
+
+impl CrowdingFunction {
+    // Checks the function is finite, non-negative and non-decreasing from empty to capacity, since a mistyped
+    // parameter otherwise silently gives absurd assignments. Sampled, so very narrow problems can be missed.
+    pub fn validate(&self) -> Result<(), CrowdingFunctionError> {
+        const NUM_SAMPLES: usize = 1000;
+
+        let samples = (0..=NUM_SAMPLES).map(|i| {
+            let x = i as CrowdingCost / NUM_SAMPLES as CrowdingCost;
+            (x, self.eval(x))
+        }).collect::<Vec<_>>();
+
+        // The range of proportions around the first sample that fails a check, for the error message.
+        let bad_range = |is_bad: &dyn Fn(CrowdingCost) -> bool| {
+            let first = samples.iter().position(|&(_, cost)| is_bad(cost))?;
+            let last = samples.iter().rposition(|&(_, cost)| is_bad(cost))?;
+            Some((samples[first].0, samples[last].0))
+        };
+        if let Some((start, end)) = bad_range(&|cost| !cost.is_finite()) {
+            return Err(CrowdingFunctionError::NotFinite { function: *self, start, end });
+        }
+        if let Some((start, end)) = bad_range(&|cost| cost < 0.) {
+            return Err(CrowdingFunctionError::Negative { function: *self, start, end });
+        }
+        if let Some(pair) = samples.windows(2).find(|pair| pair[1].1 < pair[0].1) {
+            return Err(CrowdingFunctionError::Decreasing { function: *self, start: pair[0].0, end: pair[1].0 });
+        }
+
+        Ok(())
+    }
+}
+
+// A named crowding model, so users don't have to work out the parameters themselves.
+pub struct CrowdingPreset {
+    pub name: &'static str,
+    pub max_train_capacity: AgentCount,
+    pub crowding_function: CrowdingFunction,
+}
+
+// Capacities are from VicSig (https://vicsig.net/suburban/train). Each preset is named for the rolling stock and the
+// shape of its crowding function.
+pub static CROWDING_PRESETS: [CrowdingPreset; 5] = [
+    CrowdingPreset {
+        name: "xtrapolis-6car-exponential",
+        max_train_capacity: 794,
+        crowding_function: CrowdingFunction::Exponential(5.),
+    },
+    CrowdingPreset {
+        name: "xtrapolis-6car-linear",
+        max_train_capacity: 794,
+        crowding_function: CrowdingFunction::Linear,
+    },
+    CrowdingPreset {
+        name: "xtrapolis-6car-quadratic",
+        max_train_capacity: 794,
+        crowding_function: CrowdingFunction::Quadratic,
+    },
+    CrowdingPreset {
+        name: "xtrapolis-3car-exponential",
+        max_train_capacity: 397,
+        crowding_function: CrowdingFunction::Exponential(5.),
+    },
+    CrowdingPreset {
+        name: "hcmt-7car-exponential",
+        max_train_capacity: 1100,
+        crowding_function: CrowdingFunction::Exponential(5.),
+    },
+];
+
+pub fn find_preset(name: &str) -> Option<&'static CrowdingPreset> {
+    CROWDING_PRESETS.iter().find(|preset| preset.name.eq_ignore_ascii_case(name))
+}
+
+// Picks the crowding function of each network route from a mapping of GTFS route ids (e.g. so V/Line coaches and metro
+// trains can have different standing tolerance). Routes not in the mapping use the default.
+pub fn resolve_route_crowding_functions(network: &Network, gtfs: &Gtfs, route_functions: &HashMap<String, CrowdingFunction>, default: CrowdingFunction) -> Vec<CrowdingFunction> {
+    network.routes.iter().map(|route| {
+        // Every trip in a network route has the same GTFS route.
+        route.trip_ids.first()
+            .and_then(|trip_id| gtfs.trips.get::<str>(trip_id))
+            .and_then(|gtfs_trip| route_functions.get(&gtfs_trip.route_id))
+            .copied()
+            .unwrap_or(default)
+    }).collect()
+}
+
+// Exports the cost curve of each model in tidy long format (one row per model and agent count), up to 1.5x each
+// model's capacity, so the curves can be plotted and compared before running a simulation.
+pub fn export_cost_curves<T: SimulationParams>(path: &str, models: &[(&str, T)]) -> Result<(), DataExportError> {
+    const NUM_SAMPLES: usize = 100;
+
+    let mut csv_writer = csv::Writer::from_path(path)?;
+    csv_writer.write_record(["model", "max_train_capacity", "count", "proportion", "cost"])?;
+    for (name, params) in models {
+        let capacity = params.max_train_capacity();
+        let max_count = capacity as f64 * 1.5;
+        for i in 0..=NUM_SAMPLES {
+            let count = (max_count * i as f64 / NUM_SAMPLES as f64).round() as PopulationCount;
+            let proportion = count as f64 / capacity as f64;
+            csv_writer.write_record([*name, &capacity.to_string(), &count.to_string(), &proportion.to_string(), &params.cost_fn(count, capacity).to_string()])?;
+        }
+    }
+
+    Ok(())
+}
@@ -1,15 +1,24 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::File;
-use std::io::Write;
+use std::io::{BufWriter, Write};
 use std::mem;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 
-use arrow::array::{Array, StringArray, TimestampMillisecondArray, UInt32Array};
-use arrow::datatypes::{Field, Schema};
+use arrow::array::{Array, ArrayRef, BooleanArray, Float32Array, Int32Array, ListArray, PrimitiveArray, StringArray, TimestampMillisecondArray, UInt32Array, UInt64Array, UInt8Array};
+use arrow::buffer::OffsetBuffer;
+use arrow::datatypes::{ArrowPrimitiveType, DataType, Field, Float32Type, Schema, UInt32Type, UInt8Type};
+use arrow::ipc::writer::FileWriter;
+use arrow::record_batch::RecordBatch;
+use chrono::{DateTime, NaiveDate, Utc};
+use gtfs_structures::{DirectionType, Gtfs};
 use itertools::{Itertools, izip};
-use parquet::basic::Compression;
-use parquet::file::properties::WriterProperties;
-use rgb::RGB8;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::{EnabledStatistics, WriterProperties};
+use parquet::schema::types::ColumnPath;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 use zip::write::SimpleFileOptions;
 use zip::ZipWriter;
@@ -18,8 +27,10 @@ use raptor::Network;
 use raptor::network::{NetworkPoint, Timestamp};
 use raptor::utils::get_time_str;
 
-use crate::simulation::SimulationResult;
-use crate::utils::{mix_rgb, quadratic_ease_in_out, quadratic_inv_ease_in_out};
+use crate::capacities::{CapacityMatcher, ResolvedTripCapacities, TripCapacities};
+use crate::crowding::CrowdingFunction;
+use crate::simulation::{query_journey_legs, AgentCount, AgentJourney, CrowdingCost, SimulationParams, SimulationResult};
+use crate::utils::{douglas_peucker, load_factor_colour, route_shape_matches, quadratic_ease_in_out, quadratic_inv_ease_in_out};
 
 #[derive(Error, Debug)]
 pub enum DataExportError {
@@ -31,12 +42,24 @@ pub enum DataExportError {
     ParquetError(#[from] parquet::errors::ParquetError),
     #[error("CSV error: {0}")]
     CsvError(#[from] csv::Error),
+    #[error("JSON error: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("Time bin or step size must be more than zero")]
+    ZeroBinSize,
+    #[error("Journey index {0} is out of range")]
+    JourneyIndex(usize),
 }
 
-// Writes a set of binary data to a zip file in a simple format:
-// - A 32-bit byte offset and length for each data chunk.
+// Identifies the binary visualisation format, followed by the format version.
+pub const BIN_MAGIC: [u8; 4] = *b"TUTE";
+pub const BIN_VERSION: u32 = 1;
+
+// Writes a set of tagged binary data chunks to a zip file in a simple format:
+// - The magic bytes, the format version, the number of chunks and a reserved u32 (16 bytes).
+// - For each chunk, a 4-byte ASCII tag, then a 32-bit byte offset and length (12 bytes).
 // - The binary data chunks, each aligned to 8 bytes.
-fn write_bin(path: &str, data_list: &[&[u8]]) -> std::io::Result<()> {
+// Readers should look chunks up by tag, and ignore tags they don't know.
+fn write_bin(path: &str, data_list: &[(&[u8; 4], &[u8])]) -> std::io::Result<()> {
     // Simple power-of-two alignment.
     fn round_up_to_eight(num: usize) -> usize { (num + 7) & !7 }
 
@@ -44,12 +67,20 @@ fn write_bin(path: &str, data_list: &[&[u8]]) -> std::io::Result<()> {
     let mut zip = ZipWriter::new(File::create(path)?);
     zip.start_file("data.bin", SimpleFileOptions::default())?;
 
-    // A 32-bit byte offset and length for each data chunk, followed by the data chunks.
+    // File header, then a tag, 32-bit byte offset and length for each data chunk, followed by the data chunks.
     // We want the data to be aligned to 8 bytes.
-    let header_size = data_list.len() * 2 * mem::size_of::<u32>(); // 2 32-bit values per data chunk.
-    let mut index = header_size as u32; // Start past header.
+    const FILE_HEADER_SIZE: usize = 4 * mem::size_of::<u32>();
+    const CHUNK_HEADER_SIZE: usize = 3 * mem::size_of::<u32>();
+    let header_size = FILE_HEADER_SIZE + data_list.len() * CHUNK_HEADER_SIZE;
     let mut written_bytes = 0;
-    for &data in data_list {
+    written_bytes += zip.write(&BIN_MAGIC)?;
+    written_bytes += zip.write(&BIN_VERSION.to_le_bytes())?;
+    written_bytes += zip.write(&(data_list.len() as u32).to_le_bytes())?;
+    written_bytes += zip.write(&0u32.to_le_bytes())?;
+
+    let mut index = round_up_to_eight(header_size) as u32; // Start past header.
+    for &(tag, data) in data_list {
+        written_bytes += zip.write(tag)?;
         written_bytes += zip.write(&index.to_le_bytes())?;
         written_bytes += zip.write(&(data.len() as u32).to_le_bytes())?;
         index += round_up_to_eight(data.len()) as u32;
@@ -57,9 +88,12 @@ fn write_bin(path: &str, data_list: &[&[u8]]) -> std::io::Result<()> {
 
     // Sanity check.
     assert_eq!(written_bytes, header_size);
+    for _ in header_size..round_up_to_eight(header_size) {
+        zip.write_all(&0u8.to_le_bytes())?;
+    }
 
     // Write data, maintaining 8-byte alignment.
-    for &data in data_list {
+    for &(_, data) in data_list {
         zip.write_all(data)?;
         let padding = round_up_to_eight(data.len()) - data.len();
         for _ in 0..padding {
@@ -70,36 +104,145 @@ fn write_bin(path: &str, data_list: &[&[u8]]) -> std::io::Result<()> {
     Ok(())
 }
 
-pub fn export_shape_file(path: &str, network: &Network) -> Result<(), DataExportError> {
+// Compression used for parquet exports, "snappy" or {"zstd": level} in the run config.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParquetCompression {
+    #[default]
+    Snappy,
+    // Smaller files than Snappy at some cost in write time. Level is 1 (fastest) to 22 (smallest).
+    Zstd(i32),
+}
+
+impl ParquetCompression {
+    // Fails if the Zstd level is out of range.
+    pub fn to_parquet(self) -> Result<Compression, parquet::errors::ParquetError> {
+        Ok(match self {
+            ParquetCompression::Snappy => Compression::SNAPPY,
+            ParquetCompression::Zstd(level) => Compression::ZSTD(ZstdLevel::try_new(level)?),
+        })
+    }
+}
+
+// Trip and station columns, which are what exports are usually filtered on.
+const BLOOM_FILTER_COLUMNS: [&str; 10] = ["trip_id", "trip_name", "stop", "station", "departure", "arrival", "board_stop", "alight_stop", "origin", "destination"];
+
+// Writes a single record batch to a parquet file.
+pub fn write_parquet(path: &str, record_batch: &RecordBatch, compression: ParquetCompression) -> Result<(), DataExportError> {
+    let mut props = WriterProperties::builder()
+        .set_compression(compression.to_parquet()?);
+    // Columns downstream queries filter on get bloom filters and page-level statistics, so readers like DuckDB can
+    // skip row groups and pages.
+    for field in record_batch.schema().fields() {
+        if BLOOM_FILTER_COLUMNS.contains(&field.name().as_str()) {
+            let column = ColumnPath::from(field.name().as_str());
+            props = props
+                .set_column_bloom_filter_enabled(column.clone(), true)
+                .set_column_statistics_enabled(column, EnabledStatistics::Page);
+        }
+    }
+    let props = props.build();
+    let mut writer = parquet::arrow::ArrowWriter::try_new(File::create(path)?, record_batch.schema(), Some(props))?;
+
+    writer.write(record_batch)?;
+
+    writer.close()?;
+
+    Ok(())
+}
+
+// Writes a single record batch to an Arrow IPC file, which the frontend reads with arrow-js.
+pub fn write_ipc(path: &str, record_batch: &RecordBatch) -> Result<(), DataExportError> {
+    let mut writer = FileWriter::try_new(BufWriter::new(File::create(path)?), &record_batch.schema())?;
+
+    writer.write(record_batch)?;
+
+    writer.finish()?;
+
+    Ok(())
+}
+
+// Builds a list column from flat values, where row i spans values[offsets[i]..offsets[i + 1]].
+fn list_array<T: ArrowPrimitiveType>(values: Vec<T::Native>, offsets: Vec<i32>) -> (Field, ArrayRef) {
+    let item_field = Arc::new(Field::new("item", T::DATA_TYPE, false));
+    let array = ListArray::new(item_field, OffsetBuffer::new(offsets.into()), Arc::new(PrimitiveArray::<T>::from_iter_values(values)), None);
+    (Field::new("", array.data_type().clone(), false), Arc::new(array))
+}
+
+// Exports route shapes for the frontend as an Arrow IPC table, one row per route, with columns:
+// - line: The route's line name.
+// - points: The flattened (longitude, latitude, height) of each shape point.
+// - r, g, b: The route colour.
+// - approximate_shape: Whether the shape is straight lines between stops, as the feed had none (see
+//   utils::gen_fallback_shapes).
+// If a simplification tolerance (in metres) is given, shape points that don't change the line by more than that are dropped.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(path = path)))]
+pub fn export_shape_file(path: &str, network: &Network, approximate_shapes: &[bool], simplify_tolerance: Option<f32>) -> Result<(), DataExportError> {
+    let mut lines = Vec::new();
     let mut shape_points = Vec::new();
-    let mut shape_start_indices = Vec::new();
-    let mut shape_colours = Vec::new();
+    let mut shape_offsets = vec![0i32];
+    let mut colours = [Vec::new(), Vec::new(), Vec::new()];
 
     for route in network.routes.iter() {
         let colour = route.colour;
         let height = route.shape_height;
 
-        // Indices are based on points, not coordinates.
-        shape_start_indices.push(shape_points.len() as u32 / 3);
+        let keep_points = match simplify_tolerance {
+            Some(tolerance) => douglas_peucker(&route.shape, tolerance),
+            None => vec![true; route.shape.len()],
+        };
 
         // Construct line string from shape.
-        for point in route.shape.iter() {
+        for (point, _) in route.shape.iter().zip(keep_points).filter(|&(_, keep_point)| keep_point) {
             shape_points.push(point.longitude);
             shape_points.push(point.latitude);
             shape_points.push(height);
-
-            shape_colours.push(colour.r);
-            shape_colours.push(colour.g);
-            shape_colours.push(colour.b);
         }
+        shape_offsets.push(shape_points.len() as i32);
+
+        lines.push(&*route.line);
+        colours[0].push(colour.r);
+        colours[1].push(colour.g);
+        colours[2].push(colour.b);
     }
 
-    write_bin(path, &[bytemuck::must_cast_slice(&shape_points), bytemuck::must_cast_slice(&shape_start_indices), &shape_colours])?;
+    let (points_field, points_arr) = list_array::<Float32Type>(shape_points, shape_offsets);
+    let [r, g, b] = colours;
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("line", DataType::Utf8, false),
+        points_field.with_name("points"),
+        Field::new("r", DataType::UInt8, false),
+        Field::new("g", DataType::UInt8, false),
+        Field::new("b", DataType::UInt8, false),
+        Field::new("approximate_shape", DataType::Boolean, false),
+    ]));
+    let record_batch = RecordBatch::try_new(schema, vec![
+        Arc::new(StringArray::from(lines)),
+        points_arr,
+        Arc::new(UInt8Array::from(r)),
+        Arc::new(UInt8Array::from(g)),
+        Arc::new(UInt8Array::from(b)),
+        Arc::new(BooleanArray::from(approximate_shapes.to_vec())),
+    ])?;
+
+    write_ipc(path, &record_batch)?;
 
     Ok(())
 }
 
-pub fn export_network_trips(path: &str, network: &Network, simulation_result: &SimulationResult) -> Result<(), DataExportError> {
+// Stops further than this (in metres) from their route's shape are reported.
+pub const SHAPE_MATCH_TOLERANCE: f32 = 50.;
+
+// Exports the animated trips for the frontend as an Arrow IPC table, one row per trip, with columns:
+// - trip_id, capacity: The trip's identity and capacity.
+// - approximate_shape: Whether the trip's route has an approximate shape (see export_shape_file).
+// - counts: The agent count departing each stop of the trip.
+// - points: The flattened (longitude, latitude, height) of each trip point.
+// - times: The time of each trip point (seconds since midnight).
+// - colours: The flattened RGBA colour of each trip point.
+// If a simplification tolerance (in metres) is given, shape points that don't change the line by more than that are dropped.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(path = path)))]
+pub fn export_network_trips(path: &str, network: &Network, gtfs: &Gtfs, approximate_shapes: &[bool], simulation_result: &SimulationResult, params: &impl SimulationParams, simplify_tolerance: Option<f32>) -> Result<(), DataExportError> {
     const NUM_COORDS_PER_POINT: u32 = 3;
 
     // I haven't bothered to calculate capacities, but it's amortised constant to push anyway so there's not really any point.
@@ -108,31 +251,51 @@ pub fn export_network_trips(path: &str, network: &Network, simulation_result: &S
     let mut trip_times = Vec::new();
     let mut trip_colours = Vec::new();
 
+    // Per-trip identity and loads, so the frontend can show tooltips and filter trips.
+    let mut trip_ids = Vec::new();
+    let mut trip_capacities = Vec::new();
+    let mut trip_approximate_shapes = Vec::new();
+    let mut trip_counts = Vec::new();
+    let mut trip_count_offsets = Vec::new();
+
+    let route_shape_matches = route_shape_matches(network, gtfs, SHAPE_MATCH_TOLERANCE);
     for route_idx in 0..network.num_routes() {
         let num_stops = network.num_stops_in_route(route_idx);
         let route = &network.routes[route_idx];
         let route_shape = &route.shape;
         let height = route.shape_height;
         
-        const LOW_COLOUR: RGB8 = RGB8 { r: 0, g: 0, b: 255 };
-        const HIGH_COLOUR: RGB8 = RGB8 { r: 255, g: 0, b: 0 };
+        // Find where each stop lies along the shape. This is the same for every trip in the route.
+        let stop_shape_matches = &route_shape_matches[route_idx];
+        for (stop_order, stop_shape_match) in stop_shape_matches.iter().enumerate() {
+            if stop_shape_match.distance > SHAPE_MATCH_TOLERANCE {
+                let stop_idx = network.get_stop_in_route(route_idx, stop_order) as usize;
+                println!("Warning: Stop {}({stop_order}) is {:.0}m from the shape of route {}.", network.stops[stop_idx].name, stop_shape_match.distance, route.line);
+            }
+        }
 
         for trip_idx in 0..network.num_trips(route_idx) {
-            start_indices.push(trip_points.len() as u32 / NUM_COORDS_PER_POINT);
+            start_indices.push((trip_points.len() as u32 / NUM_COORDS_PER_POINT) as i32);
 
             let agent_counts = &simulation_result.agent_journeys[route.get_trip_range(trip_idx)];
 
-            let mut shape_idx = 0;
+            trip_ids.push(route.trip_ids[trip_idx].as_ref());
+            let capacity = params.trip_capacity(route_idx, trip_idx);
+            trip_capacities.push(capacity as u32);
+            trip_approximate_shapes.push(approximate_shapes[route_idx]);
+            trip_count_offsets.push(trip_counts.len() as i32);
+            trip_counts.extend(agent_counts.iter().map(|&count| count.max(0) as u32));
+
             for dep_stop_order in 0..num_stops - 1 {
                 let arr_stop_order = dep_stop_order + 1;
 
                 let departure_time = network.get_departure_time(route_idx, trip_idx, dep_stop_order) as f32;
 
+                let dep_stop_idx = network.get_stop_in_route(route_idx, dep_stop_order) as usize;
                 let arr_stop_idx = network.get_stop_in_route(route_idx, arr_stop_order) as usize;
-                let arr_point = network.stop_points[arr_stop_idx];
                 let arrival_time = network.get_arrival_time(route_idx, trip_idx, arr_stop_order) as f32;
 
-                // Calculate alpha based on agent count.
+                // Colour is based on agent count.
                 let dep_count = agent_counts[dep_stop_order];
                 
                 // Ignore trips with no agents.
@@ -144,8 +307,6 @@ pub fn export_network_trips(path: &str, network: &Network, simulation_result: &S
                 let arr_count = agent_counts[arr_stop_order] as f32;
                 let agent_count_diff = arr_count - dep_count;
                 
-                const MAX_AGENT_COUNT: f32 = 50.;
-
                 let mut push_point = |point: NetworkPoint, next_point: NetworkPoint| {
                     // Location is offset to the left to separate inbound and outbound.
                     const OFFSET: f32 = 20.;
@@ -155,82 +316,197 @@ pub fn export_network_trips(path: &str, network: &Network, simulation_result: &S
                     trip_points.push(height);
                 };
 
-                // Go through shape points and add to point list.
-                let start_shape_idx = shape_idx;
-                let mut current_point = route_shape[shape_idx];
-                let mut distance_along_shape_section = 0f32;
-                while !current_point.very_close(arr_point) {
-                    if route_shape.len() <= shape_idx + 1 {
-                        println!("Warning: Shape index out of bounds for route {}, stop {}({arr_stop_order}).", network.routes[route_idx].line, network.stops[arr_stop_idx].name);
-                        break;
-                    }
-
-                    shape_idx += 1;
-                    let next_point = route_shape[shape_idx];
-                    distance_along_shape_section += current_point.distance(next_point);
-
-                    push_point(current_point, next_point);
+                // The section of shape between the two stops. The last point is also counted as the start of the next section.
+                // If the stops matched out of order, fall back to a straight line between them.
+                let start_shape_idx = stop_shape_matches[dep_stop_order].shape_idx;
+                let end_shape_idx = stop_shape_matches[arr_stop_order].shape_idx;
+                let straight_section = [network.stop_points[dep_stop_idx], network.stop_points[arr_stop_idx]];
+                let section_shape = if start_shape_idx < end_shape_idx {
+                    &route_shape[start_shape_idx..=end_shape_idx]
+                } else {
+                    &straight_section[..]
+                };
+                // Used to calculate the direction of the final point.
+                let after_section_point = route_shape.get(end_shape_idx + 1).copied().unwrap_or(section_shape[section_shape.len() - 1]);
+                let distance_along_shape_section: f32 = section_shape.iter().tuple_windows().map(|(a, b)| a.distance(*b)).sum();
 
-                    current_point = next_point;
-                }
+                // Only keep the shape points needed to represent the section within the tolerance.
+                let keep_points = match simplify_tolerance {
+                    Some(tolerance) => douglas_peucker(section_shape, tolerance),
+                    None => vec![true; section_shape.len()],
+                };
 
-                // Push the arrival point.
-                shape_idx += 1;
-                if shape_idx < route_shape.len() {
-                    push_point(current_point, route_shape[shape_idx]);
-                } else {
-                    push_point(arr_point, arr_point);
-                }
-                
-                // Calculate time based on distance proportion.
+                // Add points to point list, calculating time based on distance proportion.
                 let section_duration = arrival_time - departure_time;
                 let mut distance = 0.;
-                for shape_idx in start_shape_idx..shape_idx {
+                for (i, keep_point) in keep_points.into_iter().enumerate() {
                     assert!(distance >= 0.);
-                    assert!(distance_along_shape_section > 0.);
-                    
-                    // Calculate proportion along this shape we are, for interpolating properties.
-                    // Apply an easing function to the proportion, so trains accelerate and decelerate.
-                    // We use the inverse of the easing function for easing time.
-                    let proportion = distance / distance_along_shape_section;
-                    let proportion_inv = quadratic_inv_ease_in_out(proportion);
-                    let proportion = quadratic_ease_in_out(proportion);
-                    let time = departure_time + section_duration * proportion_inv;
-                    trip_times.push(time);
-
-                    // Colour (RGBA). Calculate alpha based on agent count.
-                    let value = (dep_count + agent_count_diff * proportion) / MAX_AGENT_COUNT;
-                    let shape_colour = mix_rgb(LOW_COLOUR, HIGH_COLOUR, value);
-
-                    trip_colours.push(shape_colour.r);
-                    trip_colours.push(shape_colour.g);
-                    trip_colours.push(shape_colour.b);
-                    trip_colours.push(255);
-
-                    let segment_distance = if shape_idx + 1 < route_shape.len() {
-                        route_shape[shape_idx].distance(route_shape[shape_idx + 1])
-                    } else {
-                        0.
-                    };
 
-                    distance += segment_distance;
-                }
+                    let point = section_shape[i];
+                    let next_point = section_shape.get(i + 1).copied();
+
+                    if keep_point {
+                        push_point(point, next_point.unwrap_or(after_section_point));
+
+                        // Calculate proportion along this shape we are, for interpolating properties.
+                        // Apply an easing function to the proportion, so trains accelerate and decelerate.
+                        // We use the inverse of the easing function for easing time.
+                        let proportion = if distance_along_shape_section > 0. { distance / distance_along_shape_section } else { 0. };
+                        let proportion_inv = quadratic_inv_ease_in_out(proportion);
+                        let proportion = quadratic_ease_in_out(proportion);
+                        let time = departure_time + section_duration * proportion_inv;
+                        trip_times.push(time);
+
+                        // Colour (RGBA), based on the load relative to this trip's capacity.
+                        let load_factor = (dep_count + agent_count_diff * proportion) / capacity as f32;
+                        let shape_colour = load_factor_colour(load_factor);
 
-                // This is required so we count the last point as the start of the next section.
-                shape_idx -= 1;
+                        trip_colours.push(shape_colour.r);
+                        trip_colours.push(shape_colour.g);
+                        trip_colours.push(shape_colour.b);
+                        trip_colours.push(255);
+                    }
+
+                    distance += next_point.map_or(0., |next_point| point.distance(next_point));
+                }
 
                 assert_eq!(trip_points.len(), trip_times.len() * NUM_COORDS_PER_POINT as usize);
             }
         }
     }
 
-    write_bin(path, &[bytemuck::must_cast_slice(&trip_points), bytemuck::must_cast_slice(&start_indices), bytemuck::must_cast_slice(&trip_times), &trip_colours])?;
+    start_indices.push((trip_points.len() as u32 / NUM_COORDS_PER_POINT) as i32);
+    trip_count_offsets.push(trip_counts.len() as i32);
+
+    // Offsets of each trip's coordinates and colour components, from its point offsets.
+    let point_offsets = start_indices.iter().map(|&index| index * NUM_COORDS_PER_POINT as i32).collect();
+    let colour_offsets = start_indices.iter().map(|&index| index * 4).collect();
+
+    let (counts_field, counts_arr) = list_array::<UInt32Type>(trip_counts, trip_count_offsets);
+    let (points_field, points_arr) = list_array::<Float32Type>(trip_points, point_offsets);
+    let (times_field, times_arr) = list_array::<Float32Type>(trip_times, start_indices);
+    let (colours_field, colours_arr) = list_array::<UInt8Type>(trip_colours, colour_offsets);
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("trip_id", DataType::Utf8, false),
+        Field::new("capacity", DataType::UInt32, false),
+        Field::new("approximate_shape", DataType::Boolean, false),
+        counts_field.with_name("counts"),
+        points_field.with_name("points"),
+        times_field.with_name("times"),
+        colours_field.with_name("colours"),
+    ]));
+    let record_batch = RecordBatch::try_new(schema, vec![
+        Arc::new(StringArray::from(trip_ids)),
+        Arc::new(UInt32Array::from(trip_capacities)),
+        Arc::new(BooleanArray::from(trip_approximate_shapes)),
+        counts_arr,
+        points_arr,
+        times_arr,
+        colours_arr,
+    ])?;
+
+    write_ipc(path, &record_batch)?;
+
+    Ok(())
+}
+
+// Exports the agent load at each stop over the day, binned by time, for a station heatmap layer:
+// - A single u32 with the bin size in seconds.
+// - The (longitude, latitude) of each stop.
+// - The through-load (agents on board departing the stop), one u32 per stop per bin.
+// - The net boardings (increase in load at the stop), one u32 per stop per bin.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(path = path)))]
+pub fn export_stop_loads(path: &str, network: &Network, simulation_result: &SimulationResult, bin_size: Timestamp) -> Result<(), DataExportError> {
+    if bin_size == 0 {
+        return Err(DataExportError::ZeroBinSize);
+    }
+    let num_stops = network.num_stops();
+    let max_time = network.stop_times.iter().map(|stop_time| stop_time.departure_time).max().unwrap_or(0);
+    let num_bins = (max_time / bin_size) as usize + 1;
+
+    let mut stop_points = Vec::with_capacity(num_stops * 2);
+    for point in network.stop_points.iter() {
+        stop_points.push(point.longitude);
+        stop_points.push(point.latitude);
+    }
+
+    // Indexed by stop, then bin.
+    let mut through_loads = vec![0u32; num_stops * num_bins];
+    let mut boardings = vec![0u32; num_stops * num_bins];
+
+    for route_idx in 0..network.num_routes() {
+        let route = &network.routes[route_idx];
+        for trip_idx in 0..network.num_trips(route_idx) {
+            let agent_counts = &simulation_result.agent_journeys[route.get_trip_range(trip_idx)];
+            let mut previous_count = 0;
+            for (stop_order, &count) in agent_counts.iter().enumerate() {
+                assert!(count >= 0, "Negative agent count: {}", count);
+                let stop_idx = network.get_stop_in_route(route_idx, stop_order) as usize;
+                let bin = (network.get_departure_time(route_idx, trip_idx, stop_order) / bin_size) as usize;
+
+                through_loads[stop_idx * num_bins + bin] += count as u32;
+                boardings[stop_idx * num_bins + bin] += (count - previous_count).max(0) as u32;
+                previous_count = count;
+            }
+        }
+    }
+
+    write_bin(path, &[(b"BINS", bytemuck::must_cast_slice(&[bin_size])), (b"PNTS", bytemuck::must_cast_slice(&stop_points)), (b"LOAD", bytemuck::must_cast_slice(&through_loads)), (b"BRDS", bytemuck::must_cast_slice(&boardings))])?;
 
     Ok(())
 }
 
+// Route short name, direction (0 or 1) and headsign of a trip from the GTFS, where the feed has them.
+struct TripLabels<'a> {
+    route_name: Option<&'a str>,
+    direction: Option<u32>,
+    headsign: Option<&'a str>,
+}
+
+impl<'a> TripLabels<'a> {
+    fn new(gtfs: &'a Gtfs, trip_id: &str) -> Self {
+        let Some(gtfs_trip) = gtfs.trips.get(trip_id) else {
+            return Self { route_name: None, direction: None, headsign: None };
+        };
+        Self {
+            route_name: gtfs.routes.get(&gtfs_trip.route_id).and_then(|gtfs_route| gtfs_route.short_name.as_deref()),
+            direction: gtfs_trip.direction_id.map(|direction| match direction {
+                DirectionType::Outbound => 0,
+                DirectionType::Inbound => 1,
+            }),
+            headsign: gtfs_trip.trip_headsign.as_deref(),
+        }
+    }
+}
+
+// Which columns to write in the counts and journeys exports. Dropping heavyweight columns (e.g. trip ids, crowding
+// cost) keeps files small enough to open in Excel. Names are the column names in the output.
+#[derive(Clone, Debug, Default)]
+pub struct ExportColumns {
+    excluded: HashSet<String>,
+}
+
+impl ExportColumns {
+    pub fn excluding(columns: &[String]) -> Self {
+        Self { excluded: columns.iter().cloned().collect() }
+    }
+
+    pub fn includes(&self, column: &str) -> bool {
+        !self.excluded.contains(column)
+    }
+
+    // Keeps the included columns of a batch.
+    fn select(&self, fields: Vec<Field>, arrays: Vec<ArrayRef>) -> Result<RecordBatch, DataExportError> {
+        let (fields, arrays): (Vec<_>, Vec<_>) = fields.into_iter().zip(arrays)
+            .filter(|(field, _)| self.includes(field.name()))
+            .unzip();
+        Ok(RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays)?)
+    }
+}
+
 // Exports the agent counts to a parquet (and csv) file.
-pub fn export_agent_counts(path: &str, network: &Network, simulation_result: &SimulationResult) -> Result<(), DataExportError> {
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(path = path)))]
+pub fn export_agent_counts(path: &str, network: &Network, gtfs: &Gtfs, simulation_result: &SimulationResult, params: &impl SimulationParams, columns: &ExportColumns, compression: ParquetCompression) -> Result<(), DataExportError> {
     // This is the utc timestamp for the midnight of the day the network represents.
     let date_timestamp = network.date.and_time(chrono::NaiveTime::MIN).and_utc().timestamp();
 
@@ -239,10 +515,19 @@ pub fn export_agent_counts(path: &str, network: &Network, simulation_result: &Si
     let mut departures = Vec::new();
     let mut arrivals = Vec::new();
     let mut agent_counts = Vec::new();
+    let mut pattern_ids = Vec::new();
+    let mut load_factors = Vec::new();
+    let mut spare_capacities = Vec::new();
+    let mut route_names = Vec::new();
+    let mut directions = Vec::new();
+    let mut headsigns = Vec::new();
 
-    for route in network.routes.iter() {
+    for (route_idx, route) in network.routes.iter().enumerate() {
         for trip in 0..route.num_trips as usize {
             let trip_name = route.trip_ids[trip].as_ref();
+            let trip_labels = TripLabels::new(gtfs, trip_name);
+            // The same capacity the simulation used for crowding costs.
+            let capacity = params.trip_capacity(route_idx, trip);
             let trip_range = route.get_trip_range(trip);
 
             let stop_times = network.stop_times[trip_range.clone()].iter().map(|stop_time|{
@@ -258,6 +543,14 @@ pub fn export_agent_counts(path: &str, network: &Network, simulation_result: &Si
                 arrivals.push(network.stops[arr_stop_idx as usize].name.as_ref());
                 assert!(agent_count >= 0, "Negative agent count: {}", agent_count);
                 agent_counts.push(agent_count as u32);
+                // Each route is one stopping pattern (see patterns.rs).
+                pattern_ids.push(route_idx as u32);
+                load_factors.push(agent_count as f32 / capacity as f32);
+                // Negative when over capacity.
+                spare_capacities.push(capacity as i32 - agent_count);
+                route_names.push(trip_labels.route_name);
+                directions.push(trip_labels.direction);
+                headsigns.push(trip_labels.headsign);
             }
         }
     }
@@ -279,28 +572,780 @@ pub fn export_agent_counts(path: &str, network: &Network, simulation_result: &Si
     let agent_counts_arr = Arc::new(UInt32Array::from(agent_counts.clone()));
     let agent_counts_field = Field::new("count", agent_counts_arr.data_type().clone(), false);
 
-    let schema = Arc::new(Schema::new(vec![trip_name_field, timestamp_field, departures_field, arrivals_field, agent_counts_field]));
-    // TODO: A record batch per trip? Sort trips by earliest departure time?
-    let record_batch = arrow::record_batch::RecordBatch::try_new(schema, vec![trip_names_arr, timestamps_arr, departures_arr, arrivals_arr, agent_counts_arr])?;
+    let pattern_ids_arr = Arc::new(UInt32Array::from(pattern_ids.clone()));
+    let pattern_ids_field = Field::new("pattern_id", pattern_ids_arr.data_type().clone(), false);
 
-    // Write to parquet.
-    let props = WriterProperties::builder()
-        .set_compression(Compression::SNAPPY)
-        .build();
-    let mut writer = parquet::arrow::ArrowWriter::try_new(File::create(path)?, record_batch.schema(), Some(props))?;
+    let load_factors_arr = Arc::new(Float32Array::from(load_factors.clone()));
+    let load_factors_field = Field::new("load_factor", load_factors_arr.data_type().clone(), false);
 
-    writer.write(&record_batch)?;
+    let spare_capacities_arr = Arc::new(Int32Array::from(spare_capacities.clone()));
+    let spare_capacities_field = Field::new("spare_capacity", spare_capacities_arr.data_type().clone(), false);
 
-    writer.close()?;
+    let route_names_arr = Arc::new(StringArray::from(route_names.clone()));
+    let route_names_field = Field::new("route_name", route_names_arr.data_type().clone(), true);
+
+    let directions_arr = Arc::new(UInt32Array::from(directions.clone()));
+    let directions_field = Field::new("direction", directions_arr.data_type().clone(), true);
+
+    let headsigns_arr = Arc::new(StringArray::from(headsigns.clone()));
+    let headsigns_field = Field::new("headsign", headsigns_arr.data_type().clone(), true);
+
+    let fields = vec![trip_name_field, route_names_field, directions_field, headsigns_field, timestamp_field, departures_field, arrivals_field, agent_counts_field, pattern_ids_field, load_factors_field, spare_capacities_field];
+    // TODO: A record batch per trip? Sort trips by earliest departure time?
+    let record_batch = columns.select(fields, vec![trip_names_arr, route_names_arr, directions_arr, headsigns_arr, timestamps_arr, departures_arr, arrivals_arr, agent_counts_arr, pattern_ids_arr, load_factors_arr, spare_capacities_arr])?;
+
+    write_parquet(path, &record_batch, compression)?;
 
     // Write to csv (for debugging).
     let csv_path = Path::new(path).with_extension("csv");
 
     let mut csv_writer = csv::Writer::from_path(csv_path)?;
-    csv_writer.write_record(&["trip_name", "timestamp", "departure", "arrival", "count"])?;
-    for (trip_name, timestamp, departure, arrival, count) in izip!(trip_names, timestamps, departures, arrivals, agent_counts) {
-        csv_writer.write_record(&[trip_name, &get_time_str((timestamp - date_timestamp) as Timestamp), departure, arrival, &count.to_string()])?;
+    let header = ["trip_name", "route_name", "direction", "headsign", "timestamp", "departure", "arrival", "count", "pattern_id", "load_factor", "spare_capacity"];
+    let included = header.map(|column| columns.includes(column));
+    csv_writer.write_record(header.iter().zip(included).filter_map(|(column, included)| included.then_some(column)))?;
+    for (trip_name, route_name, direction, headsign, timestamp, departure, arrival, count, pattern_id, load_factor, spare_capacity) in izip!(trip_names, route_names, directions, headsigns, timestamps, departures, arrivals, agent_counts, pattern_ids, load_factors, spare_capacities) {
+        let direction = direction.map_or(String::new(), |direction| direction.to_string());
+        let record: [&str; 11] = [trip_name, route_name.unwrap_or(""), &direction, headsign.unwrap_or(""), &get_time_str((timestamp - date_timestamp) as Timestamp), departure, arrival, &count.to_string(), &pattern_id.to_string(), &format!("{load_factor:.3}"), &spare_capacity.to_string()];
+        csv_writer.write_record(record.iter().zip(included).filter_map(|(value, included)| included.then_some(value)))?;
+    }
+
+    Ok(())
+}
+
+// A named range of departure times [start, end) to aggregate counts over.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeBand {
+    pub name: String,
+    #[serde(deserialize_with = "crate::config::time_of_day")]
+    pub start: Timestamp,
+    #[serde(deserialize_with = "crate::config::time_of_day")]
+    pub end: Timestamp,
+}
+
+impl TimeBand {
+    pub fn new(name: &str, start: Timestamp, end: Timestamp) -> Self {
+        Self { name: name.to_owned(), start, end }
+    }
+
+    // Fixed-size bins covering [start, end), named by their start time.
+    pub fn bins(start: Timestamp, end: Timestamp, size: Timestamp) -> Vec<Self> {
+        (start..end).step_by(size as usize).map(|bin_start| {
+            let bin_end = (bin_start + size).min(end);
+            Self { name: get_time_str(bin_start), start: bin_start, end: bin_end }
+        }).collect()
+    }
+}
+
+// Exports agent counts aggregated per time band and stop pair (in direction of travel) to a parquet file, with the
+// number of trips, total agents carried and busiest trip in each. Trip segments are assigned to bands by departure time,
+// and segments outside every band are dropped. Bands may overlap (e.g. "AM peak" and 15-minute bins together).
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(path = path)))]
+pub fn export_agent_counts_by_time_band(path: &str, network: &Network, simulation_result: &SimulationResult, time_bands: &[TimeBand], compression: ParquetCompression) -> Result<(), DataExportError> {
+    // Keyed by band and stop pair: (number of trips, total count, max count).
+    let mut band_counts: HashMap<(usize, u32, u32), (u32, u64, u32)> = HashMap::new();
+    for route in network.routes.iter() {
+        let route_stops = route.get_stops(&network.route_stops);
+        for trip in 0..route.num_trips as usize {
+            let trip_range = route.get_trip_range(trip);
+            let stop_times = &network.stop_times[trip_range.clone()];
+            let trip_agent_counts = &simulation_result.agent_journeys[trip_range];
+            for ((&dep_stop_idx, &arr_stop_idx), stop_time, &agent_count) in izip!(route_stops.iter().tuple_windows(), stop_times, trip_agent_counts) {
+                assert!(agent_count >= 0, "Negative agent count: {}", agent_count);
+                let departure_time = stop_time.departure_time;
+                for (band_idx, _) in time_bands.iter().enumerate().filter(|(_, band)| band.start <= departure_time && departure_time < band.end) {
+                    let entry = band_counts.entry((band_idx, dep_stop_idx, arr_stop_idx)).or_default();
+                    entry.0 += 1;
+                    entry.1 += agent_count as u64;
+                    entry.2 = entry.2.max(agent_count as u32);
+                }
+            }
+        }
+    }
+
+    let mut rows = band_counts.into_iter().collect::<Vec<_>>();
+    rows.sort_unstable_by_key(|&(key, _)| key);
+
+    let mut bands = Vec::new();
+    let mut band_starts = Vec::new();
+    let mut band_ends = Vec::new();
+    let mut departures = Vec::new();
+    let mut arrivals = Vec::new();
+    let mut num_trips = Vec::new();
+    let mut total_counts = Vec::new();
+    let mut max_counts = Vec::new();
+    for ((band_idx, dep_stop_idx, arr_stop_idx), (trips, total_count, max_count)) in rows {
+        let band = &time_bands[band_idx];
+        bands.push(band.name.as_str());
+        band_starts.push(get_time_str(band.start));
+        band_ends.push(get_time_str(band.end));
+        departures.push(&*network.stops[dep_stop_idx as usize].name);
+        arrivals.push(&*network.stops[arr_stop_idx as usize].name);
+        num_trips.push(trips);
+        total_counts.push(total_count);
+        max_counts.push(max_count);
     }
 
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("band", DataType::Utf8, false),
+        Field::new("band_start", DataType::Utf8, false),
+        Field::new("band_end", DataType::Utf8, false),
+        Field::new("departure", DataType::Utf8, false),
+        Field::new("arrival", DataType::Utf8, false),
+        Field::new("num_trips", DataType::UInt32, false),
+        Field::new("total_count", DataType::UInt64, false),
+        Field::new("max_count", DataType::UInt32, false),
+    ]));
+    let record_batch = RecordBatch::try_new(schema, vec![
+        Arc::new(StringArray::from(bands)),
+        Arc::new(StringArray::from(band_starts)),
+        Arc::new(StringArray::from(band_ends)),
+        Arc::new(StringArray::from(departures)),
+        Arc::new(StringArray::from(arrivals)),
+        Arc::new(UInt32Array::from(num_trips)),
+        Arc::new(UInt64Array::from(total_counts)),
+        Arc::new(UInt32Array::from(max_counts)),
+    ])?;
+
+    write_parquet(path, &record_batch, compression)?;
+
     Ok(())
 }
+
+// Exports the legs of every journey in the simulation steps to a parquet file, one row per leg, with the coordinates
+// of the boarded and alighted stops. Unless the geometry column is excluded, a WKT LINESTRING of the route shape
+// between the two stops is added, so individual journeys can be mapped directly. Columns not in `columns` are left out.
+// Each leg's generalised cost is broken down into in-vehicle time, waiting time, the journey's number of transfers and,
+// if the crowding costs of a simulation result are given (see simulation::crowding_costs), the crowding disutility.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(path = path)))]
+pub fn export_agent_journeys(path: &str, network: &Network, gtfs: &Gtfs, simulation_steps: &[AgentJourney], trip_stops_crowding_cost: Option<&[CrowdingCost]>, columns: &ExportColumns, compression: ParquetCompression) -> Result<(), DataExportError> {
+    let date_timestamp = network.date.and_time(chrono::NaiveTime::MIN).and_utc().timestamp();
+
+    let journey_legs = query_journey_legs(network, simulation_steps, None);
+    let include_shapes = columns.includes("geometry");
+
+    // Where each route's stops lie along its shape, only needed for leg geometry.
+    let route_shape_matches = if include_shapes {
+        route_shape_matches(network, gtfs, SHAPE_MATCH_TOLERANCE)
+    } else {
+        Vec::new()
+    };
+
+    let mut journey_ids = Vec::new();
+    let mut leg_numbers = Vec::new();
+    let mut trip_ids = Vec::new();
+    let mut route_names = Vec::new();
+    let mut directions = Vec::new();
+    let mut headsigns = Vec::new();
+    let mut board_stops = Vec::new();
+    let mut board_stop_ids = Vec::new();
+    let mut wait_times = Vec::new();
+    let mut board_longitudes = Vec::new();
+    let mut board_latitudes = Vec::new();
+    let mut board_times = Vec::new();
+    let mut alight_stops = Vec::new();
+    let mut alight_stop_ids = Vec::new();
+    let mut alight_longitudes = Vec::new();
+    let mut alight_latitudes = Vec::new();
+    let mut alight_times = Vec::new();
+    let mut counts = Vec::new();
+    let mut in_vehicle_times = Vec::new();
+    let mut transfers = Vec::new();
+    let mut crowding_costs = Vec::new();
+    let mut geometries = Vec::new();
+
+    for (journey_id, (journey, legs)) in simulation_steps.iter().zip(journey_legs.iter()).enumerate() {
+        // When the agent became ready to board the next leg (includes any transfer walk).
+        let mut ready_time = journey.start_time;
+        for (leg_number, leg) in legs.iter().enumerate() {
+            let route_idx = leg.route_idx;
+            let trip_idx = leg.trip_idx;
+            let boarded_stop_order = leg.boarded_stop_order;
+            let arrival_stop_order = leg.arrival_stop_order;
+
+            let route = &network.routes[route_idx];
+            let board_stop_idx = network.get_stop_in_route(route_idx, boarded_stop_order) as usize;
+            let alight_stop_idx = network.get_stop_in_route(route_idx, arrival_stop_order) as usize;
+            let board_point = network.stop_points[board_stop_idx];
+            let alight_point = network.stop_points[alight_stop_idx];
+
+            journey_ids.push(journey_id as u32);
+            leg_numbers.push(leg_number as u32);
+            let trip_id = route.trip_ids[trip_idx].as_ref();
+            let trip_labels = TripLabels::new(gtfs, trip_id);
+            // GTFS stop ids come from the trip's stop times, which are in route stop order.
+            let gtfs_stop_times = gtfs.trips.get(trip_id).map(|gtfs_trip| &gtfs_trip.stop_times);
+            let gtfs_stop_id = |stop_order: usize| gtfs_stop_times.and_then(|stop_times| stop_times.get(stop_order)).map(|stop_time| stop_time.stop.id.as_str());
+            trip_ids.push(trip_id);
+            route_names.push(trip_labels.route_name);
+            directions.push(trip_labels.direction);
+            headsigns.push(trip_labels.headsign);
+            board_stops.push(network.stops[board_stop_idx].name.as_ref());
+            board_longitudes.push(board_point.longitude);
+            board_latitudes.push(board_point.latitude);
+            let board_time = network.get_departure_time(route_idx, trip_idx, boarded_stop_order);
+            let alight_time = network.get_arrival_time(route_idx, trip_idx, arrival_stop_order);
+            board_stop_ids.push(gtfs_stop_id(boarded_stop_order));
+            wait_times.push(board_time.saturating_sub(ready_time));
+            ready_time = alight_time;
+            board_times.push((date_timestamp + board_time as i64) * 1000);
+            alight_stops.push(network.stops[alight_stop_idx].name.as_ref());
+            alight_stop_ids.push(gtfs_stop_id(arrival_stop_order));
+            alight_longitudes.push(alight_point.longitude);
+            alight_latitudes.push(alight_point.latitude);
+            alight_times.push((date_timestamp + alight_time as i64) * 1000);
+            counts.push(journey.count as u32);
+            in_vehicle_times.push(alight_time - board_time);
+            transfers.push(legs.len() as u32 - 1);
+            // Crowding cost over the span of trip stops this leg covers.
+            crowding_costs.push(trip_stops_crowding_cost.map(|trip_stops_cost| {
+                trip_stops_cost[route.get_trip_range(trip_idx)][boarded_stop_order..arrival_stop_order].iter().sum::<CrowdingCost>()
+            }));
+
+            if include_shapes {
+                // Same as the trip export: use the shape section between the stops, or a straight line if they matched out of order.
+                let shape_matches = &route_shape_matches[route_idx];
+                let start_shape_idx = shape_matches[boarded_stop_order].shape_idx;
+                let end_shape_idx = shape_matches[arrival_stop_order].shape_idx;
+                let straight_section = [board_point, alight_point];
+                let section_shape = if start_shape_idx < end_shape_idx {
+                    &route.shape[start_shape_idx..=end_shape_idx]
+                } else {
+                    &straight_section[..]
+                };
+                let coordinates = section_shape.iter().map(|point| format!("{} {}", point.longitude, point.latitude)).join(", ");
+                geometries.push(Some(format!("LINESTRING ({coordinates})")));
+            } else {
+                geometries.push(None);
+            }
+        }
+    }
+
+    let mut fields = vec![
+        Field::new("journey_id", DataType::UInt32, false),
+        Field::new("leg", DataType::UInt32, false),
+        Field::new("trip_id", DataType::Utf8, false),
+        Field::new("route_name", DataType::Utf8, true),
+        Field::new("direction", DataType::UInt32, true),
+        Field::new("headsign", DataType::Utf8, true),
+        Field::new("board_stop", DataType::Utf8, false),
+        Field::new("board_stop_id", DataType::Utf8, true),
+        // Seconds between arriving at the boarding stop (or starting the journey) and the trip departing.
+        Field::new("wait_seconds", DataType::UInt32, false),
+        Field::new("board_longitude", DataType::Float32, false),
+        Field::new("board_latitude", DataType::Float32, false),
+        Field::new("board_time", DataType::Timestamp(arrow::datatypes::TimeUnit::Millisecond, None), false),
+        Field::new("alight_stop", DataType::Utf8, false),
+        Field::new("alight_stop_id", DataType::Utf8, true),
+        Field::new("alight_longitude", DataType::Float32, false),
+        Field::new("alight_latitude", DataType::Float32, false),
+        Field::new("alight_time", DataType::Timestamp(arrow::datatypes::TimeUnit::Millisecond, None), false),
+        Field::new("count", DataType::UInt32, false),
+        Field::new("in_vehicle_seconds", DataType::UInt32, false),
+        // Transfers in the whole journey, repeated on each leg.
+        Field::new("num_transfers", DataType::UInt32, false),
+        Field::new("crowding_cost", DataType::Float32, true),
+    ];
+    let mut arrays: Vec<ArrayRef> = vec![
+        Arc::new(UInt32Array::from(journey_ids)),
+        Arc::new(UInt32Array::from(leg_numbers)),
+        Arc::new(StringArray::from(trip_ids)),
+        Arc::new(StringArray::from(route_names)),
+        Arc::new(UInt32Array::from(directions)),
+        Arc::new(StringArray::from(headsigns)),
+        Arc::new(StringArray::from(board_stops)),
+        Arc::new(StringArray::from(board_stop_ids)),
+        Arc::new(UInt32Array::from(wait_times)),
+        Arc::new(Float32Array::from(board_longitudes)),
+        Arc::new(Float32Array::from(board_latitudes)),
+        Arc::new(TimestampMillisecondArray::from(board_times)),
+        Arc::new(StringArray::from(alight_stops)),
+        Arc::new(StringArray::from(alight_stop_ids)),
+        Arc::new(Float32Array::from(alight_longitudes)),
+        Arc::new(Float32Array::from(alight_latitudes)),
+        Arc::new(TimestampMillisecondArray::from(alight_times)),
+        Arc::new(UInt32Array::from(counts)),
+        Arc::new(UInt32Array::from(in_vehicle_times)),
+        Arc::new(UInt32Array::from(transfers)),
+        Arc::new(Float32Array::from(crowding_costs)),
+    ];
+    if include_shapes {
+        fields.push(Field::new("geometry", DataType::Utf8, true));
+        arrays.push(Arc::new(StringArray::from(geometries)));
+    }
+
+    write_parquet(path, &columns.select(fields, arrays)?, compression)?;
+
+    Ok(())
+}
+
+// Exports, per station and time band, the agents starting their journey there (originating), ending it there
+// (terminating), and changing between trips there (interchanging), for station sizing studies.
+// Events are assigned to bands by when they happen: journey start, final arrival, or arrival before the interchange.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(path = path)))]
+pub fn export_station_interchanges(path: &str, network: &Network, simulation_steps: &[AgentJourney], time_bands: &[TimeBand], compression: ParquetCompression) -> Result<(), DataExportError> {
+    let journey_legs = query_journey_legs(network, simulation_steps, None);
+
+    // Keyed by band and stop: [originating, terminating, interchanging].
+    const ORIGINATING: usize = 0;
+    const TERMINATING: usize = 1;
+    const INTERCHANGING: usize = 2;
+    let mut station_counts: HashMap<(usize, usize), [u64; 3]> = HashMap::new();
+    let mut add_event = |stop_idx: usize, time: Timestamp, count: u64, event: usize| {
+        for (band_idx, _) in time_bands.iter().enumerate().filter(|(_, band)| band.start <= time && time < band.end) {
+            station_counts.entry((band_idx, stop_idx)).or_default()[event] += count;
+        }
+    };
+
+    for (journey, legs) in simulation_steps.iter().zip(journey_legs.iter()) {
+        let (Some(first_leg), Some(last_leg)) = (legs.first(), legs.last()) else {
+            continue;
+        };
+        let count = journey.count as u64;
+
+        let origin_stop_idx = network.get_stop_in_route(first_leg.route_idx, first_leg.boarded_stop_order) as usize;
+        add_event(origin_stop_idx, journey.start_time, count, ORIGINATING);
+
+        let destination_stop_idx = network.get_stop_in_route(last_leg.route_idx, last_leg.arrival_stop_order) as usize;
+        let arrival_time = network.get_arrival_time(last_leg.route_idx, last_leg.trip_idx, last_leg.arrival_stop_order);
+        add_event(destination_stop_idx, arrival_time, count, TERMINATING);
+
+        // Interchanges are counted at the stop the agent alights at, including when they walk to another stop.
+        for leg in legs[..legs.len() - 1].iter() {
+            let stop_idx = network.get_stop_in_route(leg.route_idx, leg.arrival_stop_order) as usize;
+            let time = network.get_arrival_time(leg.route_idx, leg.trip_idx, leg.arrival_stop_order);
+            add_event(stop_idx, time, count, INTERCHANGING);
+        }
+    }
+
+    let mut rows = station_counts.into_iter().collect::<Vec<_>>();
+    rows.sort_unstable_by_key(|&(key, _)| key);
+
+    let mut bands = Vec::new();
+    let mut stations = Vec::new();
+    let mut originating = Vec::new();
+    let mut terminating = Vec::new();
+    let mut interchanging = Vec::new();
+    for ((band_idx, stop_idx), [origin_count, destination_count, interchange_count]) in rows {
+        bands.push(time_bands[band_idx].name.as_str());
+        stations.push(&*network.stops[stop_idx].name);
+        originating.push(origin_count);
+        terminating.push(destination_count);
+        interchanging.push(interchange_count);
+    }
+
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("band", DataType::Utf8, false),
+        Field::new("station", DataType::Utf8, false),
+        Field::new("originating", DataType::UInt64, false),
+        Field::new("terminating", DataType::UInt64, false),
+        Field::new("interchanging", DataType::UInt64, false),
+    ]));
+    let record_batch = RecordBatch::try_new(schema, vec![
+        Arc::new(StringArray::from(bands)),
+        Arc::new(StringArray::from(stations)),
+        Arc::new(UInt64Array::from(originating)),
+        Arc::new(UInt64Array::from(terminating)),
+        Arc::new(UInt64Array::from(interchanging)),
+    ])?;
+
+    write_parquet(path, &record_batch, compression)?;
+
+    Ok(())
+}
+
+// Exports entries and exits per station and time bin in the layout of typical gate-count data (one row per station,
+// date and bin, with bin start and end times), so results can be checked against the operator's gate counts.
+// Entries are at the first boarding stop at the journey start time; exits are at the final stop at the arrival time.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(path = path)))]
+pub fn export_gate_flows(path: &str, network: &Network, simulation_steps: &[AgentJourney], bin_size: Timestamp) -> Result<(), DataExportError> {
+    if bin_size == 0 {
+        return Err(DataExportError::ZeroBinSize);
+    }
+    let journey_legs = query_journey_legs(network, simulation_steps, None);
+
+    // Keyed by stop and bin: (entries, exits).
+    let mut flows: HashMap<(usize, Timestamp), (u64, u64)> = HashMap::new();
+    for (journey, legs) in simulation_steps.iter().zip(journey_legs.iter()) {
+        let (Some(first_leg), Some(last_leg)) = (legs.first(), legs.last()) else {
+            continue;
+        };
+        let count = journey.count as u64;
+
+        let entry_stop_idx = network.get_stop_in_route(first_leg.route_idx, first_leg.boarded_stop_order) as usize;
+        flows.entry((entry_stop_idx, journey.start_time / bin_size)).or_default().0 += count;
+
+        let exit_stop_idx = network.get_stop_in_route(last_leg.route_idx, last_leg.arrival_stop_order) as usize;
+        let exit_time = network.get_arrival_time(last_leg.route_idx, last_leg.trip_idx, last_leg.arrival_stop_order);
+        flows.entry((exit_stop_idx, exit_time / bin_size)).or_default().1 += count;
+    }
+
+    let mut rows = flows.into_iter().collect::<Vec<_>>();
+    rows.sort_unstable_by_key(|&((stop_idx, bin), _)| (&*network.stops[stop_idx].name, bin));
+
+    let date = network.date.to_string();
+    let mut csv_writer = csv::Writer::from_path(path)?;
+    csv_writer.write_record(["station", "date", "bin_start", "bin_end", "entries", "exits"])?;
+    for ((stop_idx, bin), (entries, exits)) in rows {
+        csv_writer.write_record([&*network.stops[stop_idx].name, &date, &get_time_str(bin * bin_size), &get_time_str((bin + 1) * bin_size), &entries.to_string(), &exits.to_string()])?;
+    }
+
+    Ok(())
+}
+
+// Exports a (band, from_line, to_line, volume) table of agents changing between lines, for Sankey diagrams of
+// interchange movements. Transfers are banded by the arrival time of the leg before the transfer; without bands, the
+// whole day is one band. Changes between trips of the same line are included.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(path = path)))]
+pub fn export_line_transfers(path: &str, network: &Network, simulation_steps: &[AgentJourney], time_bands: Option<&[TimeBand]>) -> Result<(), DataExportError> {
+    let all_day = [TimeBand::new("all day", 0, Timestamp::MAX)];
+    let time_bands = time_bands.unwrap_or(&all_day);
+
+    let journey_legs = query_journey_legs(network, simulation_steps, None);
+
+    let mut transfer_volumes: HashMap<(usize, &str, &str), u64> = HashMap::new();
+    for (journey, legs) in simulation_steps.iter().zip(journey_legs.iter()) {
+        for (from_leg, to_leg) in legs.iter().tuple_windows() {
+            let from_line = &*network.routes[from_leg.route_idx].line;
+            let to_line = &*network.routes[to_leg.route_idx].line;
+            let time = network.get_arrival_time(from_leg.route_idx, from_leg.trip_idx, from_leg.arrival_stop_order);
+            for (band_idx, _) in time_bands.iter().enumerate().filter(|(_, band)| band.start <= time && time < band.end) {
+                *transfer_volumes.entry((band_idx, from_line, to_line)).or_default() += journey.count as u64;
+            }
+        }
+    }
+
+    let mut rows = transfer_volumes.into_iter().collect::<Vec<_>>();
+    rows.sort_unstable();
+
+    let mut csv_writer = csv::Writer::from_path(path)?;
+    csv_writer.write_record(["band", "from_line", "to_line", "volume"])?;
+    for ((band_idx, from_line, to_line), volume) in rows {
+        csv_writer.write_record([&*time_bands[band_idx].name, from_line, to_line, &volume.to_string()])?;
+    }
+
+    Ok(())
+}
+
+// Exports space-time traces of selected journeys for animating individual agents, interpolating each agent's position
+// along the route shapes every time_step seconds, from its start time until it reaches its destination:
+// - The journey index (into simulation_steps) of each traced agent, one u32 each.
+// - The start index of each agent's trace in the point list, one u32 each.
+// - The (longitude, latitude) of each trace point.
+// - The time of each trace point (seconds since midnight, as f32).
+// Agents wait at stops between legs.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(path = path)))]
+pub fn export_agent_traces(path: &str, network: &Network, gtfs: &Gtfs, simulation_steps: &[AgentJourney], journey_indices: &[usize], time_step: Timestamp) -> Result<(), DataExportError> {
+    if time_step == 0 {
+        return Err(DataExportError::ZeroBinSize);
+    }
+    let traced_steps = journey_indices.iter()
+        .map(|&journey_idx| simulation_steps.get(journey_idx).copied().ok_or(DataExportError::JourneyIndex(journey_idx)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let journey_legs = query_journey_legs(network, &traced_steps, None);
+    let route_shape_matches = route_shape_matches(network, gtfs, SHAPE_MATCH_TOLERANCE);
+
+    let mut agent_ids = Vec::new();
+    let mut start_indices = Vec::new();
+    let mut trace_points = Vec::new();
+    let mut trace_times = Vec::new();
+
+    for ((&journey_idx, journey), legs) in journey_indices.iter().zip(traced_steps.iter()).zip(journey_legs.iter()) {
+        if legs.is_empty() {
+            continue;
+        }
+
+        // Build a list of (time, position) keyframes, starting at the origin stop.
+        let mut keyframes = vec![(journey.start_time as f32, network.stop_points[journey.start_stop as usize])];
+        for leg in legs.iter() {
+            let route_idx = leg.route_idx;
+            let trip_idx = leg.trip_idx;
+            let route = &network.routes[route_idx];
+            let shape_matches = &route_shape_matches[route_idx];
+
+            for dep_stop_order in leg.boarded_stop_order..leg.arrival_stop_order {
+                let arr_stop_order = dep_stop_order + 1;
+                let departure_time = network.get_departure_time(route_idx, trip_idx, dep_stop_order) as f32;
+                let arrival_time = network.get_arrival_time(route_idx, trip_idx, arr_stop_order) as f32;
+                let dep_point = network.stop_points[network.get_stop_in_route(route_idx, dep_stop_order) as usize];
+                let arr_point = network.stop_points[network.get_stop_in_route(route_idx, arr_stop_order) as usize];
+
+                // Same section logic as the trip export, without the easing.
+                let start_shape_idx = shape_matches[dep_stop_order].shape_idx;
+                let end_shape_idx = shape_matches[arr_stop_order].shape_idx;
+                let straight_section = [dep_point, arr_point];
+                let section_shape = if start_shape_idx < end_shape_idx {
+                    &route.shape[start_shape_idx..=end_shape_idx]
+                } else {
+                    &straight_section[..]
+                };
+                let section_length: f32 = section_shape.iter().tuple_windows().map(|(a, b)| a.distance(*b)).sum();
+
+                let mut distance = 0.;
+                for (i, &point) in section_shape.iter().enumerate() {
+                    let proportion = if section_length > 0. { distance / section_length } else { 0. };
+                    keyframes.push((departure_time + (arrival_time - departure_time) * proportion, point));
+                    distance += section_shape.get(i + 1).map_or(0., |&next_point| point.distance(next_point));
+                }
+            }
+        }
+
+        // Sample the keyframes at each time step.
+        agent_ids.push(journey_idx as u32);
+        start_indices.push(trace_times.len() as u32);
+        let end_time = keyframes[keyframes.len() - 1].0;
+        let mut keyframe_idx = 0;
+        let mut time = journey.start_time as f32;
+        loop {
+            let time_clamped = time.min(end_time);
+            while keyframe_idx + 1 < keyframes.len() - 1 && keyframes[keyframe_idx + 1].0 <= time_clamped {
+                keyframe_idx += 1;
+            }
+            let (time_a, point_a) = keyframes[keyframe_idx];
+            let (time_b, point_b) = keyframes[(keyframe_idx + 1).min(keyframes.len() - 1)];
+            let t = if time_b > time_a { ((time_clamped - time_a) / (time_b - time_a)).clamp(0., 1.) } else { 1. };
+            trace_points.push(point_a.longitude + (point_b.longitude - point_a.longitude) * t);
+            trace_points.push(point_a.latitude + (point_b.latitude - point_a.latitude) * t);
+            trace_times.push(time_clamped);
+
+            if time >= end_time {
+                break;
+            }
+            time += time_step as f32;
+        }
+    }
+
+    write_bin(path, &[(b"AIDS", bytemuck::must_cast_slice(&agent_ids)), (b"STRT", bytemuck::must_cast_slice(&start_indices)), (b"PNTS", bytemuck::must_cast_slice(&trace_points)), (b"TIME", bytemuck::must_cast_slice(&trace_times))])?;
+
+    Ok(())
+}
+
+// Layout of the per-trip occupancy table.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OccupancyFormat {
+    // One row per trip stop.
+    #[default]
+    Long,
+    // One row per trip, with a count column per stop in the sequence.
+    Wide,
+}
+
+// Exports each trip's load departing each stop to a parquet file, keyed by trip_id and stop_sequence.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(path = path)))]
+pub fn export_trip_occupancy(path: &str, network: &Network, simulation_result: &SimulationResult, format: OccupancyFormat, compression: ParquetCompression) -> Result<(), DataExportError> {
+    let date_timestamp = network.date.and_time(chrono::NaiveTime::MIN).and_utc().timestamp();
+
+    let record_batch = match format {
+        OccupancyFormat::Long => {
+            let mut trip_ids = Vec::new();
+            let mut stop_sequences = Vec::new();
+            let mut stops = Vec::new();
+            let mut timestamps = Vec::new();
+            let mut agent_counts = Vec::new();
+
+            for route in network.routes.iter() {
+                let route_stops = route.get_stops(&network.route_stops);
+                for trip in 0..route.num_trips as usize {
+                    let trip_range = route.get_trip_range(trip);
+                    let stop_times = &network.stop_times[trip_range.clone()];
+                    let trip_agent_counts = &simulation_result.agent_journeys[trip_range];
+                    for (stop_sequence, (&stop_idx, stop_time, &agent_count)) in izip!(route_stops, stop_times, trip_agent_counts).enumerate() {
+                        assert!(agent_count >= 0, "Negative agent count: {}", agent_count);
+                        trip_ids.push(route.trip_ids[trip].as_ref());
+                        stop_sequences.push(stop_sequence as u32);
+                        stops.push(network.stops[stop_idx as usize].name.as_ref());
+                        timestamps.push((date_timestamp + stop_time.departure_time as i64) * 1000);
+                        agent_counts.push(agent_count as u32);
+                    }
+                }
+            }
+
+            let trip_ids_arr = Arc::new(StringArray::from(trip_ids));
+            let stop_sequences_arr = Arc::new(UInt32Array::from(stop_sequences));
+            let stops_arr = Arc::new(StringArray::from(stops));
+            let timestamps_arr = Arc::new(TimestampMillisecondArray::from(timestamps));
+            let agent_counts_arr = Arc::new(UInt32Array::from(agent_counts));
+
+            let schema = Arc::new(Schema::new(vec![
+                Field::new("trip_id", trip_ids_arr.data_type().clone(), false),
+                Field::new("stop_sequence", stop_sequences_arr.data_type().clone(), false),
+                Field::new("stop", stops_arr.data_type().clone(), false),
+                Field::new("timestamp", timestamps_arr.data_type().clone(), false),
+                Field::new("count", agent_counts_arr.data_type().clone(), false),
+            ]));
+            RecordBatch::try_new(schema, vec![trip_ids_arr, stop_sequences_arr, stops_arr, timestamps_arr, agent_counts_arr])?
+        }
+        OccupancyFormat::Wide => {
+            let max_stops = (0..network.num_routes()).map(|route_idx| network.num_stops_in_route(route_idx)).max().unwrap_or(0);
+
+            let mut trip_ids = Vec::new();
+            // One column per stop sequence, null where the trip has fewer stops.
+            let mut columns = vec![Vec::new(); max_stops];
+
+            for route in network.routes.iter() {
+                for trip in 0..route.num_trips as usize {
+                    trip_ids.push(route.trip_ids[trip].as_ref());
+                    let trip_agent_counts = &simulation_result.agent_journeys[route.get_trip_range(trip)];
+                    for (stop_sequence, column) in columns.iter_mut().enumerate() {
+                        column.push(trip_agent_counts.get(stop_sequence).map(|&count| {
+                            assert!(count >= 0, "Negative agent count: {}", count);
+                            count as u32
+                        }));
+                    }
+                }
+            }
+
+            let mut fields = vec![Field::new("trip_id", DataType::Utf8, false)];
+            let mut arrays: Vec<ArrayRef> = vec![Arc::new(StringArray::from(trip_ids))];
+            for (stop_sequence, column) in columns.into_iter().enumerate() {
+                fields.push(Field::new(format!("stop_{stop_sequence}"), DataType::UInt32, true));
+                arrays.push(Arc::new(UInt32Array::from(column)));
+            }
+            RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays)?
+        }
+    };
+
+    write_parquet(path, &record_batch, compression)?;
+
+    Ok(())
+}
+
+// Exports the capacity applied to every trip, and the rule (if any) it came from, so configuration mistakes are visible.
+pub fn export_trip_capacities(path: &str, network: &Network, trip_capacities: &TripCapacities, resolved_capacities: &ResolvedTripCapacities) -> Result<(), DataExportError> {
+    let mut csv_writer = csv::Writer::from_path(path)?;
+    csv_writer.write_record(["trip_id", "line", "capacity", "source", "source_row", "rule_type", "rule_key"])?;
+    for (route_idx, route) in network.routes.iter().enumerate() {
+        for trip_idx in 0..route.num_trips as usize {
+            let resolved = resolved_capacities.get(route_idx, trip_idx);
+            let (source, source_row, rule_type, rule_key) = match resolved.rule_idx {
+                Some(rule_idx) => {
+                    let rule = &trip_capacities.rules[rule_idx];
+                    let (rule_type, rule_key) = match &rule.matcher {
+                        CapacityMatcher::TripId(key) => ("trip_id", key.as_str()),
+                        CapacityMatcher::TripPattern(key) => ("trip_pattern", key.as_str()),
+                        CapacityMatcher::RouteId(key) => ("route_id", key.as_str()),
+                        CapacityMatcher::RouteName(key) => ("route_name", key.as_str()),
+                    };
+                    ("rule", rule.source_row.to_string(), rule_type, rule_key)
+                }
+                None => ("default", String::new(), "", ""),
+            };
+            csv_writer.write_record([&*route.trip_ids[trip_idx], &*route.line, &resolved.capacity.to_string(), source, &source_row, rule_type, rule_key])?;
+        }
+    }
+
+    Ok(())
+}
+
+// Machine-readable record of what produced a set of exports, for auditability.
+#[derive(Serialize)]
+pub struct RunManifest {
+    pub tool_version: String,
+    pub gtfs_path: String,
+    // Missing if the feed couldn't be read for hashing (see hash_gtfs).
+    pub gtfs_sha256: Option<String>,
+    pub model_date: NaiveDate,
+    pub max_train_capacity: AgentCount,
+    pub crowding_function: CrowdingFunction,
+    // Crowding functions of particular GTFS routes, which override crowding_function.
+    pub route_crowding_functions: BTreeMap<String, CrowdingFunction>,
+    // Whether crowding is charged per passenger-km rather than per segment.
+    pub crowding_per_km: bool,
+    pub num_steps: usize,
+    pub seed: Option<u64>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    // Paths of the files exported in this run.
+    pub exports: Vec<String>,
+    // Wall-clock time of each stage of the run, in milliseconds.
+    pub timings_ms: BTreeMap<String, u128>,
+    // Fraction of the demand simulated, if the run was on a sample. Counts in the exports are expanded by 1 / fraction.
+    pub sample_fraction: Option<f64>,
+}
+
+impl RunManifest {
+    pub fn new(gtfs_path: &str, model_date: NaiveDate, max_train_capacity: AgentCount, crowding_function: CrowdingFunction, num_steps: usize, seed: Option<u64>, started_at: DateTime<Utc>) -> Self {
+        Self {
+            tool_version: env!("CARGO_PKG_VERSION").to_owned(),
+            gtfs_path: gtfs_path.to_owned(),
+            gtfs_sha256: hash_gtfs(gtfs_path).ok(),
+            model_date,
+            max_train_capacity,
+            crowding_function,
+            route_crowding_functions: BTreeMap::new(),
+            crowding_per_km: false,
+            num_steps,
+            seed,
+            started_at,
+            finished_at: started_at,
+            exports: Vec::new(),
+            timings_ms: BTreeMap::new(),
+            sample_fraction: None,
+        }
+    }
+
+    pub fn record_timing(&mut self, stage: &str, duration: Duration) {
+        self.timings_ms.insert(stage.to_owned(), duration.as_millis());
+    }
+}
+
+// SHA-256 of a GTFS feed, which is either a zip file or a directory. A directory is hashed over its *.txt files (names
+// and contents) in name order, so the hash doesn't depend on how the files are listed.
+pub fn hash_gtfs(path: &str) -> std::io::Result<String> {
+    let path = Path::new(path);
+    let mut hasher = Sha256::new();
+    if path.is_dir() {
+        let mut files = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        files.retain(|file| file.is_file() && file.extension().is_some_and(|extension| extension == "txt"));
+        files.sort();
+        for file in files {
+            hasher.update(file.file_name().unwrap_or_default().to_string_lossy().as_bytes());
+            std::io::copy(&mut File::open(&file)?, &mut hasher)?;
+        }
+    } else {
+        std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// The current time, as a UTC timestamp for manifests.
+pub fn utc_now() -> DateTime<Utc> {
+    let since_epoch = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    DateTime::from_timestamp(since_epoch.as_secs() as i64, since_epoch.subsec_nanos()).unwrap_or_default()
+}
+
+// Writes the run manifest as pretty-printed JSON, stamping the finish time.
+pub fn export_run_manifest(path: &str, manifest: &mut RunManifest) -> Result<(), DataExportError> {
+    manifest.finished_at = utc_now();
+    serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), manifest)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_import::read_bin;
+
+    #[test]
+    fn bin_round_trip() {
+        let path = std::env::temp_dir().join(format!("train-ute-bin-round-trip-{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+        // Lengths that aren't multiples of eight, so the chunks are padded.
+        let chunks: [(&[u8; 4], &[u8]); 3] = [(b"STOP", &[1, 2, 3]), (b"NONE", &[]), (b"TRIP", &[4, 5, 6, 7, 8, 9, 10, 11, 12])];
+        write_bin(path, &chunks).unwrap();
+        let bin_file = read_bin(path);
+        std::fs::remove_file(path).unwrap();
+
+        let bin_file = bin_file.unwrap();
+        assert_eq!(bin_file.version, BIN_VERSION);
+        assert_eq!(bin_file.chunks.len(), chunks.len());
+        for ((tag, data), (expected_tag, expected_data)) in bin_file.chunks.iter().zip(chunks) {
+            assert_eq!(tag, expected_tag);
+            assert_eq!(&data[..], expected_data);
+        }
+    }
+}
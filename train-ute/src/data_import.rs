@@ -1,3 +1,473 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::sync::Arc;
+
+use arrow::array::{Date32Array, RecordBatch, StringArray, Time64MicrosecondArray, UInt16Array};
+use arrow::datatypes::{DataType, Date32Type, Field, Schema, TimeUnit};
+use chrono::NaiveDate;
+use thiserror::Error;
+
+use raptor::Network;
+use raptor::network::Timestamp;
+
+use crate::capacities::{CapacityMatcher, CapacityRule, TripCapacities};
+use crate::comparison::ObservedBoarding;
+use crate::crowding::{self, CrowdingFunction};
+use crate::data_export::{BIN_MAGIC, BIN_VERSION};
+use crate::demand::StopWeight;
+use crate::fares::FareTable;
+use crate::line_injection::LineStop;
+use crate::simulation::{spread_departure_window, AgentCount, AgentJourney, DepartureProfile, JourneyLeg, PinnedPath};
+
+#[derive(Error, Debug)]
+pub enum DataImportError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("Arrow error: {0}")]
+    Arrow(#[from] arrow::error::ArrowError),
+    #[error("Invalid row {row}: {reason}")]
+    InvalidRow { row: usize, reason: String },
+    #[error("Missing column {0}")]
+    MissingColumn(&'static str),
+    #[error("Zip error: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("Invalid binary file: {0}")]
+    InvalidBin(String),
+}
+
+// Imports trip capacity rules from a CSV with columns type,key,capacity, where type is one of
+// trip_id, trip_pattern (a glob over trip ids), route_id or route_name (GTFS route short name).
+pub fn import_trip_capacities(path: &str) -> Result<TripCapacities, DataImportError> {
+    let mut reader = csv::Reader::from_path(path)?;
+
+    let mut rules = Vec::new();
+    for (i, record) in reader.records().enumerate() {
+        let record = record?;
+        let row = i + 1;
+        let invalid = |reason: &str| DataImportError::InvalidRow { row, reason: reason.to_owned() };
+
+        let (Some(key_type), Some(key), Some(capacity)) = (record.get(0), record.get(1), record.get(2)) else {
+            return Err(invalid("expected type,key,capacity"));
+        };
+        let key = key.trim().to_owned();
+        let matcher = match key_type.trim() {
+            "trip_id" => CapacityMatcher::TripId(key),
+            "trip_pattern" => CapacityMatcher::TripPattern(key),
+            "route_id" => CapacityMatcher::RouteId(key),
+            "route_name" => CapacityMatcher::RouteName(key),
+            other => return Err(invalid(&format!("unknown type '{other}'"))),
+        };
+        let capacity = capacity.trim().parse::<AgentCount>().map_err(|_| invalid("capacity is not a valid number"))?;
+        if capacity == 0 {
+            return Err(invalid("capacity must be greater than zero"));
+        }
+
+        rules.push(CapacityRule { matcher, capacity, source_row: row });
+    }
+
+    Ok(TripCapacities { rules })
+}
+
+// Parses a date as either YYYY-MM-DD or DD/MM/YYYY.
+fn parse_date(date: &str) -> Option<NaiveDate> {
+    let parts = date.trim().split(['-', '/']).map(|part| part.parse::<u32>().ok()).collect::<Option<Vec<_>>>()?;
+    match parts[..] {
+        [year, month, day] if date.contains('-') => NaiveDate::from_ymd_opt(year as i32, month, day),
+        [day, month, year] => NaiveDate::from_ymd_opt(year as i32, month, day),
+        _ => None,
+    }
+}
+
+// Parses a time of day as HH:MM[:SS] into seconds since midnight. Hours past 24 are allowed, as in GTFS, but minutes
+// and seconds must be under 60.
+pub fn parse_time_seconds(time: &str) -> Option<i64> {
+    let parts = time.trim().split(':').map(|part| part.parse::<u32>().ok()).collect::<Option<Vec<_>>>()?;
+    let (hours, minutes, seconds) = match parts[..] {
+        [hours, minutes] => (hours, minutes, 0),
+        [hours, minutes, seconds] => (hours, minutes, seconds),
+        _ => return None,
+    };
+    if minutes >= 60 || seconds >= 60 {
+        return None;
+    }
+    Some(hours as i64 * 3600 + minutes as i64 * 60 + seconds as i64)
+}
+
+// Parses a duration as [[HH:]MM:]SS, so "1:30" is 90 seconds and "90" is too. Durations that overflow are rejected.
+fn parse_duration_seconds(duration: &str) -> Option<u32> {
+    let parts = duration.trim().split(':').map(|part| part.parse::<u32>().ok()).collect::<Option<Vec<_>>>()?;
+    match parts[..] {
+        [seconds] => Some(seconds),
+        [minutes, seconds] => minutes.checked_mul(60)?.checked_add(seconds),
+        [hours, minutes, seconds] => hours.checked_mul(3600)?.checked_add(minutes.checked_mul(60)?)?.checked_add(seconds),
+        _ => None,
+    }
+}
+
+fn parse_time_micros(time: &str) -> Option<i64> {
+    parse_time_seconds(time).map(|seconds| seconds * 1_000_000)
+}
+
+// Reads the raw PTV passenger count CSV, keeping only rows for the given mode (e.g. "Metro") that have boardings or
+// alightings, and only the columns the simulation needs. This replaces the separate patronage_data_processing step.
+// The result has the same schema as that tool's parquet output, so it can be saved with data_export::write_parquet.
+pub fn import_patronage_csv(path: &str, mode: &str) -> Result<RecordBatch, DataImportError> {
+    let mut reader = csv::Reader::from_path(path)?;
+
+    let headers = reader.headers()?.clone();
+    let column = |name: &'static str| headers.iter().position(|header| header == name).ok_or(DataImportError::MissingColumn(name));
+    let mode_idx = column("Mode")?;
+    let date_idx = column("Business_Date")?;
+    let station_idx = column("Station_Name")?;
+    let arrival_idx = column("Arrival_Time_Scheduled")?;
+    let departure_idx = column("Departure_Time_Scheduled")?;
+    let boardings_idx = column("Passenger_Boardings")?;
+    let alightings_idx = column("Passenger_Alightings")?;
+
+    let mut dates = Vec::new();
+    let mut station_names = Vec::new();
+    let mut arrival_times = Vec::new();
+    let mut departure_times = Vec::new();
+    let mut boardings = Vec::new();
+    let mut alightings = Vec::new();
+
+    let mut record = csv::StringRecord::new();
+    let mut row = 0;
+    while reader.read_record(&mut record)? {
+        row += 1;
+        let invalid = |reason: &str| DataImportError::InvalidRow { row, reason: reason.to_owned() };
+
+        if &record[mode_idx] != mode {
+            continue;
+        }
+        let passenger_boardings = record[boardings_idx].trim().parse::<u16>().map_err(|_| invalid("invalid Passenger_Boardings"))?;
+        let passenger_alightings = record[alightings_idx].trim().parse::<u16>().map_err(|_| invalid("invalid Passenger_Alightings"))?;
+        if passenger_boardings == 0 && passenger_alightings == 0 {
+            continue;
+        }
+
+        let date = parse_date(&record[date_idx]).ok_or_else(|| invalid("invalid Business_Date"))?;
+        dates.push(Date32Type::from_naive_date(date));
+        station_names.push(record[station_idx].to_owned());
+        // Times can be missing at the start and end of a trip.
+        arrival_times.push(parse_time_micros(&record[arrival_idx]));
+        departure_times.push(parse_time_micros(&record[departure_idx]));
+        boardings.push(passenger_boardings);
+        alightings.push(passenger_alightings);
+    }
+
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("Business_Date", DataType::Date32, false),
+        Field::new("Station_Name", DataType::Utf8, false),
+        Field::new("Arrival_Time_Scheduled", DataType::Time64(TimeUnit::Microsecond), true),
+        Field::new("Departure_Time_Scheduled", DataType::Time64(TimeUnit::Microsecond), true),
+        Field::new("Passenger_Boardings", DataType::UInt16, false),
+        Field::new("Passenger_Alightings", DataType::UInt16, false),
+    ]));
+
+    Ok(RecordBatch::try_new(schema, vec![
+        Arc::new(Date32Array::from(dates)),
+        Arc::new(StringArray::from(station_names)),
+        Arc::new(Time64MicrosecondArray::from(arrival_times)),
+        Arc::new(Time64MicrosecondArray::from(departure_times)),
+        Arc::new(UInt16Array::from(boardings)),
+        Arc::new(UInt16Array::from(alightings)),
+    ])?)
+}
+
+// Imports OD demand from a CSV with columns origin,destination,window_start,window_end,count, where origin and
+// destination are stop names and the window is HH:MM[:SS]. Each row's count is spread over its departure window
+// using the profile. An empty window_end means the count departs exactly at window_start.
+// Counts can be fractional expansion weights (e.g. 2.37). Agents are whole, so each row's count is rounded to the
+// nearest agent on its own, keeping agents on their OD pair. Carrying fractional weights through the simulation
+// is not supported yet.
+pub fn import_od_windows(path: &str, network: &Network, profile: &DepartureProfile) -> Result<Vec<AgentJourney>, DataImportError> {
+    let mut reader = csv::Reader::from_path(path)?;
+
+    let mut simulation_steps = Vec::new();
+    for (i, record) in reader.records().enumerate() {
+        let record = record?;
+        let row = i + 1;
+        let invalid = |reason: &str| DataImportError::InvalidRow { row, reason: reason.to_owned() };
+
+        let (Some(origin), Some(destination), Some(window_start), Some(count)) = (record.get(0), record.get(1), record.get(2), record.get(4)) else {
+            return Err(invalid("expected origin,destination,window_start,window_end,count"));
+        };
+        let start_stop = network.get_stop_idx_from_name(origin.trim()).ok_or_else(|| invalid(&format!("unknown stop '{origin}'")))?;
+        let end_stop = network.get_stop_idx_from_name(destination.trim()).ok_or_else(|| invalid(&format!("unknown stop '{destination}'")))?;
+        let window_start = parse_time_seconds(window_start).ok_or_else(|| invalid("invalid window_start"))? as Timestamp;
+        let window_end = match record.get(3).map(str::trim) {
+            None | Some("") => window_start,
+            Some(window_end) => parse_time_seconds(window_end).ok_or_else(|| invalid("invalid window_end"))? as Timestamp,
+        };
+        if window_end < window_start {
+            return Err(invalid("window_end is before window_start"));
+        }
+        let count = count.trim().parse::<f64>().ok().filter(|count| count.is_finite() && *count >= 0.).ok_or_else(|| invalid("count is not a valid non-negative number"))?;
+        // Float to int casts saturate.
+        let mut row_count = count.round() as u64;
+
+        // Large counts are split so each part fits in an AgentCount.
+        while row_count > 0 {
+            let part = row_count.min(AgentCount::MAX as u64) as AgentCount;
+            if window_end == window_start {
+                simulation_steps.push(AgentJourney { start_time: window_start, start_stop, end_stop, count: part });
+            } else {
+                simulation_steps.extend(spread_departure_window(start_stop, end_stop, window_start, window_end, part, profile));
+            }
+            row_count -= part as u64;
+        }
+    }
+
+    Ok(simulation_steps)
+}
+
+// The chunks of a binary visualisation file, as written by data_export.
+pub struct BinFile {
+    pub version: u32,
+    pub chunks: Vec<([u8; 4], Vec<u8>)>,
+}
+
+// Reads a binary visualisation file, checking the magic bytes and that the version isn't newer than this build writes.
+pub fn read_bin(path: &str) -> Result<BinFile, DataImportError> {
+    let mut zip = zip::ZipArchive::new(File::open(path)?)?;
+    let mut data = Vec::new();
+    zip.by_name("data.bin")?.read_to_end(&mut data)?;
+
+    let read_u32 = |offset: usize| -> Result<u32, DataImportError> {
+        data.get(offset..offset + 4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+            .ok_or_else(|| DataImportError::InvalidBin(format!("truncated at byte {offset}")))
+    };
+
+    if data.get(0..4) != Some(&BIN_MAGIC[..]) {
+        return Err(DataImportError::InvalidBin("missing magic bytes (file may predate the versioned format)".to_owned()));
+    }
+    let version = read_u32(4)?;
+    if version > BIN_VERSION {
+        return Err(DataImportError::InvalidBin(format!("version {version} is newer than supported version {BIN_VERSION}")));
+    }
+    let num_chunks = read_u32(8)? as usize;
+
+    let mut chunks = Vec::with_capacity(num_chunks);
+    for i in 0..num_chunks {
+        let header_offset = 16 + i * 12;
+        let tag: [u8; 4] = data.get(header_offset..header_offset + 4)
+            .ok_or_else(|| DataImportError::InvalidBin(format!("truncated at byte {header_offset}")))?
+            .try_into().unwrap();
+        let offset = read_u32(header_offset + 4)? as usize;
+        let length = read_u32(header_offset + 8)? as usize;
+        let chunk = data.get(offset..offset + length)
+            .ok_or_else(|| DataImportError::InvalidBin(format!("chunk {} extends past the end of the file", String::from_utf8_lossy(&tag))))?;
+        chunks.push((tag, chunk.to_vec()));
+    }
+
+    Ok(BinFile { version, chunks })
+}
+
+// Imports per-stop trip production and attraction weights (e.g. station entries and exits) for demand synthesis,
+// from a CSV with columns stop,production,attraction, where stop is a stop name.
+pub fn import_stop_weights(path: &str, network: &Network) -> Result<Vec<StopWeight>, DataImportError> {
+    let mut reader = csv::Reader::from_path(path)?;
+
+    let mut weights = Vec::new();
+    for (i, record) in reader.records().enumerate() {
+        let record = record?;
+        let row = i + 1;
+        let invalid = |reason: &str| DataImportError::InvalidRow { row, reason: reason.to_owned() };
+
+        let (Some(stop), Some(production), Some(attraction)) = (record.get(0), record.get(1), record.get(2)) else {
+            return Err(invalid("expected stop,production,attraction"));
+        };
+        let stop_idx = network.get_stop_idx_from_name(stop.trim()).ok_or_else(|| invalid(&format!("unknown stop '{stop}'")))?;
+        let production = production.trim().parse::<f64>().ok().filter(|weight| *weight >= 0.).ok_or_else(|| invalid("production is not a non-negative number"))?;
+        let attraction = attraction.trim().parse::<f64>().ok().filter(|weight| *weight >= 0.).ok_or_else(|| invalid("attraction is not a non-negative number"))?;
+
+        weights.push(StopWeight { stop_idx, production, attraction });
+    }
+
+    Ok(weights)
+}
+
+// Imports agents with prescribed paths from a CSV with columns agent,count,trip_id,board_stop,alight_stop, with one row
+// per leg in order. An agent's rows must be consecutive and share one count, and stops are stop names.
+pub fn import_pinned_paths(path: &str, network: &Network) -> Result<Vec<PinnedPath>, DataImportError> {
+    let mut reader = csv::Reader::from_path(path)?;
+
+    // Where each trip is in the network.
+    let trip_indices = network.routes.iter().enumerate().flat_map(|(route_idx, route)| {
+        route.trip_ids.iter().enumerate().map(move |(trip_idx, trip_id)| (trip_id.to_string(), (route_idx, trip_idx)))
+    }).collect::<HashMap<_, _>>();
+
+    let mut pinned_paths: Vec<PinnedPath> = Vec::new();
+    let mut current_agent = None;
+    let mut seen_agents = HashSet::new();
+    for (i, record) in reader.records().enumerate() {
+        let record = record?;
+        let row = i + 1;
+        let invalid = |reason: &str| DataImportError::InvalidRow { row, reason: reason.to_owned() };
+
+        let (Some(agent), Some(count), Some(trip_id), Some(board_stop), Some(alight_stop)) = (record.get(0), record.get(1), record.get(2), record.get(3), record.get(4)) else {
+            return Err(invalid("expected agent,count,trip_id,board_stop,alight_stop"));
+        };
+        let count = count.trim().parse::<AgentCount>().map_err(|_| invalid("count is not a valid number"))?;
+        let &(route_idx, trip_idx) = trip_indices.get(trip_id.trim()).ok_or_else(|| invalid(&format!("trip '{trip_id}' does not run in the network")))?;
+
+        // Find the stops in the trip's stopping pattern, alighting after boarding.
+        let route_stops = network.routes[route_idx].get_stops(&network.route_stops);
+        let board_stop_idx = network.get_stop_idx_from_name(board_stop.trim()).ok_or_else(|| invalid(&format!("unknown stop '{board_stop}'")))?;
+        let alight_stop_idx = network.get_stop_idx_from_name(alight_stop.trim()).ok_or_else(|| invalid(&format!("unknown stop '{alight_stop}'")))?;
+        let boarded_stop_order = route_stops.iter().position(|&stop_idx| stop_idx == board_stop_idx).ok_or_else(|| invalid("trip does not stop at board_stop"))?;
+        let arrival_stop_order = route_stops[boarded_stop_order + 1..].iter().position(|&stop_idx| stop_idx == alight_stop_idx)
+            .map(|offset| boarded_stop_order + 1 + offset)
+            .ok_or_else(|| invalid("trip does not stop at alight_stop after board_stop"))?;
+        let leg = JourneyLeg { route_idx, trip_idx, boarded_stop_order, arrival_stop_order };
+
+        if let (Some(path), true) = (pinned_paths.last_mut(), current_agent.as_deref() == Some(agent.trim())) {
+            if path.count != count {
+                return Err(invalid(&format!("count {count} differs from the count {} on agent '{agent}'s earlier rows", path.count)));
+            }
+            path.legs.push(leg);
+        } else {
+            if !seen_agents.insert(agent.trim().to_owned()) {
+                return Err(invalid(&format!("rows of agent '{agent}' are not consecutive")));
+            }
+            current_agent = Some(agent.trim().to_owned());
+            pinned_paths.push(PinnedPath { count, legs: vec![leg] });
+        }
+    }
+
+    Ok(pinned_paths)
+}
+
+// Imports observed boardings from a CSV with columns trip_id,stop,count, e.g. from ticketing data.
+pub fn import_observed_boardings(path: &str, network: &Network) -> Result<Vec<ObservedBoarding>, DataImportError> {
+    let mut reader = csv::Reader::from_path(path)?;
+
+    // Where each trip is in the network.
+    let trip_indices = network.routes.iter().enumerate().flat_map(|(route_idx, route)| {
+        route.trip_ids.iter().enumerate().map(move |(trip_idx, trip_id)| (trip_id.to_string(), (route_idx, trip_idx)))
+    }).collect::<HashMap<_, _>>();
+
+    let mut observed = Vec::new();
+    for (i, record) in reader.records().enumerate() {
+        let record = record?;
+        let row = i + 1;
+        let invalid = |reason: &str| DataImportError::InvalidRow { row, reason: reason.to_owned() };
+
+        let (Some(trip_id), Some(stop), Some(count)) = (record.get(0), record.get(1), record.get(2)) else {
+            return Err(invalid("expected trip_id,stop,count"));
+        };
+        let count = count.trim().parse::<u32>().map_err(|_| invalid("count is not a valid number"))?;
+        let &(route_idx, trip_idx) = trip_indices.get(trip_id.trim()).ok_or_else(|| invalid(&format!("trip '{trip_id}' does not run in the network")))?;
+        let stop_idx = network.get_stop_idx_from_name(stop.trim()).ok_or_else(|| invalid(&format!("unknown stop '{stop}'")))?;
+        let stop_order = network.routes[route_idx].get_stops(&network.route_stops).iter().position(|&route_stop_idx| route_stop_idx == stop_idx)
+            .ok_or_else(|| invalid("trip does not stop at stop"))?;
+
+        observed.push(ObservedBoarding { route_idx, trip_idx, stop_order, count });
+    }
+
+    Ok(observed)
+}
+
+// Imports a fare table from a stop zones CSV with columns stop,zone and a fares CSV with columns
+// origin_zone,destination_zone,fare.
+pub fn import_fare_table(zones_path: &str, fares_path: &str, network: &Network) -> Result<FareTable, DataImportError> {
+    let mut stop_zones = vec![None; network.stops.len()];
+    let mut reader = csv::Reader::from_path(zones_path)?;
+    for (i, record) in reader.records().enumerate() {
+        let record = record?;
+        let row = i + 1;
+        let invalid = |reason: &str| DataImportError::InvalidRow { row, reason: reason.to_owned() };
+
+        let (Some(stop), Some(zone)) = (record.get(0), record.get(1)) else {
+            return Err(invalid("expected stop,zone"));
+        };
+        let stop_idx = network.get_stop_idx_from_name(stop.trim()).ok_or_else(|| invalid(&format!("unknown stop '{stop}'")))?;
+        stop_zones[stop_idx as usize] = Some(zone.trim().to_owned());
+    }
+
+    let mut fares = HashMap::new();
+    let mut reader = csv::Reader::from_path(fares_path)?;
+    for (i, record) in reader.records().enumerate() {
+        let record = record?;
+        let row = i + 1;
+        let invalid = |reason: &str| DataImportError::InvalidRow { row, reason: reason.to_owned() };
+
+        let (Some(origin_zone), Some(destination_zone), Some(fare)) = (record.get(0), record.get(1), record.get(2)) else {
+            return Err(invalid("expected origin_zone,destination_zone,fare"));
+        };
+        let fare = fare.trim().parse::<f64>().map_err(|_| invalid("fare is not a valid number"))?;
+        fares.insert((origin_zone.trim().to_owned(), destination_zone.trim().to_owned()), fare);
+    }
+
+    Ok(FareTable { stop_zones, fares })
+}
+
+// Imports per-route crowding functions from a CSV with columns route_id,function,parameter, where function is linear,
+// quadratic, exponential (with parameter b) or the name of a crowding preset.
+pub fn import_route_crowding_functions(path: &str) -> Result<HashMap<String, CrowdingFunction>, DataImportError> {
+    let mut reader = csv::Reader::from_path(path)?;
+
+    let mut route_functions = HashMap::new();
+    for (i, record) in reader.records().enumerate() {
+        let record = record?;
+        let row = i + 1;
+        let invalid = |reason: &str| DataImportError::InvalidRow { row, reason: reason.to_owned() };
+
+        let (Some(route_id), Some(function)) = (record.get(0), record.get(1)) else {
+            return Err(invalid("expected route_id,function,parameter"));
+        };
+        let parameter = record.get(2).map(str::trim).filter(|parameter| !parameter.is_empty());
+        let crowding_function = match function.trim() {
+            "linear" => CrowdingFunction::Linear,
+            "quadratic" => CrowdingFunction::Quadratic,
+            "exponential" => {
+                let b = parameter.and_then(|parameter| parameter.parse().ok()).ok_or_else(|| invalid("exponential needs a numeric parameter"))?;
+                CrowdingFunction::Exponential(b)
+            }
+            other => crowding::find_preset(other).map(|preset| preset.crowding_function).ok_or_else(|| invalid(&format!("unknown function '{other}'")))?,
+        };
+
+        crowding_function.validate().map_err(|error| invalid(&error.to_string()))?;
+
+        route_functions.insert(route_id.trim().to_owned(), crowding_function);
+    }
+
+    Ok(route_functions)
+}
+
+// Imports the stops of a hypothetical line from a CSV with columns stop,latitude,longitude,run_time, where run_time
+// is the time from the previous stop as [[HH:]MM:]SS or plain seconds (empty for the first stop).
+pub fn import_line_stops(path: &str) -> Result<Vec<LineStop>, DataImportError> {
+    let mut reader = csv::Reader::from_path(path)?;
+
+    let mut stops = Vec::new();
+    for (i, record) in reader.records().enumerate() {
+        let record = record?;
+        let row = i + 1;
+        let invalid = |reason: &str| DataImportError::InvalidRow { row, reason: reason.to_owned() };
+
+        let (Some(name), Some(latitude), Some(longitude)) = (record.get(0), record.get(1), record.get(2)) else {
+            return Err(invalid("expected stop,latitude,longitude,run_time"));
+        };
+        let latitude = latitude.trim().parse().map_err(|_| invalid("latitude is not a valid number"))?;
+        let longitude = longitude.trim().parse().map_err(|_| invalid("longitude is not a valid number"))?;
+        let run_time = match record.get(3).map(str::trim) {
+            None | Some("") if stops.is_empty() => 0,
+            None | Some("") => return Err(invalid("run_time is required after the first stop")),
+            Some(run_time) => parse_duration_seconds(run_time).ok_or_else(|| invalid("run_time is not a valid duration"))? as Timestamp,
+        };
+
+        stops.push(LineStop { name: name.trim().to_owned(), latitude, longitude, run_time });
+    }
+
+    Ok(stops)
+}
+
 //use std::collections::HashMap;
 //use std::fs::File;
 //
@@ -120,4 +590,88 @@
 //    } else {
 //        Ok(simulation_steps)
 //    }
-//}
\ No newline at end of file
+//}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{leg, temp_dir, test_network};
+
+    #[test]
+    fn parse_time_seconds_formats() {
+        assert_eq!(parse_time_seconds("07:30"), Some(7 * 3600 + 30 * 60));
+        assert_eq!(parse_time_seconds(" 07:30:15 "), Some(7 * 3600 + 30 * 60 + 15));
+        // Times past midnight on the service day, as in GTFS.
+        assert_eq!(parse_time_seconds("25:00:00"), Some(25 * 3600));
+    }
+
+    #[test]
+    fn parse_time_seconds_rejects_invalid_times() {
+        assert_eq!(parse_time_seconds(""), None);
+        assert_eq!(parse_time_seconds("7"), None);
+        assert_eq!(parse_time_seconds("7:30am"), None);
+        assert_eq!(parse_time_seconds("1:2:3:4"), None);
+        assert_eq!(parse_time_seconds("07:60"), None);
+        assert_eq!(parse_time_seconds("07:30:60"), None);
+        assert_eq!(parse_time_seconds("-1:30"), None);
+        assert_eq!(parse_time_seconds("07:59:59"), Some(7 * 3600 + 59 * 60 + 59));
+    }
+
+    #[test]
+    fn parse_duration_seconds_formats() {
+        assert_eq!(parse_duration_seconds("90"), Some(90));
+        assert_eq!(parse_duration_seconds("1:30"), Some(90));
+        assert_eq!(parse_duration_seconds("1:00:05"), Some(3605));
+        assert_eq!(parse_duration_seconds("-1"), None);
+        assert_eq!(parse_duration_seconds("1:2:3:4"), None);
+    }
+
+    #[test]
+    fn parse_duration_seconds_rejects_overflow() {
+        assert_eq!(parse_duration_seconds("4294967295"), Some(u32::MAX));
+        assert_eq!(parse_duration_seconds("71582789:0"), None);
+        assert_eq!(parse_duration_seconds("71582788:15"), Some(u32::MAX));
+        assert_eq!(parse_duration_seconds("71582788:16"), None);
+        assert_eq!(parse_duration_seconds("1193047:0:0"), None);
+    }
+
+    fn import_pinned_paths_csv(network: &Network, rows: &str) -> Result<Vec<PinnedPath>, DataImportError> {
+        let dir = temp_dir();
+        let path = dir.join("pinned_paths.csv");
+        std::fs::write(&path, format!("agent,count,trip_id,board_stop,alight_stop\n{rows}")).unwrap();
+        let pinned_paths = import_pinned_paths(path.to_str().unwrap(), network);
+        std::fs::remove_dir_all(&dir).unwrap();
+        pinned_paths
+    }
+
+    #[test]
+    fn import_pinned_paths_legs() {
+        let (_, network) = test_network();
+        let pinned_paths = import_pinned_paths_csv(&network, "a,3,A1,West,Central\na,3,B1,Central,South\nb,1, A2 , West , East \n").unwrap();
+        assert_eq!(pinned_paths.len(), 2);
+        assert_eq!(pinned_paths[0].count, 3);
+        let expected_legs = [leg(&network, "A1", "West", "Central"), leg(&network, "B1", "Central", "South")];
+        for (leg, expected_leg) in pinned_paths[0].legs.iter().zip(expected_legs.iter()) {
+            assert_eq!((leg.route_idx, leg.trip_idx, leg.boarded_stop_order, leg.arrival_stop_order), (expected_leg.route_idx, expected_leg.trip_idx, expected_leg.boarded_stop_order, expected_leg.arrival_stop_order));
+        }
+        assert_eq!(pinned_paths[0].legs.len(), 2);
+        assert_eq!(pinned_paths[1].count, 1);
+        assert_eq!(pinned_paths[1].legs.len(), 1);
+    }
+
+    #[test]
+    fn import_pinned_paths_rejects_invalid_rows() {
+        let (_, network) = test_network();
+        let invalid_row = |rows| match import_pinned_paths_csv(&network, rows) {
+            Err(DataImportError::InvalidRow { row, .. }) => row,
+            _ => panic!("expected an invalid row in {rows:?}"),
+        };
+        assert_eq!(invalid_row("a,1,X1,West,Central\n"), 1);
+        assert_eq!(invalid_row("a,1,A1,Nowhere,Central\n"), 1);
+        assert_eq!(invalid_row("a,1,A1,West,South\n"), 1);
+        // Alighting before boarding.
+        assert_eq!(invalid_row("a,1,A1,Central,West\n"), 1);
+        assert_eq!(invalid_row("a,1,A1,West,Central\na,2,B1,Central,South\n"), 2);
+        assert_eq!(invalid_row("a,1,A1,West,Central\nb,1,A2,West,East\na,1,B1,Central,South\n"), 3);
+    }
+}
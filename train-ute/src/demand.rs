@@ -0,0 +1,189 @@
+use rand::prelude::*;
+use serde::Deserialize;
+
+use raptor::Network;
+use raptor::network::{StopIndex, Timestamp};
+
+use crate::data_import::{self, DataImportError};
+use crate::simulation::{gen_simulation_steps, spread_departure_window, AgentCount, AgentJourney, DepartureProfile};
+
+// How the attractiveness of a destination falls off with distance (in metres).
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DistanceDecay {
+    // exp(-beta * distance / 1000).
+    Exponential(f64),
+    // (distance / 1000)^-alpha, with distances under 1km treated as 1km.
+    Power(f64),
+}
+
+impl DistanceDecay {
+    fn eval(&self, distance: f64) -> f64 {
+        let distance_km = distance / 1000.;
+        match *self {
+            DistanceDecay::Exponential(beta) => (-beta * distance_km).exp(),
+            DistanceDecay::Power(alpha) => distance_km.max(1.).powf(-alpha),
+        }
+    }
+}
+
+// Trip production (e.g. entries) and attraction (e.g. exits) weights of a stop.
+pub struct StopWeight {
+    pub stop_idx: StopIndex,
+    pub production: f64,
+    pub attraction: f64,
+}
+
+// Synthesises demand with a gravity model: trips from i to j are proportional to production_i * attraction_j * decay(d_ij),
+// scaled so there are total_trips in all. Departures are spread over the window with the profile.
+// Fractional OD volumes are rounded randomly (seeded), so small flows still appear in proportion.
+pub fn gravity_demand(network: &Network, weights: &[StopWeight], decay: &DistanceDecay, total_trips: usize, window: (Timestamp, Timestamp), profile: &DepartureProfile, seed: u64) -> Vec<AgentJourney> {
+    let mut od_weights = Vec::new();
+    for origin in weights.iter().filter(|weight| weight.production > 0.) {
+        let origin_point = network.stop_points[origin.stop_idx as usize];
+        for destination in weights.iter().filter(|weight| weight.attraction > 0. && weight.stop_idx != origin.stop_idx) {
+            let distance = origin_point.distance(network.stop_points[destination.stop_idx as usize]) as f64;
+            od_weights.push((origin.stop_idx, destination.stop_idx, origin.production * destination.attraction * decay.eval(distance)));
+        }
+    }
+
+    let total_weight = od_weights.iter().map(|&(_, _, weight)| weight).sum::<f64>();
+    if total_weight <= 0. {
+        return Vec::new();
+    }
+
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let mut simulation_steps = Vec::new();
+    for (start_stop, end_stop, weight) in od_weights {
+        let volume = total_trips as f64 * weight / total_weight;
+        let mut count = volume.floor() as usize;
+        if rng.gen::<f64>() < volume.fract() {
+            count += 1;
+        }
+        // Large flows are split so each part fits in an AgentCount.
+        while count > 0 {
+            let part = count.min(AgentCount::MAX as usize);
+            simulation_steps.extend(spread_departure_window(start_stop, end_stop, window.0, window.1, part as AgentCount, profile));
+            count -= part;
+        }
+    }
+    simulation_steps
+}
+
+// A source of simulation steps. The simulation only needs the steps, so custom demand (e.g. from a database or an API)
+// can be plugged in by implementing this rather than changing data_import.
+pub trait DemandProvider {
+    fn simulation_steps(&self, network: &Network) -> Result<Vec<AgentJourney>, DataImportError>;
+}
+
+// Uniformly random demand, for testing and benchmarking (see simulation::gen_simulation_steps).
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RandomDemand {
+    pub number: Option<usize>,
+    pub seed: Option<u64>,
+}
+
+impl DemandProvider for RandomDemand {
+    fn simulation_steps(&self, network: &Network) -> Result<Vec<AgentJourney>, DataImportError> {
+        Ok(gen_simulation_steps(network, self.number, self.seed))
+    }
+}
+
+// OD demand with departure windows from a CSV (see data_import::import_od_windows).
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct OdWindowsCsv {
+    pub path: String,
+    #[serde(default)]
+    pub profile: DepartureProfile,
+}
+
+impl DemandProvider for OdWindowsCsv {
+    fn simulation_steps(&self, network: &Network) -> Result<Vec<AgentJourney>, DataImportError> {
+        data_import::import_od_windows(&self.path, network, &self.profile)
+    }
+}
+
+// Gravity model demand from a stop weights CSV (see data_import::import_stop_weights and gravity_demand).
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GravityDemand {
+    pub weights_path: String,
+    pub decay: DistanceDecay,
+    pub total_trips: usize,
+    #[serde(deserialize_with = "crate::config::time_of_day")]
+    pub window_start: Timestamp,
+    #[serde(deserialize_with = "crate::config::time_of_day")]
+    pub window_end: Timestamp,
+    #[serde(default)]
+    pub profile: DepartureProfile,
+    #[serde(default)]
+    pub seed: u64,
+}
+
+impl DemandProvider for GravityDemand {
+    fn simulation_steps(&self, network: &Network) -> Result<Vec<AgentJourney>, DataImportError> {
+        let weights = data_import::import_stop_weights(&self.weights_path, network)?;
+        Ok(gravity_demand(network, &weights, &self.decay, self.total_trips, (self.window_start, self.window_end), &self.profile, self.seed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{stop, test_network};
+
+    const WINDOW: (Timestamp, Timestamp) = (7 * 3600, 9 * 3600);
+
+    fn weight(network: &Network, name: &str, production: f64, attraction: f64) -> StopWeight {
+        StopWeight { stop_idx: stop(network, name), production, attraction }
+    }
+
+    fn od_count(steps: &[AgentJourney], start_stop: StopIndex, end_stop: StopIndex) -> usize {
+        steps.iter().filter(|step| step.start_stop == start_stop && step.end_stop == end_stop).map(|step| step.count as usize).sum()
+    }
+
+    #[test]
+    fn gravity_demand_totals_and_window() {
+        let (_, network) = test_network();
+        let weights = [weight(&network, "West", 1., 1.), weight(&network, "East", 2., 1.), weight(&network, "North", 1., 3.)];
+        let steps = gravity_demand(&network, &weights, &DistanceDecay::Power(1.), 1000, WINDOW, &DepartureProfile::Uniform, 0);
+
+        // Each of the 6 OD pairs rounds up or down.
+        let total = steps.iter().map(|step| step.count as usize).sum::<usize>();
+        assert!(total.abs_diff(1000) <= 6);
+        assert!(steps.iter().all(|step| (WINDOW.0..=WINDOW.1).contains(&step.start_time)));
+        assert!(steps.iter().all(|step| step.start_stop != step.end_stop));
+    }
+
+    #[test]
+    fn gravity_demand_skips_zero_weights() {
+        let (_, network) = test_network();
+        let weights = [weight(&network, "West", 1., 0.), weight(&network, "East", 0., 1.), weight(&network, "North", 1., 1.)];
+        let steps = gravity_demand(&network, &weights, &DistanceDecay::Exponential(0.1), 100, WINDOW, &DepartureProfile::Uniform, 0);
+        assert!(steps.iter().all(|step| step.end_stop != stop(&network, "West") && step.start_stop != stop(&network, "East")));
+
+        let weights = [weight(&network, "West", 1., 0.), weight(&network, "East", 1., 0.)];
+        assert!(gravity_demand(&network, &weights, &DistanceDecay::Exponential(0.1), 100, WINDOW, &DepartureProfile::Uniform, 0).is_empty());
+    }
+
+    #[test]
+    fn gravity_demand_decays_with_distance() {
+        let (_, network) = test_network();
+        let weights = [weight(&network, "West", 1., 0.), weight(&network, "Central", 0., 1.), weight(&network, "East", 0., 1.)];
+        let steps = gravity_demand(&network, &weights, &DistanceDecay::Exponential(1.), 1000, WINDOW, &DepartureProfile::Uniform, 0);
+        assert!(od_count(&steps, stop(&network, "West"), stop(&network, "Central")) > od_count(&steps, stop(&network, "West"), stop(&network, "East")));
+    }
+
+    #[test]
+    fn gravity_demand_is_seeded() {
+        let (_, network) = test_network();
+        let weights = [weight(&network, "West", 1., 1.), weight(&network, "East", 1., 1.), weight(&network, "North", 1., 1.), weight(&network, "South", 1., 1.)];
+        let tuples = |seed| {
+            gravity_demand(&network, &weights, &DistanceDecay::Power(2.), 7, WINDOW, &DepartureProfile::Uniform, seed).iter()
+                .map(|step| (step.start_time, step.start_stop, step.end_stop, step.count)).collect::<Vec<_>>()
+        };
+        assert_eq!(tuples(1), tuples(1));
+    }
+}
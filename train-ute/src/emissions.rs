@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use raptor::Network;
+
+use crate::data_export::{DataExportError, TimeBand};
+use crate::simulation::SimulationResult;
+
+// Simple traction energy model: a fixed energy per train-km, extra energy for the passengers carried, and the energy
+// lost to braking and accelerating at each stop.
+// In the run config, missing figures are taken from melbourne_emu.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnergyModel {
+    pub kwh_per_train_km: f64,
+    pub kwh_per_passenger_km: f64,
+    pub kwh_per_stop: f64,
+    // Emissions intensity of the electricity supply.
+    pub kg_co2_per_kwh: f64,
+}
+
+impl EnergyModel {
+    // Rough figures for a Melbourne 6-car EMU on the Victorian grid.
+    pub fn melbourne_emu() -> Self {
+        Self { kwh_per_train_km: 15., kwh_per_passenger_km: 0.005, kwh_per_stop: 4., kg_co2_per_kwh: 0.8 }
+    }
+}
+
+impl Default for EnergyModel {
+    fn default() -> Self {
+        Self::melbourne_emu()
+    }
+}
+
+// Energy and emissions of one trip.
+pub struct TripEnergy {
+    pub route_idx: usize,
+    pub trip_idx: usize,
+    pub train_km: f64,
+    pub energy_kwh: f64,
+    pub co2_kg: f64,
+}
+
+// Estimates the energy and emissions of every trip from its length (see utils::route_segment_lengths), number of
+// stops and passenger load.
+pub fn trip_energy(network: &Network, simulation_result: &SimulationResult, segment_lengths: &[Vec<f32>], model: &EnergyModel) -> Vec<TripEnergy> {
+    let mut trips = Vec::new();
+    for (route_idx, route) in network.routes.iter().enumerate() {
+        // Stops between the first and last, where the train brakes and accelerates again.
+        let num_intermediate_stops = route.get_stops(&network.route_stops).len().saturating_sub(2);
+        let train_km = segment_lengths[route_idx].iter().map(|&length| length as f64 / 1000.).sum::<f64>();
+        for trip_idx in 0..route.num_trips as usize {
+            let agent_counts = &simulation_result.agent_journeys[route.get_trip_range(trip_idx)];
+            let passenger_km = segment_lengths[route_idx].iter().zip(agent_counts).map(|(&length, &count)| count as f64 * length as f64 / 1000.).sum::<f64>();
+            let energy_kwh = train_km * model.kwh_per_train_km + passenger_km * model.kwh_per_passenger_km + num_intermediate_stops as f64 * model.kwh_per_stop;
+            trips.push(TripEnergy { route_idx, trip_idx, train_km, energy_kwh, co2_kg: energy_kwh * model.kg_co2_per_kwh });
+        }
+    }
+    trips
+}
+
+// Exports energy and emissions per time band and line. Trips are assigned to bands by their first departure.
+pub fn export_energy_by_line(path: &str, network: &Network, trips: &[TripEnergy], time_bands: &[TimeBand]) -> Result<(), DataExportError> {
+    // Keyed by band and line: (number of trips, train-km, energy, emissions).
+    let mut totals: HashMap<(usize, &str), (u32, f64, f64, f64)> = HashMap::new();
+    for trip in trips {
+        let departure_time = network.get_departure_time(trip.route_idx, trip.trip_idx, 0);
+        let line = &*network.routes[trip.route_idx].line;
+        for (band_idx, _) in time_bands.iter().enumerate().filter(|(_, band)| band.start <= departure_time && departure_time < band.end) {
+            let total = totals.entry((band_idx, line)).or_default();
+            total.0 += 1;
+            total.1 += trip.train_km;
+            total.2 += trip.energy_kwh;
+            total.3 += trip.co2_kg;
+        }
+    }
+
+    let mut rows = totals.into_iter().collect::<Vec<_>>();
+    rows.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut csv_writer = csv::Writer::from_path(path)?;
+    csv_writer.write_record(["band", "line", "num_trips", "train_km", "energy_kwh", "co2_kg"])?;
+    for ((band_idx, line), (num_trips, train_km, energy_kwh, co2_kg)) in rows {
+        csv_writer.write_record([&*time_bands[band_idx].name, line, &num_trips.to_string(), &format!("{train_km:.1}"), &format!("{energy_kwh:.1}"), &format!("{co2_kg:.1}")])?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::data_export::utc_now;
+use crate::gtfs_validation::GtfsReport;
+use crate::simulation::CountIssue;
+
+// Something that happened during a run, for pipelines to check without scraping the console output.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RunEvent<'a> {
+    RunStarted { gtfs_path: &'a str },
+    StageCompleted { stage: &'a str, duration_ms: u128 },
+    // kind is a stable identifier (e.g. "missing_shapes"), count is how many items are affected.
+    Warning { kind: &'a str, count: usize, message: String },
+    SimulationCompleted { num_steps: usize, duration_ms: u128 },
+    ExportWritten { path: &'a str },
+    RunFinished { duration_ms: u128 },
+}
+
+#[derive(Serialize)]
+struct LogEntry<'a> {
+    time: DateTime<Utc>,
+    #[serde(flatten)]
+    event: RunEvent<'a>,
+}
+
+// Writes run events to a JSON lines file, one event per line. Lines are flushed as they're written, so the log is
+// usable even if the run fails part way.
+pub struct EventLog {
+    writer: BufWriter<File>,
+    // Completed stages and how long they took, for the run manifest.
+    stage_timings: Vec<(String, Duration)>,
+}
+
+impl EventLog {
+    pub fn create(path: &str) -> std::io::Result<Self> {
+        Ok(Self { writer: BufWriter::new(File::create(path)?), stage_timings: Vec::new() })
+    }
+
+    pub fn log(&mut self, event: RunEvent) -> std::io::Result<()> {
+        serde_json::to_writer(&mut self.writer, &LogEntry { time: utc_now(), event })?;
+        writeln!(self.writer)?;
+        self.writer.flush()
+    }
+
+    pub fn stage_completed(&mut self, stage: &str, duration: Duration) -> std::io::Result<()> {
+        self.stage_timings.push((stage.to_owned(), duration));
+        self.log(RunEvent::StageCompleted { stage, duration_ms: duration.as_millis() })
+    }
+
+    pub fn stage_timings(&self) -> &[(String, Duration)] {
+        &self.stage_timings
+    }
+
+    pub fn warning(&mut self, kind: &str, count: usize, message: String) -> std::io::Result<()> {
+        self.log(RunEvent::Warning { kind, count, message })
+    }
+
+    // Logs a warning for each kind of issue in a GTFS validation report.
+    pub fn gtfs_report(&mut self, report: &GtfsReport) -> std::io::Result<()> {
+        if !report.routes_missing_shapes.is_empty() {
+            self.warning("missing_shapes", report.routes_missing_shapes.len(), format!("Routes with missing shapes: {}", report.routes_missing_shapes.join(", ")))?;
+        }
+        if !report.stops_missing_coordinates.is_empty() {
+            self.warning("missing_coordinates", report.stops_missing_coordinates.len(), format!("Stops with no coordinates: {}", report.stops_missing_coordinates.join(", ")))?;
+        }
+        if !report.non_monotonic_trips.is_empty() {
+            self.warning("non_monotonic_stop_times", report.non_monotonic_trips.len(), format!("Trips with non-monotonic stop times: {}", report.non_monotonic_trips.join(", ")))?;
+        }
+        match report.calendar_range {
+            Some((start, end)) if report.date < start || report.date > end => {
+                self.warning("date_outside_calendar", 1, format!("Date {} is outside the calendar range {start} to {end}", report.date))?;
+            }
+            _ => {}
+        }
+        if report.active_trips == 0 {
+            self.warning("no_active_trips", 0, format!("No trips run on {}", report.date))?;
+        }
+        Ok(())
+    }
+
+    // Logs a warning for each kind of out of range count from a simulation run.
+    pub fn count_issues(&mut self, issues: &[CountIssue]) -> std::io::Result<()> {
+        let num_overflows = issues.iter().filter(|issue| matches!(issue, CountIssue::Overflow { .. })).count();
+        let num_negative = issues.iter().filter(|issue| matches!(issue, CountIssue::Negative { .. })).count();
+        let num_invalid_trips = issues.iter().filter(|issue| matches!(issue, CountIssue::InvalidTrip { .. })).count();
+        if num_overflows > 0 {
+            self.warning("count_overflow", num_overflows, "Trip stop counts overflowed and were saturated".to_owned())?;
+        }
+        if num_negative > 0 {
+            self.warning("count_negative", num_negative, "Trip stop counts went negative and were costed as empty".to_owned())?;
+        }
+        if num_invalid_trips > 0 {
+            self.warning("invalid_trip_counts", num_invalid_trips, "Trips had counts wrap around, so their loads are invalid".to_owned())?;
+        }
+        Ok(())
+    }
+}
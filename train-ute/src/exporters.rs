@@ -0,0 +1,194 @@
+use std::path::Path;
+use std::slice;
+
+use gtfs_structures::Gtfs;
+
+use raptor::Network;
+
+use crate::data_export::{self, DataExportError, ExportColumns, OccupancyFormat, ParquetCompression, SHAPE_MATCH_TOLERANCE};
+use crate::simulation::{SimulationParams, SimulationResult};
+use crate::statistics;
+use crate::utils::route_segment_lengths;
+
+// Everything an exporter can draw on from a finished run.
+pub struct ExportContext<'a, T: SimulationParams> {
+    pub network: &'a Network,
+    pub gtfs: &'a Gtfs,
+    // Whether each route's shape is approximate (see utils::gen_fallback_shapes).
+    pub approximate_shapes: &'a [bool],
+    pub simulation_result: &'a SimulationResult,
+    pub params: &'a T,
+}
+
+pub struct ExportOptions {
+    // Where result tables go.
+    pub output_dir: String,
+    // Where files read by the frontend (train-vis) go.
+    pub visualisation_dir: String,
+    // Formats to write (see available_formats). Exporters of other formats are skipped.
+    pub formats: Vec<String>,
+    pub parquet_compression: ParquetCompression,
+    pub occupancy_format: OccupancyFormat,
+    // Columns of the counts table.
+    pub columns: ExportColumns,
+}
+
+impl ExportOptions {
+    fn path(&self, dir: &str, file_name: &str) -> String {
+        Path::new(dir).join(file_name).to_string_lossy().into_owned()
+    }
+}
+
+// One output of a run. Adding a new output means implementing this and adding it to the registry, rather than another
+// call in main.
+pub trait Exporter<T: SimulationParams> {
+    fn name(&self) -> &'static str;
+    fn format(&self) -> &'static str;
+    // Writes the export and returns the paths written.
+    fn export(&self, context: &ExportContext<T>, options: &ExportOptions) -> Result<Vec<String>, DataExportError>;
+}
+
+struct CountsExporter;
+
+impl<T: SimulationParams> Exporter<T> for CountsExporter {
+    fn name(&self) -> &'static str { "counts" }
+    fn format(&self) -> &'static str { "parquet" }
+    fn export(&self, context: &ExportContext<T>, options: &ExportOptions) -> Result<Vec<String>, DataExportError> {
+        let path = options.path(&options.output_dir, "counts.parquet");
+        data_export::export_agent_counts(&path, context.network, context.gtfs, context.simulation_result, context.params, &options.columns, options.parquet_compression)?;
+        Ok(vec![path])
+    }
+}
+
+struct OccupancyExporter;
+
+impl<T: SimulationParams> Exporter<T> for OccupancyExporter {
+    fn name(&self) -> &'static str { "occupancy" }
+    fn format(&self) -> &'static str { "parquet" }
+    fn export(&self, context: &ExportContext<T>, options: &ExportOptions) -> Result<Vec<String>, DataExportError> {
+        let path = options.path(&options.output_dir, "occupancy.parquet");
+        data_export::export_trip_occupancy(&path, context.network, context.simulation_result, options.occupancy_format, options.parquet_compression)?;
+        Ok(vec![path])
+    }
+}
+
+struct StopLoadsExporter;
+
+impl<T: SimulationParams> Exporter<T> for StopLoadsExporter {
+    fn name(&self) -> &'static str { "stop_loads" }
+    fn format(&self) -> &'static str { "bin" }
+    fn export(&self, context: &ExportContext<T>, options: &ExportOptions) -> Result<Vec<String>, DataExportError> {
+        let path = options.path(&options.visualisation_dir, "stop_loads.bin.zip");
+        data_export::export_stop_loads(&path, context.network, context.simulation_result, 15 * 60)?;
+        Ok(vec![path])
+    }
+}
+
+struct SegmentStatsExporter;
+
+impl<T: SimulationParams> Exporter<T> for SegmentStatsExporter {
+    fn name(&self) -> &'static str { "segment_load_stats" }
+    fn format(&self) -> &'static str { "csv" }
+    fn export(&self, context: &ExportContext<T>, options: &ExportOptions) -> Result<Vec<String>, DataExportError> {
+        // Headline load statistics, so results don't need the full counts table.
+        let segment_stats_path = options.path(&options.output_dir, "segment_load_stats.csv");
+        let peak_segments_path = options.path(&options.output_dir, "peak_segments.csv");
+        let segment_stats = statistics::segment_load_stats(context.network, slice::from_ref(context.simulation_result), context.params);
+        statistics::export_segment_load_stats(&segment_stats_path, context.network, &segment_stats)?;
+        statistics::export_peak_segments(&peak_segments_path, context.network, &statistics::peak_segments(context.network, context.simulation_result, context.params))?;
+        Ok(vec![segment_stats_path, peak_segments_path])
+    }
+}
+
+struct PassengerKpisExporter;
+
+impl<T: SimulationParams> Exporter<T> for PassengerKpisExporter {
+    fn name(&self) -> &'static str { "passenger_kpis" }
+    fn format(&self) -> &'static str { "csv" }
+    fn export(&self, context: &ExportContext<T>, options: &ExportOptions) -> Result<Vec<String>, DataExportError> {
+        let path = options.path(&options.output_dir, "passenger_kpis.csv");
+        let segment_lengths = route_segment_lengths(context.network, context.gtfs, SHAPE_MATCH_TOLERANCE);
+        statistics::export_passenger_kpis(&path, context.network, &statistics::trip_kpis(context.network, context.simulation_result, &segment_lengths))?;
+        Ok(vec![path])
+    }
+}
+
+struct SegmentLoadsKmlExporter;
+
+impl<T: SimulationParams> Exporter<T> for SegmentLoadsKmlExporter {
+    fn name(&self) -> &'static str { "segment_loads" }
+    fn format(&self) -> &'static str { "kml" }
+    fn export(&self, context: &ExportContext<T>, options: &ExportOptions) -> Result<Vec<String>, DataExportError> {
+        let path = options.path(&options.output_dir, "segment_loads.kml");
+        let segment_stats = statistics::segment_load_stats(context.network, slice::from_ref(context.simulation_result), context.params);
+        statistics::export_segment_loads_kml(&path, context.network, &segment_stats)?;
+        Ok(vec![path])
+    }
+}
+
+struct SegmentLoadsGeoJsonExporter;
+
+impl<T: SimulationParams> Exporter<T> for SegmentLoadsGeoJsonExporter {
+    fn name(&self) -> &'static str { "segment_loads" }
+    fn format(&self) -> &'static str { "geojson" }
+    fn export(&self, context: &ExportContext<T>, options: &ExportOptions) -> Result<Vec<String>, DataExportError> {
+        let path = options.path(&options.output_dir, "segment_loads.geojson");
+        let segment_stats = statistics::segment_load_stats(context.network, slice::from_ref(context.simulation_result), context.params);
+        statistics::export_segment_loads_geojson(&path, context.network, &segment_stats)?;
+        Ok(vec![path])
+    }
+}
+
+struct VisualisationExporter;
+
+impl<T: SimulationParams> Exporter<T> for VisualisationExporter {
+    fn name(&self) -> &'static str { "visualisation" }
+    fn format(&self) -> &'static str { "arrow" }
+    fn export(&self, context: &ExportContext<T>, options: &ExportOptions) -> Result<Vec<String>, DataExportError> {
+        // Drop shape points that are within a few metres of the simplified line.
+        let simplify_tolerance = Some(2.);
+        let shapes_path = options.path(&options.visualisation_dir, "shapes.arrow");
+        let trips_path = options.path(&options.visualisation_dir, "trips.arrow");
+        data_export::export_shape_file(&shapes_path, context.network, context.approximate_shapes, simplify_tolerance)?;
+        data_export::export_network_trips(&trips_path, context.network, context.gtfs, context.approximate_shapes, context.simulation_result, context.params, simplify_tolerance)?;
+        Ok(vec![shapes_path, trips_path])
+    }
+}
+
+// Every available exporter. Besides the table formats, "bin" and "arrow" are the files train-vis reads, and "kml" and
+// "geojson" are segment loads for Google Earth and GIS tools. There's no DuckDB format, as DuckDB reads the parquet
+// exports directly and the duckdb crate would add a bundled C++ build to train-ute.
+pub fn registry<T: SimulationParams>() -> Vec<Box<dyn Exporter<T>>> {
+    vec![
+        Box::new(CountsExporter),
+        Box::new(OccupancyExporter),
+        Box::new(StopLoadsExporter),
+        Box::new(SegmentStatsExporter),
+        Box::new(PassengerKpisExporter),
+        Box::new(SegmentLoadsKmlExporter),
+        Box::new(SegmentLoadsGeoJsonExporter),
+        Box::new(VisualisationExporter),
+    ]
+}
+
+// The formats the registry can write.
+pub fn available_formats<T: SimulationParams>() -> Vec<&'static str> {
+    let mut formats = registry::<T>().iter().map(|exporter| exporter.format()).collect::<Vec<_>>();
+    formats.sort_unstable();
+    formats.dedup();
+    formats
+}
+
+// Runs every exporter of the chosen formats, returning the paths written.
+pub fn export_all<T: SimulationParams>(context: &ExportContext<T>, options: &ExportOptions) -> Result<Vec<String>, DataExportError> {
+    let mut paths = Vec::new();
+    for exporter in registry::<T>() {
+        if options.formats.iter().any(|format| format == exporter.format()) {
+            #[cfg(feature = "tracing")]
+            let _span = tracing::info_span!("exporter", name = exporter.name()).entered();
+            println!("Exporting {} ({}).", exporter.name(), exporter.format());
+            paths.extend(exporter.export(context, options)?);
+        }
+    }
+    Ok(paths)
+}
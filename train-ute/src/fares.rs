@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+
+use raptor::Network;
+
+use crate::data_export::DataExportError;
+use crate::simulation::{query_journey_legs, AgentJourney, JourneyLeg};
+
+// Fare zones of the network's stops and the fare between each pair of zones.
+pub struct FareTable {
+    // Indexed by stop. Stops without a zone don't earn revenue.
+    pub stop_zones: Vec<Option<String>>,
+    // Keyed by (origin zone, destination zone). A pair only listed one way applies both ways.
+    pub fares: HashMap<(String, String), f64>,
+}
+
+impl FareTable {
+    pub fn fare(&self, origin_stop: usize, destination_stop: usize) -> Option<f64> {
+        let origin_zone = self.stop_zones[origin_stop].clone()?;
+        let destination_zone = self.stop_zones[destination_stop].clone()?;
+        self.fares.get(&(origin_zone.clone(), destination_zone.clone()))
+            .or_else(|| self.fares.get(&(destination_zone, origin_zone)))
+            .copied()
+    }
+}
+
+pub struct FareRevenue {
+    // Keyed by (origin stop, destination stop): (agents, revenue).
+    pub by_od: HashMap<(usize, usize), (u64, f64)>,
+    // Keyed by line: (boardings, revenue).
+    pub by_line: HashMap<String, (u64, f64)>,
+    // Agents whose OD has no fare (stop without a zone or zone pair not in the table).
+    pub num_unpriced: u64,
+}
+
+// Works out fare revenue per OD and per line from the simulated journeys. A journey's fare is split between the lines it
+// rides in proportion to in-vehicle time.
+pub fn fare_revenue(network: &Network, simulation_steps: &[AgentJourney], fare_table: &FareTable) -> FareRevenue {
+    let journey_legs = query_journey_legs(network, simulation_steps, None);
+    journey_fare_revenue(network, simulation_steps, &journey_legs, fare_table)
+}
+
+// Fare revenue of journeys along the given legs (one list of legs per simulation step).
+fn journey_fare_revenue(network: &Network, simulation_steps: &[AgentJourney], journey_legs: &[Vec<JourneyLeg>], fare_table: &FareTable) -> FareRevenue {
+    let mut revenue = FareRevenue { by_od: HashMap::new(), by_line: HashMap::new(), num_unpriced: 0 };
+    for (journey, legs) in simulation_steps.iter().zip(journey_legs.iter()) {
+        if legs.is_empty() {
+            continue;
+        }
+        let count = journey.count as u64;
+        let Some(fare) = fare_table.fare(journey.start_stop as usize, journey.end_stop as usize) else {
+            revenue.num_unpriced += count;
+            continue;
+        };
+        let journey_revenue = fare * count as f64;
+
+        let od_revenue = revenue.by_od.entry((journey.start_stop as usize, journey.end_stop as usize)).or_default();
+        od_revenue.0 += count;
+        od_revenue.1 += journey_revenue;
+
+        let leg_times = legs.iter().map(|leg| {
+            let route_idx = leg.route_idx;
+            let trip_idx = leg.trip_idx;
+            (network.get_arrival_time(route_idx, trip_idx, leg.arrival_stop_order) - network.get_departure_time(route_idx, trip_idx, leg.boarded_stop_order)) as f64
+        }).collect::<Vec<_>>();
+        let total_time = leg_times.iter().sum::<f64>();
+        for (leg, leg_time) in legs.iter().zip(leg_times) {
+            let share = if total_time > 0. { leg_time / total_time } else { 1. / legs.len() as f64 };
+            let line_revenue = revenue.by_line.entry(network.routes[leg.route_idx].line.to_string()).or_default();
+            line_revenue.0 += count;
+            line_revenue.1 += journey_revenue * share;
+        }
+    }
+    revenue
+}
+
+pub fn export_fare_revenue(od_path: &str, line_path: &str, network: &Network, revenue: &FareRevenue) -> Result<(), DataExportError> {
+    let mut by_od = revenue.by_od.iter().collect::<Vec<_>>();
+    by_od.sort_by(|a, b| a.0.cmp(b.0));
+    let mut csv_writer = csv::Writer::from_path(od_path)?;
+    csv_writer.write_record(["origin", "destination", "agents", "revenue"])?;
+    for (&(origin_stop, destination_stop), &(agents, od_revenue)) in by_od {
+        csv_writer.write_record([&*network.stops[origin_stop].name, &*network.stops[destination_stop].name, &agents.to_string(), &format!("{od_revenue:.2}")])?;
+    }
+
+    let mut by_line = revenue.by_line.iter().collect::<Vec<_>>();
+    by_line.sort_by(|a, b| a.0.cmp(b.0));
+    let mut csv_writer = csv::Writer::from_path(line_path)?;
+    csv_writer.write_record(["line", "boardings", "revenue"])?;
+    for (line, &(boardings, line_revenue)) in by_line {
+        csv_writer.write_record([line, &boardings.to_string(), &format!("{line_revenue:.2}")])?;
+    }
+
+    if revenue.num_unpriced > 0 {
+        println!("Warning: {} agents have no fare for their journey and earn no revenue.", revenue.num_unpriced);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{leg, stop, test_network};
+
+    fn fare_table(network: &Network) -> FareTable {
+        let zone = |name: &str| match name {
+            "West" | "North" => Some("1".to_owned()),
+            "South" | "East" => Some("2".to_owned()),
+            _ => None,
+        };
+        FareTable {
+            stop_zones: network.stops.iter().map(|stop| zone(&stop.name)).collect(),
+            fares: HashMap::from([(("1".to_owned(), "1".to_owned()), 2.), (("2".to_owned(), "1".to_owned()), 10.)]),
+        }
+    }
+
+    #[test]
+    fn fares_apply_both_ways() {
+        let (_, network) = test_network();
+        let fare_table = fare_table(&network);
+        let west = stop(&network, "West") as usize;
+        let south = stop(&network, "South") as usize;
+        assert_eq!(fare_table.fare(south, west), Some(10.));
+        assert_eq!(fare_table.fare(west, south), Some(10.));
+        assert_eq!(fare_table.fare(west, stop(&network, "North") as usize), Some(2.));
+        assert_eq!(fare_table.fare(west, stop(&network, "Central") as usize), None);
+        assert_eq!(fare_table.fare(south, stop(&network, "East") as usize), None);
+    }
+
+    #[test]
+    fn revenue_is_split_by_in_vehicle_time() {
+        let (_, network) = test_network();
+        let fare_table = fare_table(&network);
+        let journey = |start_stop, end_stop, count| AgentJourney { start_time: 8 * 3600, start_stop: stop(&network, start_stop), end_stop: stop(&network, end_stop), count };
+        let steps = [journey("West", "South", 2), journey("West", "Central", 3), journey("North", "East", 4)];
+        // The split only depends on each leg's in-vehicle time, so the legs needn't connect: 5 minutes on A1, then 11 on B1.
+        let journey_legs = vec![
+            vec![leg(&network, "A1", "West", "Central"), leg(&network, "B1", "North", "South")],
+            vec![leg(&network, "A1", "West", "Central")],
+            // No path.
+            Vec::new(),
+        ];
+        let revenue = journey_fare_revenue(&network, &steps, &journey_legs, &fare_table);
+
+        assert_eq!(revenue.num_unpriced, 3);
+        assert_eq!(revenue.by_od.len(), 1);
+        assert_eq!(revenue.by_od[&(stop(&network, "West") as usize, stop(&network, "South") as usize)], (2, 20.));
+        let line_revenue = |line| revenue.by_line[line];
+        let line_a = network.routes[leg(&network, "A1", "West", "Central").route_idx].line.to_string();
+        let line_b = network.routes[leg(&network, "B1", "North", "South").route_idx].line.to_string();
+        assert_eq!(line_revenue(&line_a).0, 2);
+        assert!((line_revenue(&line_a).1 - 20. * 5. / 16.).abs() < 1e-9);
+        assert_eq!(line_revenue(&line_b).0, 2);
+        assert!((line_revenue(&line_b).1 - 20. * 11. / 16.).abs() < 1e-9);
+    }
+}
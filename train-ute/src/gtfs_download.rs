@@ -0,0 +1,101 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum GtfsDownloadError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("HTTP error: {0}")]
+    Http(#[from] Box<ureq::Error>),
+    #[error("Zip error: {0}")]
+    Zip(#[from] zip::result::ZipError),
+}
+
+// Downloads a GTFS feed to the cache directory and returns its path, for reading with GtfsReader. A cached feed is
+// revalidated with its ETag, so it's only downloaded again when it changes on the server. If the server can't be
+// reached, the cached feed is used.
+pub fn download_gtfs(url: &str, cache_dir: &str) -> Result<PathBuf, GtfsDownloadError> {
+    std::fs::create_dir_all(cache_dir)?;
+    // Feeds are cached by a hash of their URL.
+    let url_hash = Sha256::digest(url.as_bytes()).iter().take(8).map(|byte| format!("{byte:02x}")).collect::<String>();
+    let feed_path = Path::new(cache_dir).join(format!("{url_hash}.zip"));
+    let etag_path = feed_path.with_extension("etag");
+
+    let cached_etag = if feed_path.exists() { std::fs::read_to_string(&etag_path).ok() } else { None };
+    let mut request = ureq::get(url);
+    if let Some(etag) = &cached_etag {
+        request = request.set("If-None-Match", etag.trim());
+    }
+
+    let response = match request.call() {
+        Ok(response) => response,
+        Err(error) if feed_path.exists() => {
+            println!("Warning: could not download {url} ({error}), using cached feed.");
+            return Ok(feed_path);
+        }
+        Err(error) => return Err(Box::new(error).into()),
+    };
+
+    if response.status() == 304 {
+        println!("GTFS feed at {url} is unchanged, using cached feed.");
+        return Ok(feed_path);
+    }
+
+    let etag = response.header("ETag").map(str::to_owned);
+    let mut feed = Vec::new();
+    response.into_reader().read_to_end(&mut feed)?;
+    std::fs::write(&feed_path, feed)?;
+    match etag {
+        Some(etag) => std::fs::write(&etag_path, etag)?,
+        None => {
+            // Without an ETag the feed is downloaded every time.
+            let _ = std::fs::remove_file(&etag_path);
+        }
+    }
+    println!("Downloaded GTFS feed from {url}.");
+
+    Ok(feed_path)
+}
+
+// A published feed to keep model inputs current from.
+pub struct FeedSource {
+    pub name: &'static str,
+    pub url: &'static str,
+    // Path of the feed inside the download, for operators that publish a zip of per-mode feeds.
+    pub inner_path: Option<&'static str>,
+}
+
+// PTV publishes one zip with a feed per mode (see the mode numbers in main), e.g. 2 for metropolitan trains.
+pub fn ptv_feed(mode: u8) -> FeedSource {
+    FeedSource {
+        name: "ptv",
+        url: "https://data.ptv.vic.gov.au/downloads/gtfs.zip",
+        inner_path: Some(["1/google_transit.zip", "2/google_transit.zip", "3/google_transit.zip", "4/google_transit.zip", "5/google_transit.zip", "6/google_transit.zip"][(mode as usize).clamp(1, 6) - 1]),
+    }
+}
+
+// Downloads the latest feed from a source (see download_gtfs) and returns the path of the GTFS zip to load, extracting
+// it from the download if it's nested.
+pub fn download_feed(source: &FeedSource, cache_dir: &str) -> Result<PathBuf, GtfsDownloadError> {
+    let download_path = download_gtfs(source.url, cache_dir)?;
+    let Some(inner_path) = source.inner_path else {
+        return Ok(download_path);
+    };
+
+    let feed_path = Path::new(cache_dir).join(format!("{}-{}", source.name, inner_path.replace('/', "-")));
+    // Only extract again if the download has changed since.
+    let is_stale = match (std::fs::metadata(&feed_path), std::fs::metadata(&download_path)) {
+        (Ok(feed), Ok(download)) => feed.modified()? < download.modified()?,
+        _ => true,
+    };
+    if is_stale {
+        let mut archive = zip::ZipArchive::new(std::fs::File::open(&download_path)?)?;
+        let mut inner_file = archive.by_name(inner_path)?;
+        std::io::copy(&mut inner_file, &mut std::fs::File::create(&feed_path)?)?;
+    }
+
+    Ok(feed_path)
+}
@@ -0,0 +1,161 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{Datelike, NaiveDate};
+use gtfs_structures::Gtfs;
+
+// Issues found in a GTFS feed that affect network construction or simulation.
+pub struct GtfsReport {
+    // Route ids with at least one trip that has no (or an unknown) shape.
+    pub routes_missing_shapes: Vec<String>,
+    // Stop ids with no latitude or longitude.
+    pub stops_missing_coordinates: Vec<String>,
+    // Trip ids whose stop times go backwards in time.
+    pub non_monotonic_trips: Vec<String>,
+    // The date range covered by the feed's calendar, if it has one.
+    pub calendar_range: Option<(NaiveDate, NaiveDate)>,
+    pub date: NaiveDate,
+    // Number of trips that run on the chosen date, out of the total.
+    pub active_trips: usize,
+    pub total_trips: usize,
+}
+
+impl GtfsReport {
+    pub fn is_valid(&self) -> bool {
+        self.routes_missing_shapes.is_empty() && self.stops_missing_coordinates.is_empty() && self.non_monotonic_trips.is_empty() && self.active_trips > 0
+    }
+
+    pub fn print(&self) {
+        if self.is_valid() {
+            println!("GTFS validation passed ({} of {} trips run on {}).", self.active_trips, self.total_trips, self.date);
+            return;
+        }
+
+        println!("GTFS validation issues:");
+        if !self.routes_missing_shapes.is_empty() {
+            println!("  {} routes with missing shapes: {}", self.routes_missing_shapes.len(), self.routes_missing_shapes.join(", "));
+        }
+        if !self.stops_missing_coordinates.is_empty() {
+            println!("  {} stops with no coordinates: {}", self.stops_missing_coordinates.len(), self.stops_missing_coordinates.join(", "));
+        }
+        if !self.non_monotonic_trips.is_empty() {
+            println!("  {} trips with non-monotonic stop times: {}", self.non_monotonic_trips.len(), self.non_monotonic_trips.join(", "));
+        }
+        match self.calendar_range {
+            Some((start, end)) if self.date < start || self.date > end => println!("  Date {} is outside the calendar range {start} to {end}.", self.date),
+            _ => {}
+        }
+        println!("  {} of {} trips run on {}.", self.active_trips, self.total_trips, self.date);
+    }
+}
+
+pub fn validate_gtfs(gtfs: &Gtfs, date: NaiveDate) -> GtfsReport {
+    let mut routes_missing_shapes = Vec::new();
+    let mut non_monotonic_trips = Vec::new();
+    let mut active_trips = 0;
+
+    // Whether each service runs on the date (trip_days returns day offsets from the date).
+    let mut service_active = HashMap::new();
+
+    for trip in gtfs.trips.values() {
+        let has_shape = trip.shape_id.as_ref().is_some_and(|shape_id| gtfs.shapes.contains_key(shape_id));
+        if !has_shape && !routes_missing_shapes.contains(&trip.route_id) {
+            routes_missing_shapes.push(trip.route_id.clone());
+        }
+
+        // Each stop's arrival and departure times must not go backwards.
+        let mut previous_time = 0;
+        let monotonic = trip.stop_times.iter().all(|stop_time| {
+            [stop_time.arrival_time, stop_time.departure_time].into_iter().flatten().all(|time| {
+                let ok = time >= previous_time;
+                previous_time = time;
+                ok
+            })
+        });
+        if !monotonic {
+            non_monotonic_trips.push(trip.id.clone());
+        }
+
+        let active = *service_active.entry(trip.service_id.as_str()).or_insert_with(|| gtfs.trip_days(&trip.service_id, date).contains(&0));
+        if active {
+            active_trips += 1;
+        }
+    }
+
+    let mut stops_missing_coordinates = gtfs.stops.values()
+        .filter(|stop| stop.latitude.is_none() || stop.longitude.is_none())
+        .map(|stop| stop.id.clone())
+        .collect::<Vec<_>>();
+
+    let calendar_range = gtfs.calendar.values().fold(None, |range, calendar| match range {
+        Some((start, end)) => Some((calendar.start_date.min(start), calendar.end_date.max(end))),
+        None => Some((calendar.start_date, calendar.end_date)),
+    });
+
+    // Sort for stable output.
+    routes_missing_shapes.sort();
+    stops_missing_coordinates.sort();
+    non_monotonic_trips.sort();
+
+    GtfsReport {
+        routes_missing_shapes,
+        stops_missing_coordinates,
+        non_monotonic_trips,
+        calendar_range,
+        date,
+        active_trips,
+        total_trips: gtfs.trips.len(),
+    }
+}
+
+// The trips running on one day of a feed.
+pub struct ServiceDay {
+    pub date: NaiveDate,
+    pub active_trips: usize,
+    // Keyed by GTFS route type (e.g. "Rail").
+    pub trips_by_route_type: BTreeMap<String, usize>,
+}
+
+// Counts the trips running on each day the feed covers, so a representative weekday can be picked rather than a public
+// holiday with a reduced timetable.
+pub fn service_days(gtfs: &Gtfs) -> Vec<ServiceDay> {
+    let calendar_dates = gtfs.calendar_dates.values().flatten().map(|calendar_date| calendar_date.date);
+    let calendar_starts = gtfs.calendar.values().map(|calendar| calendar.start_date);
+    let calendar_ends = gtfs.calendar.values().map(|calendar| calendar.end_date);
+    let (Some(first_date), Some(last_date)) = (calendar_starts.chain(calendar_dates.clone()).min(), calendar_ends.chain(calendar_dates).max()) else {
+        return Vec::new();
+    };
+    let num_days = (last_date - first_date).num_days() as usize + 1;
+
+    // Trips per service and route type, so each service's days only need working out once.
+    let mut service_trips: HashMap<&str, HashMap<String, usize>> = HashMap::new();
+    for trip in gtfs.trips.values() {
+        let route_type = gtfs.routes.get(&trip.route_id).map_or_else(|| "Unknown".to_owned(), |route| format!("{:?}", route.route_type));
+        *service_trips.entry(trip.service_id.as_str()).or_default().entry(route_type).or_default() += 1;
+    }
+
+    let mut days = (0..num_days).map(|offset| ServiceDay {
+        date: first_date + chrono::Duration::days(offset as i64),
+        active_trips: 0,
+        trips_by_route_type: BTreeMap::new(),
+    }).collect::<Vec<_>>();
+    for (service_id, route_type_trips) in service_trips {
+        for offset in gtfs.trip_days(service_id, first_date) {
+            let Some(day) = days.get_mut(offset as usize) else {
+                continue;
+            };
+            for (route_type, &num_trips) in route_type_trips.iter() {
+                day.active_trips += num_trips;
+                *day.trips_by_route_type.entry(route_type.clone()).or_default() += num_trips;
+            }
+        }
+    }
+    days
+}
+
+pub fn print_service_days(days: &[ServiceDay]) {
+    println!("Active trips per day:");
+    for day in days {
+        let route_types = day.trips_by_route_type.iter().map(|(route_type, num_trips)| format!("{route_type} {num_trips}")).collect::<Vec<_>>().join(", ");
+        println!("  {} {}: {} ({route_types})", day.date, day.date.weekday(), day.active_trips);
+    }
+}
@@ -0,0 +1,152 @@
+use std::sync::Arc;
+
+use chrono::NaiveDate;
+use gtfs_structures::{CalendarDate, Exception, Gtfs, LocationType, Route, RouteType, Stop, StopTime, Trip};
+
+use raptor::network::Timestamp;
+
+use crate::capacities::{CapacityMatcher, CapacityRule};
+use crate::simulation::AgentCount;
+
+// A stop on a hypothetical line.
+pub struct LineStop {
+    pub name: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    // Run time from the previous stop (seconds), ignored for the first stop.
+    pub run_time: Timestamp,
+}
+
+// A hypothetical line, defined without a GTFS feed. Trips run in both directions at a fixed headway.
+pub struct LineDefinition {
+    pub name: String,
+    pub stops: Vec<LineStop>,
+    // Departure times of the first and last trips from each terminus.
+    pub first_departure: Timestamp,
+    pub last_departure: Timestamp,
+    pub headway: Timestamp,
+    pub dwell_time: Timestamp,
+    pub capacity: AgentCount,
+}
+
+// Adds a hypothetical line to a GTFS feed before the network is built, running on the given date, so future lines
+// (e.g. the SRL) can be tested without fabricating a whole feed. Stops are matched to existing stops by name, so the
+// line connects to the rest of the network; other stops are created. Trip ids are "<line>-<direction>-<n>".
+// Returns the capacity rule for the line's trips, to add to the trip capacities.
+pub fn inject_line(gtfs: &mut Gtfs, line: &LineDefinition, date: NaiveDate) -> CapacityRule {
+    let route_id = format!("{}-route", line.name);
+    let service_id = format!("{}-service", line.name);
+
+    gtfs.routes.insert(route_id.clone(), Route {
+        id: route_id.clone(),
+        short_name: Some(line.name.clone()),
+        long_name: Some(line.name.clone()),
+        route_type: RouteType::Rail,
+        ..Default::default()
+    });
+    gtfs.calendar_dates.entry(service_id.clone()).or_default().push(CalendarDate {
+        service_id: service_id.clone(),
+        date,
+        exception_type: Exception::Added,
+    });
+
+    let stops = line.stops.iter().enumerate().map(|(i, line_stop)| {
+        // Stops are matched by name. A boardable stop is preferred to a station, as it joins the feed's trips whether or
+        // not platforms are later collapsed to their stations, and ties go to the lowest stop id so the choice doesn't
+        // depend on the feed's hash map order.
+        let existing_stop = gtfs.stops.values()
+            .filter(|stop| stop.name.as_deref() == Some(line_stop.name.as_str()))
+            .min_by_key(|stop| (stop.location_type != LocationType::StopPoint, &stop.id));
+        match existing_stop {
+            Some(stop) => stop.clone(),
+            None => {
+                let stop = Arc::new(Stop {
+                    id: format!("{}-stop-{i}", line.name),
+                    name: Some(line_stop.name.clone()),
+                    latitude: Some(line_stop.latitude),
+                    longitude: Some(line_stop.longitude),
+                    ..Default::default()
+                });
+                gtfs.stops.insert(stop.id.clone(), stop.clone());
+                stop
+            }
+        }
+    }).collect::<Vec<_>>();
+
+    // Run times into each stop, in stop order.
+    let run_times = line.stops.iter().map(|line_stop| line_stop.run_time).collect::<Vec<_>>();
+
+    for direction in 0..2 {
+        // The second direction runs the stops in reverse, with each run time belonging to the segment it precedes.
+        let stop_order = if direction == 0 { (0..stops.len()).collect::<Vec<_>>() } else { (0..stops.len()).rev().collect() };
+        // A zero headway is rejected when the run config is loaded, so max(1) only keeps step_by from panicking.
+        for (trip_number, departure) in (line.first_departure..=line.last_departure).step_by(line.headway.max(1) as usize).enumerate() {
+            let mut time = departure;
+            let mut stop_times = Vec::with_capacity(stops.len());
+            for (sequence, &stop_idx) in stop_order.iter().enumerate() {
+                if sequence > 0 {
+                    let previous_stop_idx = stop_order[sequence - 1];
+                    time += if direction == 0 { run_times[stop_idx] } else { run_times[previous_stop_idx] };
+                }
+                let arrival_time = time;
+                if sequence > 0 && sequence < stops.len() - 1 {
+                    time += line.dwell_time;
+                }
+                stop_times.push(StopTime {
+                    arrival_time: Some(arrival_time),
+                    departure_time: Some(time),
+                    stop: stops[stop_idx].clone(),
+                    stop_sequence: sequence as u16,
+                    ..Default::default()
+                });
+            }
+
+            let trip_id = format!("{}-{direction}-{trip_number}", line.name);
+            gtfs.trips.insert(trip_id.clone(), Trip {
+                id: trip_id,
+                service_id: service_id.clone(),
+                route_id: route_id.clone(),
+                stop_times,
+                ..Default::default()
+            });
+        }
+    }
+
+    CapacityRule { matcher: CapacityMatcher::RouteId(route_id), capacity: line.capacity, source_row: 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{read_feed, TEST_DATE};
+
+    // A station and two platforms all named Central.
+    const STOPS: &str = "stop_id,stop_name,stop_lat,stop_lon,location_type,parent_station
+CS,Central,-37.80,144.92,1,
+C2,Central,-37.80,144.92,0,CS
+C1,Central,-37.80,144.92,0,CS
+";
+
+    #[test]
+    fn stops_match_the_lowest_boardable_stop_id() {
+        let mut gtfs = read_feed(&[("stops.txt", STOPS), ("routes.txt", "route_id,route_short_name,route_type\n"), ("trips.txt", "route_id,service_id,trip_id\n"), ("stop_times.txt", "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n")]);
+        let line = LineDefinition {
+            name: "New".to_owned(),
+            stops: vec![
+                LineStop { name: "Central".to_owned(), latitude: -37.80, longitude: 144.92, run_time: 0 },
+                LineStop { name: "Elsewhere".to_owned(), latitude: -37.90, longitude: 144.92, run_time: 300 },
+            ],
+            first_departure: 8 * 3600,
+            last_departure: 9 * 3600,
+            headway: 1800,
+            dwell_time: 30,
+            capacity: 500,
+        };
+        inject_line(&mut gtfs, &line, TEST_DATE);
+
+        let stop_ids = gtfs.trips.values().flat_map(|trip| trip.stop_times.iter().map(|stop_time| stop_time.stop.id.clone())).collect::<std::collections::HashSet<_>>();
+        assert!(stop_ids.contains("C1") && !stop_ids.contains("C2") && !stop_ids.contains("CS"));
+        assert_eq!(stop_ids.len(), 2);
+        assert_eq!(gtfs.stops.len(), 4);
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::time::Instant;
 use std::fs::OpenOptions;
 use std::path::Path;
@@ -6,14 +7,42 @@ use std::io::Write;
 use chrono::NaiveDate;
 use gtfs_structures::GtfsReader;
 
-use raptor::network::Network;
+use raptor::network::{Network, StopIndex};
 
-use crate::simulation::{AgentCount, CrowdingCost, PopulationCount, SimulationParams, SimulationResult};
+use crate::capacities::{ResolvedTripCapacities, TripCapacities};
+use crate::crowding::CrowdingFunction;
+use crate::data_export::RunManifest;
+use crate::event_log::{EventLog, RunEvent};
+use crate::simulation::{AgentCount, CrowdingCost, PopulationCount, SimulationParams, SimulationResult, SimulationScratch, SimulationTimings};
 
 mod simulation;
+mod crowding;
+mod demand;
+mod emissions;
+mod fares;
+mod capacities;
+mod comparison;
+mod accessibility;
+#[cfg(feature = "object-store")]
+mod cloud_export;
+#[cfg(feature = "gtfs-download")]
+mod gtfs_download;
+mod gtfs_validation;
+mod line_injection;
+mod patterns;
+mod perturbation;
+mod statistics;
+mod stations;
+mod sub_network;
+mod sweep;
 mod data_import;
+mod config;
 mod data_export;
+mod event_log;
+mod exporters;
 mod utils;
+#[cfg(test)]
+mod test_fixtures;
 
 // Simulation notes:
 // When we get the O-D data, we can run journey planning for each OD and apply the passenger counts to the relevant trips.
@@ -23,28 +52,44 @@ mod utils;
 
 pub struct DefaultSimulationParams {
     pub max_train_capacity: AgentCount,
+    pub crowding_function: CrowdingFunction,
+    // Per-trip capacities, if any were imported. Trips without one use max_train_capacity.
+    pub trip_capacities: Option<ResolvedTripCapacities>,
+    // Per-route crowding functions, if any were given. Otherwise every route uses crowding_function.
+    pub route_crowding_functions: Option<Vec<CrowdingFunction>>,
+    // Per-route segment lengths in km, to charge crowding per passenger-km (see utils::route_segment_lengths).
+    // Otherwise crowding is charged per segment.
+    pub segment_lengths_km: Option<Vec<Vec<CrowdingCost>>>,
+    // Give identical results on any number of threads (see SimulationParams::deterministic).
+    pub deterministic: bool,
     cost_lookup_table: [CrowdingCost; Self::SAMPLES + 1],
 }
 
 impl DefaultSimulationParams {
     const SAMPLES: usize = 1000;
     pub fn new(max_train_capacity: AgentCount) -> Self {
+        Self::with_crowding_function(max_train_capacity, CrowdingFunction::Exponential(5.))
+    }
+    pub fn with_crowding_function(max_train_capacity: AgentCount, crowding_function: CrowdingFunction) -> Self {
         let mut result = Self {
             max_train_capacity,
+            crowding_function,
+            trip_capacities: None,
+            route_crowding_functions: None,
+            segment_lengths_km: None,
+            deterministic: false,
             cost_lookup_table: [0.; Self::SAMPLES + 1],
         };
 
         for i in 0..=Self::SAMPLES {
-            result.cost_lookup_table[i] = Self::f((i as CrowdingCost) / Self::SAMPLES as CrowdingCost);
+            result.cost_lookup_table[i] = crowding_function.eval((i as CrowdingCost) / Self::SAMPLES as CrowdingCost);
         }
 
         result
     }
-    fn f(x: CrowdingCost) -> CrowdingCost {
-        const B: CrowdingCost = 5.;
-        let bx = B * x;
-        let ebx = bx.exp();
-        (ebx - 1.) / (B.exp() - 1.)
+    // Looks up a crowding model preset by name.
+    pub fn from_preset(name: &str) -> Option<Self> {
+        crowding::find_preset(name).map(|preset| Self::with_crowding_function(preset.max_train_capacity, preset.crowding_function))
     }
 }
 
@@ -53,18 +98,113 @@ impl SimulationParams for DefaultSimulationParams {
         self.max_train_capacity
     }
 
-    fn cost_fn(&self, count: PopulationCount) -> CrowdingCost {
+    fn trip_capacity(&self, route_idx: usize, trip_idx: usize) -> AgentCount {
+        match &self.trip_capacities {
+            Some(trip_capacities) => trip_capacities.get(route_idx, trip_idx).capacity,
+            None => self.max_train_capacity,
+        }
+    }
+
+    fn cost_fn(&self, count: PopulationCount, capacity: AgentCount) -> CrowdingCost {
         debug_assert!(count >= 0, "Negative population count");
-        let proportion = count as CrowdingCost / self.max_train_capacity() as CrowdingCost;
-        Self::f(proportion)
+        let proportion = count as CrowdingCost / capacity as CrowdingCost;
+        self.crowding_function.eval(proportion)
+    }
+
+    fn route_cost_fn(&self, route_idx: usize, count: PopulationCount, capacity: AgentCount) -> CrowdingCost {
+        match &self.route_crowding_functions {
+            Some(route_crowding_functions) => {
+                debug_assert!(count >= 0, "Negative population count");
+                route_crowding_functions[route_idx].eval(count as CrowdingCost / capacity as CrowdingCost)
+            }
+            None => self.cost_fn(count, capacity),
+        }
+    }
+
+    fn segment_cost_weight(&self, route_idx: usize, stop_order: usize) -> CrowdingCost {
+        // The last stop of a route has no segment after it.
+        self.segment_lengths_km.as_ref()
+            .and_then(|segment_lengths_km| segment_lengths_km[route_idx].get(stop_order).copied())
+            .unwrap_or(1.)
+    }
+
+    fn deterministic(&self) -> bool {
+        self.deterministic
     }
 }
 
+// Finds a stop given by name in the run config.
+fn stop_idx(network: &Network, name: &str) -> Result<StopIndex, String> {
+    network.get_stop_idx_from_name(name).ok_or_else(|| format!("Unknown stop '{name}' in the run config"))
+}
+
+// The GTFS feed to read: downloaded (and cached between runs) if the run config gives a PTV mode or a URL, otherwise
+// gtfs_path.
+fn resolve_gtfs_path(config: &config::RunConfig) -> Result<String, Box<dyn std::error::Error>> {
+    #[cfg(feature = "gtfs-download")]
+    {
+        let cache_dir = "../gtfs/cache";
+        if let Some(mode) = config.ptv_feed_mode {
+            return Ok(gtfs_download::download_feed(&gtfs_download::ptv_feed(mode), cache_dir)?.to_string_lossy().into_owned());
+        }
+        if let Some(url) = &config.gtfs_url {
+            return Ok(gtfs_download::download_gtfs(url, cache_dir)?.to_string_lossy().into_owned());
+        }
+    }
+    #[cfg(not(feature = "gtfs-download"))]
+    if config.gtfs_url.is_some() || config.ptv_feed_mode.is_some() {
+        return Err("Downloading GTFS feeds needs the gtfs-download feature".into());
+    }
+    Ok(config.gtfs_path.clone())
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let exec_start = Instant::now();
+    let started_at = data_export::utc_now();
+
+    // `train-ute inspect-bin <path>` lists the chunks of a binary visualisation file, to check what train-vis will read.
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.get(1).map(String::as_str) == Some("inspect-bin") {
+        let path = args.get(2).ok_or("Usage: train-ute inspect-bin <path>")?;
+        let bin_file = data_import::read_bin(path)?;
+        println!("{path}: version {}", bin_file.version);
+        for (tag, data) in bin_file.chunks.iter() {
+            println!("  {}: {} bytes", String::from_utf8_lossy(tag), data.len());
+        }
+        return Ok(());
+    }
+
+    // Otherwise options come from the JSON file given as the first argument, if any.
+    let config = config::RunConfig::load(args.get(1).map(String::as_str))?;
+    let available_formats = exporters::available_formats::<DefaultSimulationParams>();
+    if let Some(format) = config.export_formats.iter().find(|format| !available_formats.contains(&format.as_str())) {
+        return Err(format!("Unknown export format '{format}', expected one of {}", available_formats.join(", ")).into());
+    }
+
+    // Record spans to a chrome trace file (view in chrome://tracing or Perfetto).
+    #[cfg(feature = "tracing")]
+    let _trace_guard = {
+        use tracing_subscriber::prelude::*;
+        let (chrome_layer, guard) = tracing_chrome::ChromeLayerBuilder::new().build();
+        tracing_subscriber::registry().with(chrome_layer).init();
+        guard
+    };
 
     // Set up network.
-    let network = {
+    let gtfs_path = resolve_gtfs_path(&config)?;
+    let gtfs_path = gtfs_path.as_str();
+    // Machine-readable log of the run's stages and warnings.
+    let mut event_log = EventLog::create("../data/events.jsonl")?;
+    event_log.log(RunEvent::RunStarted { gtfs_path })?;
+    // Files written before the run manifest is created, to add to it.
+    let mut early_exports = Vec::new();
+    // Capacity rules of the injected lines, added to the trip capacities.
+    let mut injected_capacity_rules = Vec::new();
+    let (gtfs, mut network, approximate_shapes) = {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("network_build").entered();
+        let journey_date = NaiveDate::from_ymd_opt(2024, 5, 10).unwrap();
+
         let gtfs_start = Instant::now();
         // PTV GTFS:
         // 1 - Regional Train
@@ -74,7 +214,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         // 5 - Regional Coach
         // 6 - Regional Bus
 
-        let gtfs = GtfsReader::default().read_shapes(true).read("../gtfs/2/google_transit.zip")?;
+        let mut gtfs = GtfsReader::default().read_shapes(true).read(gtfs_path)?;
         //let gtfs = GtfsReader::default().read_shapes(true).read("../gtfs/3/google_transit.zip")?;
         //let gtfs = GtfsReader::default().read_shapes(true).read("../gtfs/4/google_transit.zip")?;
 
@@ -84,50 +224,218 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         //let gtfs = GtfsReader::default().read_shapes(true).read("../gtfs_processing/SRL/data/srl-gtfs")?;
 
         println!("GTFS import: {:?}", gtfs_start.elapsed());
+        event_log.stage_completed("gtfs_import", gtfs_start.elapsed())?;
+
+        // Add hypothetical lines (e.g. the SRL) without a GTFS feed. Their capacity rules go in the trip capacities.
+        for injected_line in config.injected_lines.iter() {
+            let line = line_injection::LineDefinition {
+                name: injected_line.name.clone(),
+                stops: data_import::import_line_stops(&injected_line.stops_path)?,
+                first_departure: injected_line.first_departure,
+                last_departure: injected_line.last_departure,
+                headway: injected_line.headway,
+                dwell_time: injected_line.dwell_time,
+                capacity: injected_line.capacity,
+            };
+            injected_capacity_rules.push(line_injection::inject_line(&mut gtfs, &line, journey_date));
+        }
+
+        // Merge platform-level stops into their parent stations (for feeds with a stop per platform).
+        if config.collapse_to_parent_stations {
+            let station_mappings = stations::collapse_to_parent_stations(&mut gtfs);
+            println!("Merged {} stops into their parent stations.", station_mappings.len());
+            let station_mappings_path = "../data/station_mappings.csv";
+            stations::export_station_mappings(station_mappings_path, &station_mappings)?;
+            early_exports.push(station_mappings_path.to_owned());
+        }
+        // Cut the feed down to a few lines, to reproduce odd behaviour on a small network.
+        if !config.sub_network_routes.is_empty() {
+            let routes = config.sub_network_routes.iter().map(String::as_str).collect::<Vec<_>>();
+            let sub_network_routes = sub_network::extract_sub_network(&mut gtfs, &routes);
+            println!("Sub-network routes: {sub_network_routes:?}");
+        }
         gtfs.print_stats();
 
-        let journey_date = NaiveDate::from_ymd_opt(2024, 5, 10).unwrap();
+        // List the trips running on each day of the feed, to pick a representative weekday.
+        if config.print_service_days {
+            gtfs_validation::print_service_days(&gtfs_validation::service_days(&gtfs));
+        }
+        let gtfs_report = gtfs_validation::validate_gtfs(&gtfs, journey_date);
+        gtfs_report.print();
+        event_log.gtfs_report(&gtfs_report)?;
+
         let default_transfer_time = 3 * 60;
         let network_start = Instant::now();
         let mut network = Network::new(&gtfs, journey_date, default_transfer_time);
         println!("Network parse: {:?}", network_start.elapsed());
+        event_log.stage_completed("network_parse", network_start.elapsed())?;
 
         // Set Flinders Street transfer time.
         //let flinders = network.get_stop_idx_from_name("Flinders Street").unwrap() as usize;
         //network.transfer_times[flinders] = 4 * 60;
 
+        // Connect stops with straight lines where the feed has no shapes, so we still get a visualisation export.
+        let approximate_shapes = utils::gen_fallback_shapes(&mut network);
+        let num_fallback_shapes = approximate_shapes.iter().filter(|&&approximate| approximate).count();
+        if num_fallback_shapes > 0 {
+            println!("Warning: {num_fallback_shapes} routes have no GTFS shape, using approximate shapes from stop locations.");
+            event_log.warning("fallback_shapes", num_fallback_shapes, "Routes with no GTFS shape use approximate shapes from stop locations".to_owned())?;
+        }
+
+        // Give lines without a GTFS colour a generated one, so they don't all render the same.
+        let num_generated_colours = utils::gen_route_colours(&mut network, &gtfs);
+        if num_generated_colours > 0 {
+            println!("{num_generated_colours} routes have no GTFS colour, using generated colours.");
+        }
+
         let connections_start = Instant::now();
         network.build_connections();
         println!("Build connections: {:?}", connections_start.elapsed());
+        event_log.stage_completed("build_connections", connections_start.elapsed())?;
 
-        network
+        (gtfs, network, approximate_shapes)
     };
 
+    // Report the stopping patterns of each line, as loads often differ between express and all-stops services.
+    let stopping_patterns = patterns::classify_stopping_patterns(&network);
+    patterns::print_stopping_patterns(&network, &stopping_patterns);
+
+    // Convert the raw PTV passenger counts to parquet.
+    if let Some(patronage_csv) = &config.patronage_csv {
+        let patronage = data_import::import_patronage_csv(&patronage_csv.path, &patronage_csv.mode)?;
+        data_export::write_parquet("../data/patronage.parquet", &patronage, config.parquet_compression)?;
+    }
+
     // Set up thread pool for benchmarking.
-    let num_processors = 40;
-    rayon::ThreadPoolBuilder::new().num_threads(num_processors).build_global()?;
+    rayon::ThreadPoolBuilder::new().num_threads(config.num_threads).build_global()?;
+    let num_processors = rayon::current_num_threads();
 
-    // Set up simulation.
-    let params = DefaultSimulationParams::new(
-        // From VicSig: X'Trapolis 3-car has 264 seated, 133 standing. A 6-car has 794 in total.
-        // Crush capacity is 1394, but that's a bit mean.
-        // https://vicsig.net/suburban/train/X'Trapolis
-        794,
-    );
+    // Set up simulation, from a crowding model preset if one is configured (see crowding::CROWDING_PRESETS).
+    let mut params = match &config.crowding_preset {
+        Some(name) => DefaultSimulationParams::from_preset(name).ok_or_else(|| {
+            let preset_names = crowding::CROWDING_PRESETS.iter().map(|preset| preset.name).collect::<Vec<_>>();
+            format!("Unknown crowding preset '{name}', expected one of {}", preset_names.join(", "))
+        })?,
+        None => DefaultSimulationParams::new(
+            // From VicSig: X'Trapolis 3-car has 264 seated, 133 standing. A 6-car has 794 in total.
+            // Crush capacity is 1394, but that's a bit mean.
+            // https://vicsig.net/suburban/train/X'Trapolis
+            794,
+        ),
+    };
+    params.crowding_function.validate()?;
+    params.deterministic = config.deterministic;
+
+    // Override capacities for particular trips or routes, if a capacities file is present.
+    let trip_capacities_path = "../data/trip_capacities.csv";
+    let mut trip_capacities = if Path::new(trip_capacities_path).exists() {
+        let trip_capacities = data_import::import_trip_capacities(trip_capacities_path)?;
+        println!("Imported {} trip capacity rules.", trip_capacities.rules.len());
+        trip_capacities
+    } else {
+        TripCapacities::default()
+    };
+    trip_capacities.rules.append(&mut injected_capacity_rules);
+    params.trip_capacities = Some(trip_capacities.resolve(&network, &gtfs, params.max_train_capacity));
+
+    // Per-route crowding functions, e.g. for coaches with different standing tolerance, if a file of them is present.
+    let route_crowding_path = "../data/route_crowding.csv";
+    let route_crowding_functions = if Path::new(route_crowding_path).exists() {
+        let route_crowding_functions = data_import::import_route_crowding_functions(route_crowding_path)?;
+        println!("Imported {} route crowding functions.", route_crowding_functions.len());
+        params.route_crowding_functions = Some(crowding::resolve_route_crowding_functions(&network, &gtfs, &route_crowding_functions, params.crowding_function));
+        route_crowding_functions
+    } else {
+        HashMap::new()
+    };
+
+    // Charge crowding per passenger-km rather than per segment, as some appraisal frameworks do.
+    if config.crowding_per_km {
+        let segment_lengths = utils::route_segment_lengths(&network, &gtfs, data_export::SHAPE_MATCH_TOLERANCE);
+        params.segment_lengths_km = Some(segment_lengths.iter().map(|lengths| lengths.iter().map(|&length| length / 1000.).collect()).collect());
+    }
 
     // Run prefix sum benchmark.
     //simulation::simulation_prefix_benchmark(&network, &params, "../data/benchmark.csv")?;
 
     // Run simulation and print duration to csv.
-    let simulation_steps = simulation::gen_simulation_steps(&network, None, Some(0));
+    // Random demand unless the config gives a source (OD demand with departure windows, or demand synthesised from
+    // station entries and exits with a gravity model).
+    let seed = config.demand.seed();
+    let demand_provider = config.demand.provider();
+    let simulation_steps = demand_provider.simulation_steps(&network)?;
+    // Restrict demand to a departure window and/or lines, e.g. the AM peak.
+    let simulation_steps = simulation::filter_simulation_steps(&network, &simulation_steps, &config.demand_filter);
+    // Merge steps with the same origin, departure time and destination.
+    let simulation_steps = simulation::consolidate_simulation_steps(&simulation_steps);
+    // Simulate a sample of the demand for quick iteration. Counts and steps are expanded back to the full demand
+    // after the run, so every export is in terms of the full demand.
+    let sample_fraction = config.sample_fraction;
+    let simulation_steps = match sample_fraction {
+        Some(fraction) => simulation::sample_simulation_steps(&simulation_steps, fraction, seed.unwrap_or_default())?,
+        None => simulation_steps,
+    };
+
+    // Estimate memory and runtime from a calibration sample before a long run.
+    if let Some(sample_size) = config.estimate_sample_size {
+        let estimate = simulation::estimate_run(&network, &simulation_steps, &params, sample_size);
+        println!("Estimated {} unique queries, {} MB, {:?} per run", estimate.num_unique_queries, estimate.memory_bytes / (1 << 20), estimate.duration);
+    }
 
-    let mut simulation_result = SimulationResult { agent_journeys: Vec::new() };
+    let mut simulation_result = SimulationResult { agent_journeys: Vec::new(), count_issues: Vec::new(), timings: SimulationTimings::default() };
+    let mut simulation_scratch = SimulationScratch::new();
     let simulation_start = Instant::now();
     let num_iterations = 5;
     for _ in 0..num_iterations {
-        simulation_result = simulation::run_simulation::<_, true>(&network, &simulation_steps, &params);
+        simulation_result = simulation::run_simulation_with_scratch::<_, true>(&network, &simulation_steps, &params, &mut simulation_scratch);
     }
     let duration = simulation_start.elapsed() / num_iterations;
+    event_log.log(RunEvent::SimulationCompleted { num_steps: simulation_steps.len(), duration_ms: duration.as_millis() })?;
+    if params.deterministic && !simulation::compare_thread_counts::<_, true>(&network, &simulation_steps, &params, num_processors)? {
+        println!("Warning: results differ between 1 and {num_processors} threads.");
+        event_log.warning("thread_count_dependent", 1, format!("Results differ between 1 and {num_processors} threads"))?;
+    }
+    if !simulation_result.count_issues.is_empty() {
+        println!("Warning: {} trip stop counts were out of range, see the event log.", simulation_result.count_issues.len());
+        event_log.count_issues(&simulation_result.count_issues)?;
+    }
+
+    let num_simulated_steps = simulation_steps.len();
+    let simulation_steps = match sample_fraction {
+        Some(fraction) => {
+            simulation::expand_simulation_result(&mut simulation_result, fraction)?;
+            simulation::expand_simulation_steps(&simulation_steps, fraction)?
+        }
+        None => simulation_steps,
+    };
+
+    // Print a single journey, with the crowding costs of the run, to sanity-check odd assignment results.
+    if let Some(debug_journey) = &config.debug_journey {
+        let origin = stop_idx(&network, &debug_journey.origin)?;
+        let destination = stop_idx(&network, &debug_journey.destination)?;
+        simulation::debug_journey(&network, origin, debug_journey.departure_time, destination, Some(&simulation_result), &params);
+    }
+
+    // Layer agents with prescribed paths (e.g. special-event flows) under the modelled demand, if a file of them is present.
+    let pinned_paths_path = "../data/pinned_paths.csv";
+    if Path::new(pinned_paths_path).exists() {
+        let pinned_paths = data_import::import_pinned_paths(pinned_paths_path, &network)?;
+        println!("Imported {} pinned paths.", pinned_paths.len());
+        simulation::load_pinned_paths(&network, &mut simulation_result, &pinned_paths);
+    }
+
+    // Monte Carlo replications with perturbed run and dwell times, with agents planning on the run's crowding costs.
+    if let Some(perturbation_config) = &config.perturbation {
+        let trip_stops_cost = simulation::crowding_costs(&network, &simulation_result, &params);
+        let perturbation_summary = perturbation::run_perturbed_replications(&mut network, &simulation_steps, Some(&trip_stops_cost), &perturbation_config.noise, perturbation_config.num_replications, perturbation_config.seed);
+        let total_missed_transfers = perturbation_summary.missed_transfers.iter().sum::<u64>();
+        println!("Perturbation: {total_missed_transfers} missed transfers over {} replications.", perturbation_summary.num_replications);
+        let perturbed_loads_path = "../data/perturbed_loads.csv";
+        let perturbed_replications_path = "../data/perturbed_replications.csv";
+        let perturbed_delays_path = "../data/perturbed_delays.csv";
+        perturbation::export_perturbation_summary(perturbed_loads_path, perturbed_replications_path, perturbed_delays_path, &network, &perturbation_summary)?;
+        early_exports.extend([perturbed_loads_path.to_owned(), perturbed_replications_path.to_owned(), perturbed_delays_path.to_owned()]);
+    }
 
     // Append to csv.
     {
@@ -139,22 +447,171 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
         writeln!(&mut simulation_benchmark_file, "{num_processors},{}", duration.as_micros())?;
 
-      println!("Simulation duration {:?} to run {} steps", duration, simulation_steps.len());
+      println!("Simulation duration {:?} to run {} steps", duration, num_simulated_steps);
     }
 
     println!("Exporting results.");
+    let mut manifest = RunManifest::new(gtfs_path, network.date, params.max_train_capacity, params.crowding_function, num_simulated_steps, seed, started_at);
+    if manifest.gtfs_sha256.is_none() {
+        println!("Warning: could not read {gtfs_path} to hash it for the run manifest.");
+        event_log.warning("gtfs_not_hashed", 0, format!("Could not read {gtfs_path} to hash it for the run manifest"))?;
+    }
+    manifest.route_crowding_functions = route_crowding_functions.iter().map(|(route_id, function)| (route_id.clone(), *function)).collect();
+    manifest.crowding_per_km = params.segment_lengths_km.is_some();
+    manifest.sample_fraction = sample_fraction;
+    for (stage, duration) in event_log.stage_timings() {
+        manifest.record_timing(stage, *duration);
+    }
+    // From the last simulation iteration.
+    manifest.record_timing("simulation_query", simulation_result.timings.query);
+    manifest.record_timing("simulation_prefix_sum", simulation_result.timings.prefix_sum);
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("export").entered();
     let export_start = Instant::now();
-    data_export::export_agent_counts("../data/counts.parquet", &network, &simulation_result)?;
-    if network.has_shapes {
-        data_export::export_shape_file("../train-vis/src/data/shapes.bin.zip", &network)?;
-        data_export::export_network_trips("../train-vis/src/data/trips.bin.zip", &network, &simulation_result)?;
-    } else {
-        println!("Warning: GTFS shapes not loaded, no visualisation export.");
+    // Counts, occupancy, load statistics and the visualisation files, in the configured formats.
+    let export_context = exporters::ExportContext { network: &network, gtfs: &gtfs, approximate_shapes: &approximate_shapes, simulation_result: &simulation_result, params: &params };
+    let export_options = exporters::ExportOptions {
+        output_dir: "../data".to_owned(),
+        visualisation_dir: "../train-vis/src/data".to_owned(),
+        formats: config.export_formats.clone(),
+        parquet_compression: config.parquet_compression,
+        occupancy_format: config.occupancy_format,
+        columns: data_export::ExportColumns::excluding(&config.excluded_columns),
+    };
+    manifest.exports.append(&mut early_exports);
+    manifest.exports.extend(exporters::export_all(&export_context, &export_options)?);
+    // Compare against observed boardings, if present, flagging poorly matched trip stops for calibration.
+    let observed_boardings_path = "../data/observed_boardings.csv";
+    if Path::new(observed_boardings_path).exists() {
+        let observed_boardings = data_import::import_observed_boardings(observed_boardings_path, &network)?;
+        let comparisons = comparison::compare_boardings(&network, &simulation_steps, &observed_boardings);
+        comparison::print_comparison_summary(&comparisons);
+        let comparison_path = "../data/boardings_comparison.csv";
+        comparison::export_boardings_comparison(comparison_path, &network, &comparisons)?;
+        manifest.exports.push(comparison_path.to_owned());
+    }
+    let capacities_path = "../data/trip_capacities_applied.csv";
+    if let Some(resolved_capacities) = &params.trip_capacities {
+        data_export::export_trip_capacities(capacities_path, &network, &trip_capacities, resolved_capacities)?;
+        manifest.exports.push(capacities_path.to_owned());
+    }
+    let time_bands = config.time_bands();
+    // Counts aggregated to time bands, which is much smaller than the full counts table.
+    if config.export_counts_by_time_band {
+        let counts_by_band_path = "../data/counts_by_band.parquet";
+        data_export::export_agent_counts_by_time_band(counts_by_band_path, &network, &simulation_result, &time_bands, export_options.parquet_compression)?;
+        manifest.exports.push(counts_by_band_path.to_owned());
+    }
+    if config.export_station_interchanges {
+        let station_interchanges_path = "../data/station_interchanges.parquet";
+        data_export::export_station_interchanges(station_interchanges_path, &network, &simulation_steps, &time_bands, export_options.parquet_compression)?;
+        manifest.exports.push(station_interchanges_path.to_owned());
+    }
+    if config.export_line_transfers {
+        let line_transfers_path = "../data/line_transfers.csv";
+        let line_transfer_bands = config.line_transfers_by_time_band.then_some(time_bands.as_slice());
+        data_export::export_line_transfers(line_transfers_path, &network, &simulation_steps, line_transfer_bands)?;
+        manifest.exports.push(line_transfers_path.to_owned());
+    }
+    // Station entries and exits per time bin, to check against gate counts.
+    if let Some(bin_size) = config.gate_flow_bin_size {
+        let gate_flows_path = "../data/gate_flows.csv";
+        data_export::export_gate_flows(gate_flows_path, &network, &simulation_steps, bin_size)?;
+        manifest.exports.push(gate_flows_path.to_owned());
+    }
+    // Fare revenue per OD and line, if stop fare zones and a zone-to-zone fare table are present.
+    let fare_zones_path = "../data/fare_zones.csv";
+    let fares_path = "../data/fares.csv";
+    if Path::new(fare_zones_path).exists() && Path::new(fares_path).exists() {
+        let fare_table = data_import::import_fare_table(fare_zones_path, fares_path, &network)?;
+        let revenue = fares::fare_revenue(&network, &simulation_steps, &fare_table);
+        let revenue_by_od_path = "../data/revenue_by_od.csv";
+        let revenue_by_line_path = "../data/revenue_by_line.csv";
+        fares::export_fare_revenue(revenue_by_od_path, revenue_by_line_path, &network, &revenue)?;
+        manifest.exports.extend([revenue_by_od_path.to_owned(), revenue_by_line_path.to_owned()]);
+    }
+    // Traction energy and CO2 per line and time band.
+    if let Some(energy_model) = &config.energy_model {
+        let trip_energy = emissions::trip_energy(&network, &simulation_result, &utils::route_segment_lengths(&network, &gtfs, data_export::SHAPE_MATCH_TOLERANCE), energy_model);
+        let energy_path = "../data/energy_by_line.csv";
+        emissions::export_energy_by_line(energy_path, &network, &trip_energy, &time_bands)?;
+        manifest.exports.push(energy_path.to_owned());
+    }
+    // The cost curves of the crowding presets, for comparison.
+    if config.export_cost_curves {
+        let models = crowding::CROWDING_PRESETS.iter().map(|preset| (preset.name, DefaultSimulationParams::with_crowding_function(preset.max_train_capacity, preset.crowding_function))).collect::<Vec<_>>();
+        let cost_curves_path = "../data/cost_curves.csv";
+        crowding::export_cost_curves(cost_curves_path, &models)?;
+        manifest.exports.push(cost_curves_path.to_owned());
+    }
+    // Sweep the default train capacity and the crowding curve to see how crowding costs respond. Each value keeps the
+    // run's trip capacity rules, per-route crowding functions and per-km weighting, so costs match the run.
+    let sweep_params = |max_train_capacity: AgentCount, crowding_function: CrowdingFunction| {
+        let mut sweep_params = DefaultSimulationParams::with_crowding_function(max_train_capacity, crowding_function);
+        sweep_params.trip_capacities = Some(trip_capacities.resolve(&network, &gtfs, max_train_capacity));
+        sweep_params.route_crowding_functions = params.route_crowding_functions.as_ref()
+            .map(|_| crowding::resolve_route_crowding_functions(&network, &gtfs, &route_crowding_functions, crowding_function));
+        sweep_params.segment_lengths_km = params.segment_lengths_km.clone();
+        sweep_params
+    };
+    if !config.capacity_sweep.is_empty() {
+        let capacities = config.capacity_sweep.iter().map(|&capacity| capacity as f64).collect::<Vec<_>>();
+        let capacity_sweep = sweep::run_cost_sweep(&network, &simulation_result, &capacities, |capacity| sweep_params(capacity as AgentCount, params.crowding_function));
+        let capacity_sweep_path = "../data/capacity_sweep.csv";
+        sweep::export_sweep_csv(capacity_sweep_path, "max_train_capacity", &capacity_sweep)?;
+        manifest.exports.push(capacity_sweep_path.to_owned());
+    }
+    if !config.crowding_exponent_sweep.is_empty() {
+        let exponents = config.crowding_exponent_sweep.iter().map(|&exponent| exponent as f64).collect::<Vec<_>>();
+        let exponent_sweep = sweep::run_cost_sweep(&network, &simulation_result, &exponents, |exponent| sweep_params(params.max_train_capacity, CrowdingFunction::Exponential(exponent as CrowdingCost)));
+        let exponent_sweep_path = "../data/crowding_exponent_sweep.csv";
+        sweep::export_sweep_csv(exponent_sweep_path, "crowding_exponent", &exponent_sweep)?;
+        manifest.exports.push(exponent_sweep_path.to_owned());
+    }
+    // Travel times from a single origin, with route choice taking the run's crowding into account.
+    if let Some(accessibility_config) = &config.accessibility {
+        let origin = stop_idx(&network, &accessibility_config.origin)?;
+        let accessibility = accessibility::calculate_accessibility(&network, origin, accessibility_config.departure_time, Some(&simulation_result), &params);
+        let accessibility_csv_path = "../data/accessibility.csv";
+        let accessibility_geojson_path = "../data/accessibility.geojson";
+        accessibility::export_accessibility_csv(accessibility_csv_path, &network, &accessibility)?;
+        accessibility::export_accessibility_geojson(accessibility_geojson_path, &network, &accessibility)?;
+        manifest.exports.extend([accessibility_csv_path.to_owned(), accessibility_geojson_path.to_owned()]);
+    }
+    let patterns_path = "../data/stopping_patterns.csv";
+    patterns::export_stopping_patterns(patterns_path, &network, &stopping_patterns)?;
+    manifest.exports.push(patterns_path.to_owned());
+    // Per-journey legs with geometry and cost breakdown (slow for large demand, as every journey is re-queried).
+    if config.export_journeys {
+        let trip_stops_cost = simulation::crowding_costs(&network, &simulation_result, &params);
+        let journeys_path = "../data/journeys.parquet";
+        data_export::export_agent_journeys(journeys_path, &network, &gtfs, &simulation_steps, Some(&trip_stops_cost), &export_options.columns, export_options.parquet_compression)?;
+        manifest.exports.push(journeys_path.to_owned());
+    }
+    // Traces of a few individual agents for animation.
+    if let Some(agent_traces) = &config.agent_traces {
+        let traces_path = "../train-vis/src/data/agent_traces.bin.zip";
+        data_export::export_agent_traces(traces_path, &network, &gtfs, &simulation_steps, &agent_traces.journey_indices, agent_traces.time_step)?;
+        manifest.exports.push(traces_path.to_owned());
+    }
+    manifest.record_timing("export", export_start.elapsed());
+    data_export::export_run_manifest("../data/manifest.json", &mut manifest)?;
+    // Copy the exports to cloud storage, e.g. TRAIN_UTE_EXPORT_URL=s3://bucket/run-1.
+    #[cfg(feature = "object-store")]
+    if let Ok(export_url) = std::env::var("TRAIN_UTE_EXPORT_URL") {
+        let mut exports = manifest.exports.clone();
+        exports.push("../data/manifest.json".to_owned());
+        cloud_export::upload_exports(&exports, &export_url)?;
     }
     println!("Export duration: {:?}", export_start.elapsed());
+    for path in manifest.exports.iter() {
+        event_log.log(RunEvent::ExportWritten { path })?;
+    }
+    event_log.stage_completed("export", export_start.elapsed())?;
 
     println!();
     println!("Total time: {:?}", exec_start.elapsed());
+    event_log.log(RunEvent::RunFinished { duration_ms: exec_start.elapsed().as_millis() })?;
 
     Ok(())
 }
@@ -0,0 +1,97 @@
+use std::collections::HashSet;
+
+use raptor::Network;
+use raptor::network::StopIndex;
+
+use crate::data_export::DataExportError;
+
+// Every trip in a network route stops at the same stops, so each route is one stopping pattern, and its index is the
+// pattern id used in exports.
+pub enum PatternKind {
+    AllStops,
+    // Skips stops that another pattern on the same line serves between the same stops.
+    Express { skipped_stops: Vec<StopIndex> },
+}
+
+pub struct StoppingPattern {
+    pub route_idx: usize,
+    pub kind: PatternKind,
+}
+
+impl PatternKind {
+    pub fn label(&self) -> String {
+        match self {
+            PatternKind::AllStops => "all-stops".to_owned(),
+            PatternKind::Express { skipped_stops } => format!("express (skips {})", skipped_stops.len()),
+        }
+    }
+}
+
+// Classifies each route's stopping pattern by comparing it to the other routes on its line. A route is express if a
+// route on the same line passes through its first and last stops in the same order and stops somewhere in between
+// that it doesn't.
+pub fn classify_stopping_patterns(network: &Network) -> Vec<StoppingPattern> {
+    let route_stops = network.routes.iter().map(|route| route.get_stops(&network.route_stops)).collect::<Vec<_>>();
+
+    (0..network.num_routes()).map(|route_idx| {
+        let stops = route_stops[route_idx];
+        let stop_set = stops.iter().copied().collect::<HashSet<_>>();
+        let (Some(&first_stop), Some(&last_stop)) = (stops.first(), stops.last()) else {
+            return StoppingPattern { route_idx, kind: PatternKind::AllStops };
+        };
+
+        // The stops each other route on the line serves between this route's terminals, keeping the most.
+        let line = &network.routes[route_idx].line;
+        let skipped_stops = (0..network.num_routes())
+            .filter(|&other_idx| other_idx != route_idx && network.routes[other_idx].line == *line)
+            .filter_map(|other_idx| {
+                let other_stops = route_stops[other_idx];
+                let start = other_stops.iter().position(|&stop| stop == first_stop)?;
+                let end = other_stops.iter().position(|&stop| stop == last_stop)?;
+                (start < end).then(|| other_stops[start..=end].iter().copied().filter(|stop| !stop_set.contains(stop)).collect::<Vec<_>>())
+            })
+            .max_by_key(|skipped_stops| skipped_stops.len())
+            .unwrap_or_default();
+
+        let kind = if skipped_stops.is_empty() { PatternKind::AllStops } else { PatternKind::Express { skipped_stops } };
+        StoppingPattern { route_idx, kind }
+    }).collect()
+}
+
+pub fn print_stopping_patterns(network: &Network, patterns: &[StoppingPattern]) {
+    let num_express = patterns.iter().filter(|pattern| matches!(pattern.kind, PatternKind::Express { .. })).count();
+    println!("{} stopping patterns ({num_express} express):", patterns.len());
+    for pattern in patterns {
+        let route = &network.routes[pattern.route_idx];
+        let stops = route.get_stops(&network.route_stops);
+        println!("  {} [{}]: {} to {}, {} stops, {} trips, {}",
+                 route.line, pattern.route_idx,
+                 network.stops[stops[0] as usize].name, network.stops[stops[stops.len() - 1] as usize].name,
+                 stops.len(), route.num_trips, pattern.kind.label());
+    }
+}
+
+pub fn export_stopping_patterns(path: &str, network: &Network, patterns: &[StoppingPattern]) -> Result<(), DataExportError> {
+    let mut csv_writer = csv::Writer::from_path(path)?;
+    csv_writer.write_record(["pattern_id", "line", "kind", "first_stop", "last_stop", "num_stops", "num_trips", "skipped_stops"])?;
+    for pattern in patterns {
+        let route = &network.routes[pattern.route_idx];
+        let stops = route.get_stops(&network.route_stops);
+        let skipped_stops = match &pattern.kind {
+            PatternKind::AllStops => String::new(),
+            PatternKind::Express { skipped_stops } => skipped_stops.iter().map(|&stop_idx| &*network.stops[stop_idx as usize].name).collect::<Vec<_>>().join(";"),
+        };
+        csv_writer.write_record(&[
+            pattern.route_idx.to_string(),
+            route.line.to_string(),
+            pattern.kind.label(),
+            network.stops[stops[0] as usize].name.to_string(),
+            network.stops[stops[stops.len() - 1] as usize].name.to_string(),
+            stops.len().to_string(),
+            route.num_trips.to_string(),
+            skipped_stops,
+        ])?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+
+use rand::prelude::*;
+use rayon::prelude::*;
+use serde::Deserialize;
+
+use raptor::{Network, raptor_query};
+use raptor::network::{StopIndex, Timestamp};
+use raptor::utils::get_time_str;
+
+use crate::data_export::DataExportError;
+use crate::simulation::{load_pinned_paths, query_journey_legs, AgentJourney, CrowdingCost, JourneyLeg, PinnedPath, PopulationCount, SimulationResult, SimulationTimings};
+use crate::statistics::{percentile, weighted_percentile};
+
+// Random delays applied to the timetable in each replication.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct NoiseModel {
+    // Standard deviation of the extra run time between stops, as a fraction of the scheduled run time.
+    pub run_time_sd: f32,
+    // Standard deviation of the extra dwell time at each stop (seconds).
+    pub dwell_sd: f32,
+}
+
+// Standard normal sample (Box-Muller), as rand's distributions crate isn't a dependency.
+fn standard_normal(rng: &mut SmallRng) -> f32 {
+    let u1 = rng.gen::<f32>().max(f32::MIN_POSITIVE);
+    let u2 = rng.gen::<f32>();
+    (-2. * u1.ln()).sqrt() * (std::f32::consts::TAU * u2).cos()
+}
+
+// Replaces the timetable with a perturbed copy of the scheduled times. Delays build up along each trip and are never
+// negative (services don't run early), and a trip never overtakes the previous trip of its route at any stop,
+// which RAPTOR relies on.
+fn perturb_timetable(network: &mut Network, scheduled: &[(Timestamp, Timestamp)], noise: &NoiseModel, rng: &mut SmallRng) {
+    for route_idx in 0..network.num_routes() {
+        let mut previous_trip_times: Vec<(Timestamp, Timestamp)> = Vec::new();
+        for trip_idx in 0..network.num_trips(route_idx) {
+            let trip_range = network.routes[route_idx].get_trip_range(trip_idx);
+            let mut delay = 0f32;
+            let mut trip_times = Vec::with_capacity(trip_range.len());
+            for (stop_order, stop_time_idx) in trip_range.enumerate() {
+                let (arrival_time, departure_time) = scheduled[stop_time_idx];
+                if stop_order > 0 {
+                    let (_, previous_departure_time) = scheduled[stop_time_idx - 1];
+                    let run_time = arrival_time.saturating_sub(previous_departure_time) as f32;
+                    delay = (delay + run_time * noise.run_time_sd * standard_normal(rng)).max(0.);
+                }
+                let mut perturbed_arrival_time = arrival_time + delay as Timestamp;
+                delay = (delay + noise.dwell_sd * standard_normal(rng)).max(0.);
+                let mut perturbed_departure_time = (departure_time + delay as Timestamp).max(perturbed_arrival_time);
+
+                if let Some(&(previous_arrival_time, previous_departure_time)) = previous_trip_times.get(stop_order) {
+                    perturbed_arrival_time = perturbed_arrival_time.max(previous_arrival_time);
+                    perturbed_departure_time = perturbed_departure_time.max(previous_departure_time);
+                }
+                trip_times.push((perturbed_arrival_time, perturbed_departure_time));
+
+                let stop_time = &mut network.stop_times[stop_time_idx];
+                stop_time.arrival_time = perturbed_arrival_time;
+                stop_time.departure_time = perturbed_departure_time;
+            }
+            previous_trip_times = trip_times;
+        }
+    }
+    network.build_connections();
+}
+
+// Spread of arrival delays between an origin and destination, over every agent and replication.
+pub struct OdDelay {
+    pub origin: StopIndex,
+    pub destination: StopIndex,
+    // Median, 90th percentile and highest delay (seconds) against the scheduled arrival of the planned journey.
+    pub p50_delay: Timestamp,
+    pub p90_delay: Timestamp,
+    pub max_delay: Timestamp,
+    // Agent-replications that didn't reach the destination after missing a transfer.
+    pub num_unreached: u64,
+}
+
+// (delay, agents) of each journey in each replication, and agents who didn't arrive, keyed by (origin, destination).
+type OdDelays = HashMap<(StopIndex, StopIndex), (Vec<(Timestamp, u64)>, u64)>;
+
+// Trip stop loads, arrival delays and missed transfers across perturbed replications, from run_perturbed_replications.
+pub struct PerturbationSummary {
+    pub num_replications: usize,
+    // Mean, median and 90th percentile load of each trip stop across the replications.
+    pub mean_loads: Vec<f32>,
+    pub p50_loads: Vec<PopulationCount>,
+    pub p90_loads: Vec<PopulationCount>,
+    // Agents who missed a transfer, in each replication.
+    pub missed_transfers: Vec<u64>,
+    // Sorted by (origin, destination).
+    pub od_delays: Vec<OdDelay>,
+}
+
+// Replays planned legs on the current (perturbed) timetable. A transfer is missed if the next trip departs before the
+// previous one arrives (the minimum transfer time isn't allowed for). From a missed transfer on, the agent re-plans
+// from where they are, knowing the delays. Returns the legs taken and whether a transfer was missed.
+fn replay_journey(network: &Network, journey: &AgentJourney, planned_legs: &[JourneyLeg], zero_cost: &[CrowdingCost]) -> (Vec<JourneyLeg>, bool) {
+    for (i, legs) in planned_legs.windows(2).enumerate() {
+        let arrival_time = network.get_arrival_time(legs[0].route_idx, legs[0].trip_idx, legs[0].arrival_stop_order);
+        let departure_time = network.get_departure_time(legs[1].route_idx, legs[1].trip_idx, legs[1].boarded_stop_order);
+        if departure_time < arrival_time {
+            let transfer_stop = network.get_stop_in_route(legs[0].route_idx, legs[0].arrival_stop_order);
+            let mut taken_legs = planned_legs[..=i].to_vec();
+            taken_legs.extend(raptor_query(network, transfer_stop, arrival_time, journey.end_stop, zero_cost).legs.iter().map(|leg| JourneyLeg {
+                route_idx: leg.route_idx as usize,
+                trip_idx: leg.trip_idx as usize,
+                boarded_stop_order: leg.boarded_stop_order as usize,
+                arrival_stop_order: leg.arrival_stop_order as usize,
+            }));
+            return (taken_legs, true);
+        }
+    }
+    (planned_legs.to_vec(), false)
+}
+
+// Estimates the spread of loads, journey time reliability and missed-transfer risk under imperfect schedule adherence.
+// Agents plan their journeys on the scheduled timetable (with the given crowding costs, see query_journey_legs), then
+// each of num_replications randomly perturbed timetables replays those plans, counting agents who miss a transfer and
+// measuring how late each agent arrives. The scheduled timetable is restored afterwards.
+pub fn run_perturbed_replications(network: &mut Network, simulation_steps: &[AgentJourney], trip_stops_cost: Option<&[CrowdingCost]>, noise: &NoiseModel, num_replications: usize, seed: u64) -> PerturbationSummary {
+    let scheduled = network.stop_times.iter().map(|stop_time| (stop_time.arrival_time, stop_time.departure_time)).collect::<Vec<_>>();
+    let planned_legs = query_journey_legs(network, simulation_steps, trip_stops_cost);
+    let scheduled_arrival_times = planned_legs.iter().map(|legs| {
+        legs.last().map(|leg| network.get_arrival_time(leg.route_idx, leg.trip_idx, leg.arrival_stop_order))
+    }).collect::<Vec<_>>();
+    let zero_cost = vec![0 as CrowdingCost; network.stop_times.len()];
+    let mut rng = SmallRng::seed_from_u64(seed);
+
+    let mut replication_loads = Vec::with_capacity(num_replications);
+    let mut missed_transfers = Vec::with_capacity(num_replications);
+    let mut od_delays: OdDelays = HashMap::new();
+    for _ in 0..num_replications {
+        perturb_timetable(network, &scheduled, noise, &mut rng);
+        let network = &*network;
+        let replayed = simulation_steps.par_iter().zip(&planned_legs).map(|(journey, legs)| {
+            let (legs, missed) = replay_journey(network, journey, legs, &zero_cost);
+            (PinnedPath { count: journey.count, legs }, missed)
+        }).collect::<Vec<_>>();
+
+        missed_transfers.push(replayed.iter().filter(|(_, missed)| *missed).map(|(path, _)| path.count as u64).sum());
+        for ((journey, (path, _)), &scheduled_arrival_time) in simulation_steps.iter().zip(&replayed).zip(&scheduled_arrival_times) {
+            // Journeys without a planned path have no delay to measure.
+            let Some(scheduled_arrival_time) = scheduled_arrival_time else {
+                continue;
+            };
+            let (delays, num_unreached) = od_delays.entry((journey.start_stop, journey.end_stop)).or_default();
+            match path.legs.last() {
+                Some(leg) if network.get_stop_in_route(leg.route_idx, leg.arrival_stop_order) == journey.end_stop => {
+                    let arrival_time = network.get_arrival_time(leg.route_idx, leg.trip_idx, leg.arrival_stop_order);
+                    delays.push((arrival_time.saturating_sub(scheduled_arrival_time), journey.count as u64));
+                }
+                _ => *num_unreached += journey.count as u64,
+            }
+        }
+        let paths = replayed.into_iter().map(|(path, _)| path).collect::<Vec<_>>();
+        let mut loads = SimulationResult { agent_journeys: vec![0; network.stop_times.len()], count_issues: Vec::new(), timings: SimulationTimings::default() };
+        load_pinned_paths(network, &mut loads, &paths);
+        replication_loads.push(loads.agent_journeys);
+    }
+
+    for (stop_time, &(arrival_time, departure_time)) in network.stop_times.iter_mut().zip(scheduled.iter()) {
+        stop_time.arrival_time = arrival_time;
+        stop_time.departure_time = departure_time;
+    }
+    network.build_connections();
+
+    let num_trip_stops = network.stop_times.len();
+    let mut mean_loads = Vec::with_capacity(num_trip_stops);
+    let mut p50_loads = Vec::with_capacity(num_trip_stops);
+    let mut p90_loads = Vec::with_capacity(num_trip_stops);
+    let mut trip_stop_loads = Vec::with_capacity(num_replications);
+    for trip_stop_idx in 0..num_trip_stops {
+        trip_stop_loads.clear();
+        trip_stop_loads.extend(replication_loads.iter().map(|loads| loads[trip_stop_idx]));
+        trip_stop_loads.sort_unstable();
+        mean_loads.push(trip_stop_loads.iter().map(|&load| load as f32).sum::<f32>() / num_replications.max(1) as f32);
+        // No replications leave every load at zero.
+        if trip_stop_loads.is_empty() {
+            p50_loads.push(0);
+            p90_loads.push(0);
+        } else {
+            p50_loads.push(percentile(&trip_stop_loads, 50));
+            p90_loads.push(percentile(&trip_stop_loads, 90));
+        }
+    }
+
+    let mut od_delays = od_delays.into_iter().map(|((origin, destination), (mut delays, num_unreached))| {
+        delays.sort_unstable();
+        // Every agent of the OD missing their destination leaves no delays.
+        let (p50_delay, p90_delay, max_delay) = match delays.last() {
+            Some(&(max_delay, _)) => (weighted_percentile(&delays, 50), weighted_percentile(&delays, 90), max_delay),
+            None => (0, 0, 0),
+        };
+        OdDelay { origin, destination, p50_delay, p90_delay, max_delay, num_unreached }
+    }).collect::<Vec<_>>();
+    od_delays.sort_unstable_by_key(|od_delay| (od_delay.origin, od_delay.destination));
+
+    PerturbationSummary { num_replications, mean_loads, p50_loads, p90_loads, missed_transfers, od_delays }
+}
+
+// Exports the load spread of each trip stop, the missed transfers in each replication and the arrival delay spread of
+// each OD to separate csv files.
+pub fn export_perturbation_summary(loads_path: &str, replications_path: &str, delays_path: &str, network: &Network, summary: &PerturbationSummary) -> Result<(), DataExportError> {
+    let mut csv_writer = csv::Writer::from_path(loads_path)?;
+    csv_writer.write_record(["line", "trip_id", "stop", "departure_time", "mean_load", "p50_load", "p90_load"])?;
+    for (route_idx, route) in network.routes.iter().enumerate() {
+        for trip_idx in 0..route.num_trips as usize {
+            for (stop_order, trip_stop_idx) in route.get_trip_range(trip_idx).enumerate() {
+                csv_writer.write_record(&[
+                    route.line.to_string(),
+                    route.trip_ids[trip_idx].to_string(),
+                    network.stops[network.get_stop_in_route(route_idx, stop_order) as usize].name.to_string(),
+                    get_time_str(network.get_departure_time(route_idx, trip_idx, stop_order)),
+                    format!("{:.2}", summary.mean_loads[trip_stop_idx]),
+                    summary.p50_loads[trip_stop_idx].to_string(),
+                    summary.p90_loads[trip_stop_idx].to_string(),
+                ])?;
+            }
+        }
+    }
+
+    let mut csv_writer = csv::Writer::from_path(replications_path)?;
+    csv_writer.write_record(["replication", "missed_transfers"])?;
+    for (replication, missed_transfers) in summary.missed_transfers.iter().enumerate() {
+        csv_writer.write_record(&[replication.to_string(), missed_transfers.to_string()])?;
+    }
+
+    let mut csv_writer = csv::Writer::from_path(delays_path)?;
+    csv_writer.write_record(["origin", "destination", "p50_delay", "p90_delay", "max_delay", "unreached"])?;
+    for od_delay in &summary.od_delays {
+        csv_writer.write_record(&[
+            network.stops[od_delay.origin as usize].name.to_string(),
+            network.stops[od_delay.destination as usize].name.to_string(),
+            od_delay.p50_delay.to_string(),
+            od_delay.p90_delay.to_string(),
+            od_delay.max_delay.to_string(),
+            od_delay.num_unreached.to_string(),
+        ])?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{leg, stop, test_network, trip};
+
+    fn stop_times(network: &Network) -> Vec<(Timestamp, Timestamp)> {
+        network.stop_times.iter().map(|stop_time| (stop_time.arrival_time, stop_time.departure_time)).collect()
+    }
+
+    #[test]
+    fn perturbed_trips_never_run_early_or_overtake() {
+        let (_, mut network) = test_network();
+        let scheduled = stop_times(&network);
+        let noise = NoiseModel { run_time_sd: 1., dwell_sd: 300. };
+        let mut rng = SmallRng::seed_from_u64(0);
+        for _ in 0..50 {
+            perturb_timetable(&mut network, &scheduled, &noise, &mut rng);
+            for (&(arrival_time, departure_time), stop_time) in scheduled.iter().zip(&network.stop_times) {
+                assert!(stop_time.arrival_time >= arrival_time);
+                assert!(stop_time.departure_time >= departure_time);
+                assert!(stop_time.departure_time >= stop_time.arrival_time);
+            }
+            for route_idx in 0..network.num_routes() {
+                for trip_idx in 1..network.num_trips(route_idx) {
+                    for stop_order in 0..network.routes[route_idx].num_stops as usize {
+                        assert!(network.get_arrival_time(route_idx, trip_idx, stop_order) >= network.get_arrival_time(route_idx, trip_idx - 1, stop_order));
+                        assert!(network.get_departure_time(route_idx, trip_idx, stop_order) >= network.get_departure_time(route_idx, trip_idx - 1, stop_order));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn replications_restore_the_timetable() {
+        let (_, mut network) = test_network();
+        let scheduled = stop_times(&network);
+        let steps = [AgentJourney { start_time: 7 * 3600 + 55 * 60, start_stop: stop(&network, "West"), end_stop: stop(&network, "South"), count: 10 }];
+        let noise = NoiseModel { run_time_sd: 0.5, dwell_sd: 120. };
+        let summary = run_perturbed_replications(&mut network, &steps, None, &noise, 5, 0);
+        assert_eq!(stop_times(&network), scheduled);
+        assert_eq!(summary.num_replications, 5);
+        assert_eq!(summary.missed_transfers.len(), 5);
+        assert_eq!(summary.mean_loads.len(), scheduled.len());
+    }
+
+    #[test]
+    fn replay_detects_missed_transfers() {
+        let (_, mut network) = test_network();
+        let journey = AgentJourney { start_time: 7 * 3600 + 55 * 60, start_stop: stop(&network, "West"), end_stop: stop(&network, "South"), count: 1 };
+        let planned_legs = [leg(&network, "A1", "West", "Central"), leg(&network, "B1", "Central", "South")];
+        let zero_cost = vec![0 as CrowdingCost; network.stop_times.len()];
+
+        // On schedule, A1 reaches Central at 08:05 and B1 leaves at 08:18.
+        let (taken_legs, missed) = replay_journey(&network, &journey, &planned_legs, &zero_cost);
+        assert!(!missed);
+        assert_eq!(taken_legs.len(), 2);
+
+        // Delaying A1 to reach Central at 08:19 misses B1, so the agent keeps the first leg and re-plans from Central.
+        let (route_idx, trip_idx) = trip(&network, "A1");
+        let central_idx = network.routes[route_idx].get_trip_range(trip_idx).start + planned_legs[0].arrival_stop_order;
+        network.stop_times[central_idx].arrival_time = 8 * 3600 + 19 * 60;
+        network.stop_times[central_idx].departure_time = 8 * 3600 + 19 * 60;
+        network.build_connections();
+        let (taken_legs, missed) = replay_journey(&network, &journey, &planned_legs, &zero_cost);
+        assert!(missed);
+        assert_eq!((taken_legs[0].route_idx, taken_legs[0].trip_idx), (route_idx, trip_idx));
+        assert_eq!(taken_legs[0].arrival_stop_order, planned_legs[0].arrival_stop_order);
+        // No later trip of line B reaches South, so the re-planned part of the journey doesn't take B1.
+        assert!(taken_legs[1..].iter().all(|leg| (leg.route_idx, leg.trip_idx) != trip(&network, "B1")));
+    }
+}
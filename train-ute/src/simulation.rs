@@ -1,13 +1,18 @@
+use std::collections::{HashMap, HashSet};
 use std::io::Write;
+use std::sync::Mutex;
 use std::sync::atomic::{AtomicI32, Ordering};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use rand::prelude::*;
 use rayon::prelude::*;
+use serde::Deserialize;
+use thiserror::Error;
 use tqdm::Iter;
 
 use raptor::{Network, raptor_query};
 use raptor::network::{PathfindingCost, StopIndex, Timestamp};
+use raptor::utils::get_time_str;
 
 pub type AgentCount = u16;
 pub type PopulationCount = i32;
@@ -16,9 +21,28 @@ pub type CrowdingCost = PathfindingCost;
 
 pub trait SimulationParams {
     fn max_train_capacity(&self) -> AgentCount;
-    fn cost_fn(&self, count: PopulationCount) -> CrowdingCost;
+    // Capacity of a particular trip, which is the max train capacity unless overridden.
+    fn trip_capacity(&self, _route_idx: usize, _trip_idx: usize) -> AgentCount {
+        self.max_train_capacity()
+    }
+    fn cost_fn(&self, count: PopulationCount, capacity: AgentCount) -> CrowdingCost;
+    // Crowding cost on a particular route, which is cost_fn unless the route has its own crowding model.
+    fn route_cost_fn(&self, _route_idx: usize, count: PopulationCount, capacity: AgentCount) -> CrowdingCost {
+        self.cost_fn(count, capacity)
+    }
+    // Multiplier on the crowding cost of the segment after a stop in a route, e.g. its length for costs per
+    // passenger-km. Costs are per segment by default.
+    fn segment_cost_weight(&self, _route_idx: usize, _stop_order: usize) -> CrowdingCost {
+        1.
+    }
+    // Whether runs must give identical results on any number of threads, for regression testing and audits. This
+    // orders the queries and count issues, at some cost in speed. See compare_thread_counts.
+    fn deterministic(&self) -> bool {
+        false
+    }
 }
 
+#[derive(Clone, Copy)]
 pub struct AgentJourney {
     pub start_time: Timestamp,
     pub start_stop: StopIndex,
@@ -28,6 +52,171 @@ pub struct AgentJourney {
 
 pub struct SimulationResult {
     pub agent_journeys: Vec<PopulationCount>,
+    // Trip stops whose counts had to be corrected. Empty for a clean run.
+    pub count_issues: Vec<CountIssue>,
+    pub timings: SimulationTimings,
+}
+
+// Wall-clock time of each phase of a simulation run.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SimulationTimings {
+    // Journey planning and loading agents onto trips.
+    pub query: Duration,
+    // Summing agent counts along trips and calculating crowding costs.
+    pub prefix_sum: Duration,
+}
+
+// A count that was out of range, so a long run with bad data still finishes and reports where it went wrong rather than
+// panicking. Trip stops are indices into network.stop_times.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CountIssue {
+    // More agents than PopulationCount can hold, saturated to PopulationCount::MAX.
+    Overflow { trip_stop_idx: usize },
+    // A negative count (e.g. from legs that alight before they board), clamped to zero once the trip is summed.
+    Negative { trip_stop_idx: usize, count: PopulationCount },
+    // A trip whose prefix-sum differences or sums wrapped around. A wrapped difference can sum back into range and give a
+    // plausible but wrong load, so none of the trip's counts can be trusted. The trip is costed as overfull.
+    InvalidTrip { route_idx: usize, trip_idx: usize },
+}
+
+// One leg of a journey: riding a trip from one stop order to a later one.
+#[derive(Clone, Copy)]
+pub struct JourneyLeg {
+    pub route_idx: usize,
+    pub trip_idx: usize,
+    pub boarded_stop_order: usize,
+    pub arrival_stop_order: usize,
+}
+
+// Re-queries the journey of every simulation step, for exports and analyses that need the legs agents took. With no
+// crowding costs, journeys are routed as in a single simulation pass. Given the costs of a result (see crowding_costs),
+// they are routed as a pass after it would.
+pub fn query_journey_legs(network: &Network, simulation_steps: &[AgentJourney], trip_stops_cost: Option<&[CrowdingCost]>) -> Vec<Vec<JourneyLeg>> {
+    let zero_cost;
+    let trip_stops_cost = match trip_stops_cost {
+        Some(trip_stops_cost) => trip_stops_cost,
+        None => {
+            zero_cost = vec![0 as CrowdingCost; network.stop_times.len()];
+            &zero_cost
+        }
+    };
+    simulation_steps.par_iter().map(|journey| {
+        raptor_query(network, journey.start_stop, journey.start_time, journey.end_stop, trip_stops_cost).legs.iter().map(|leg| JourneyLeg {
+            route_idx: leg.route_idx as usize,
+            trip_idx: leg.trip_idx as usize,
+            boarded_stop_order: leg.boarded_stop_order as usize,
+            arrival_stop_order: leg.arrival_stop_order as usize,
+        }).collect()
+    }).collect()
+}
+
+// Agents with a prescribed path (e.g. observed special-event flows), which are loaded onto the network but never routed.
+pub struct PinnedPath {
+    pub count: AgentCount,
+    pub legs: Vec<JourneyLeg>,
+}
+
+// Adds agents on pinned paths to the counts of a simulation result, underneath the modelled demand.
+// Crowding costs computed from the result afterwards (crowding_costs) include them.
+pub fn load_pinned_paths(network: &Network, simulation_result: &mut SimulationResult, pinned_paths: &[PinnedPath]) {
+    for path in pinned_paths {
+        for leg in path.legs.iter() {
+            let route = &network.routes[leg.route_idx];
+            let trip_range = route.get_trip_range(leg.trip_idx);
+            let trip = &mut simulation_result.agent_journeys[trip_range.clone()];
+            let trip_range = (trip_range.start + leg.boarded_stop_order)..(trip_range.start + leg.arrival_stop_order);
+            for (trip_stop_idx, count) in trip_range.zip(trip[leg.boarded_stop_order..leg.arrival_stop_order].iter_mut()) {
+                *count = count.checked_add(path.count as PopulationCount).unwrap_or_else(|| {
+                    simulation_result.count_issues.push(CountIssue::Overflow { trip_stop_idx });
+                    PopulationCount::MAX
+                });
+            }
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("sample fraction {0} is not in (0, 1]")]
+pub struct InvalidSampleFraction(pub f64);
+
+// A sample fraction must keep some agents and can't keep more than all of them.
+pub fn validate_sample_fraction(fraction: f64) -> Result<(), InvalidSampleFraction> {
+    if fraction > 0. && fraction <= 1. {
+        Ok(())
+    } else {
+        Err(InvalidSampleFraction(fraction))
+    }
+}
+
+// Keeps each agent with probability fraction (seeded), for quick runs on a sample of a large demand matrix.
+// Results of a sampled run should be scaled back up with expand_simulation_result, and the steps with
+// expand_simulation_steps before anything re-queries them.
+pub fn sample_simulation_steps(simulation_steps: &[AgentJourney], fraction: f64, seed: u64) -> Result<Vec<AgentJourney>, InvalidSampleFraction> {
+    validate_sample_fraction(fraction)?;
+    let mut rng = SmallRng::seed_from_u64(seed);
+    Ok(simulation_steps.iter().filter_map(|journey| {
+        let count = (0..journey.count).filter(|_| rng.gen_bool(fraction)).count() as AgentCount;
+        (count > 0).then_some(AgentJourney { count, ..*journey })
+    }).collect())
+}
+
+// Scales the counts of a run on sampled demand by 1 / fraction, so exports are in terms of the full demand.
+pub fn expand_simulation_result(simulation_result: &mut SimulationResult, fraction: f64) -> Result<(), InvalidSampleFraction> {
+    validate_sample_fraction(fraction)?;
+    let expansion_factor = 1. / fraction;
+    for count in simulation_result.agent_journeys.iter_mut() {
+        // Float to int casts saturate.
+        *count = (*count as f64 * expansion_factor).round() as PopulationCount;
+    }
+    Ok(())
+}
+
+// Scales the counts of sampled steps by 1 / fraction, so exports that re-query the steps (journeys, boardings,
+// transfers and so on) are in terms of the full demand. Steps too large for an AgentCount are split into consecutive
+// steps, so step indices after a split step move along.
+pub fn expand_simulation_steps(simulation_steps: &[AgentJourney], fraction: f64) -> Result<Vec<AgentJourney>, InvalidSampleFraction> {
+    validate_sample_fraction(fraction)?;
+    let expansion_factor = 1. / fraction;
+    let mut expanded_steps = Vec::with_capacity(simulation_steps.len());
+    for journey in simulation_steps {
+        let mut count = (journey.count as f64 * expansion_factor).round() as u64;
+        while count > 0 {
+            let part = count.min(AgentCount::MAX as u64) as AgentCount;
+            expanded_steps.push(AgentJourney { count: part, ..*journey });
+            count -= part as u64;
+        }
+    }
+    Ok(expanded_steps)
+}
+
+// Restricts demand to a departure-time window and/or to ODs served by selected lines, for focused studies (e.g. the
+// AM peak on one corridor) that run in a fraction of the time.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DemandFilter {
+    // Inclusive start, exclusive end. In the run config, a pair of times such as ["06:00", "10:00"].
+    #[serde(deserialize_with = "crate::config::time_window")]
+    pub departure_window: Option<(Timestamp, Timestamp)>,
+    // Keeps journeys whose origin and destination are both stopped at by one of these lines.
+    pub lines: Option<Vec<String>>,
+}
+
+pub fn filter_simulation_steps(network: &Network, simulation_steps: &[AgentJourney], filter: &DemandFilter) -> Vec<AgentJourney> {
+    let line_stops = filter.lines.as_ref().map(|lines| {
+        let mut line_stops = vec![false; network.num_stops()];
+        for route in network.routes.iter().filter(|route| lines.iter().any(|line| *line == *route.line)) {
+            for &stop_idx in route.get_stops(&network.route_stops) {
+                line_stops[stop_idx as usize] = true;
+            }
+        }
+        line_stops
+    });
+
+    simulation_steps.iter().filter(|journey| {
+        let in_window = filter.departure_window.is_none_or(|(start, end)| journey.start_time >= start && journey.start_time < end);
+        let on_lines = line_stops.as_ref().is_none_or(|line_stops| line_stops[journey.start_stop as usize] && line_stops[journey.end_stop as usize]);
+        in_window && on_lines
+    }).copied().collect()
 }
 
 pub fn gen_simulation_steps(network: &Network, number: Option<usize>, seed: Option<u64>) -> Vec<AgentJourney> {
@@ -56,33 +245,175 @@ pub fn gen_simulation_steps(network: &Network, number: Option<usize>, seed: Opti
     simulation_steps
 }
 
+// How agents in a departure window are spread across it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DepartureProfile {
+    #[default]
+    Uniform,
+    // Relative weights of equal-length sub-intervals of the window, e.g. [1., 3., 1.] puts most agents in the middle third.
+    Weighted(Vec<f64>),
+}
+
+impl DepartureProfile {
+    // Position in the window (0 to 1) below which the given fraction of agents depart.
+    fn quantile(&self, fraction: f64) -> f64 {
+        let weights = match self {
+            DepartureProfile::Uniform => return fraction,
+            DepartureProfile::Weighted(weights) => weights,
+        };
+        let total = weights.iter().sum::<f64>();
+        if weights.is_empty() || total <= 0. {
+            return fraction;
+        }
+
+        // Invert the piecewise linear cumulative distribution.
+        let target = fraction * total;
+        let mut cumulative = 0.;
+        for (i, &weight) in weights.iter().enumerate() {
+            if weight > 0. && cumulative + weight >= target {
+                return (i as f64 + (target - cumulative) / weight) / weights.len() as f64;
+            }
+            cumulative += weight;
+        }
+        1.
+    }
+}
+
+// Spreads an OD count departing between window_start and window_end (exclusive) into one step per agent,
+// with departure times following the profile. Consolidate the result to merge agents that share a departure time.
+pub fn spread_departure_window(start_stop: StopIndex, end_stop: StopIndex, window_start: Timestamp, window_end: Timestamp, count: AgentCount, profile: &DepartureProfile) -> Vec<AgentJourney> {
+    let window_length = window_end.saturating_sub(window_start) as f64;
+    (0..count).map(|i| {
+        // Place each agent in the middle of its share of the distribution.
+        let fraction = (i as f64 + 0.5) / count as f64;
+        let start_time = window_start + (profile.quantile(fraction) * window_length) as Timestamp;
+        AgentJourney { start_time, start_stop, end_stop, count: 1 }
+    }).collect()
+}
+
+// Merges steps with the same origin, departure time and destination, keeping each step's departure time, so
+// demand sources that emit an agent per step give fewer, larger steps. Sorted by departure time, then origin and
+// destination.
+// Grouping steps that share an origin and departure time into single multi-destination queries is deferred, as
+// raptor_query only takes a single destination.
+pub fn consolidate_simulation_steps(simulation_steps: &[AgentJourney]) -> Vec<AgentJourney> {
+    let mut consolidated_steps = Vec::new();
+    let mut step_counts = HashMap::new();
+    for journey in simulation_steps {
+        let start_time = journey.start_time;
+        let key = (journey.start_stop, start_time, journey.end_stop);
+        let count = step_counts.entry(key).or_insert(0 as AgentCount);
+        match count.checked_add(journey.count) {
+            Some(new_count) => *count = new_count,
+            None => {
+                // Too many agents for one step, so emit the full step and start a new one.
+                consolidated_steps.push(AgentJourney { start_time, start_stop: journey.start_stop, end_stop: journey.end_stop, count: *count });
+                *count = journey.count;
+            }
+        }
+    }
+
+    consolidated_steps.extend(step_counts.into_iter().map(|((start_stop, start_time, end_stop), count)| AgentJourney { start_time, start_stop, end_stop, count }));
+    // Hash map order differs between runs, so sort on the whole step to keep the order (and journey ids in exports)
+    // the same for the same inputs.
+    consolidated_steps.sort_unstable_by_key(|journey| (journey.start_time, journey.start_stop, journey.end_stop, journey.count));
+    consolidated_steps
+}
+
+// Buffers used by a simulation run, kept between runs so big networks don't reallocate them every time.
+#[derive(Default)]
+pub struct SimulationScratch {
+    trip_stops_pop: Vec<PopulationCountAtomic>,
+    trip_stops_cost: Vec<CrowdingCost>,
+    unique_query_counts: HashMap<(StopIndex, Timestamp, StopIndex), PopulationCount>,
+    unique_queries: Vec<((StopIndex, Timestamp, StopIndex), PopulationCount)>,
+}
+
+impl SimulationScratch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Clears the buffers for a new run, resizing them to fit the network.
+    fn reset(&mut self, network: &Network) {
+        let num_trip_stops = network.stop_times.len();
+
+        // Initialise agent counts to zero.
+        self.trip_stops_pop.truncate(num_trip_stops);
+        for pop in self.trip_stops_pop.iter() {
+            pop.store(0, Ordering::Relaxed);
+        }
+        self.trip_stops_pop.resize_with(num_trip_stops, PopulationCountAtomic::default);
+
+        self.trip_stops_cost.clear();
+        self.trip_stops_cost.resize(num_trip_stops, 0 as CrowdingCost);
+
+        self.unique_query_counts.clear();
+        self.unique_queries.clear();
+    }
+}
+
+// As run_simulation_with_scratch, but on a thread pool of its own with num_threads threads, so a run can be limited
+// on a shared machine without changing the global pool.
+pub fn run_simulation_with_threads<T: SimulationParams + Sync, const P: bool>(network: &Network, simulation_steps: &[AgentJourney], params: &T, scratch: &mut SimulationScratch, num_threads: usize) -> Result<SimulationResult, rayon::ThreadPoolBuildError> {
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(num_threads).build()?;
+    Ok(pool.install(|| run_simulation_with_scratch::<T, P>(network, simulation_steps, params, scratch)))
+}
+
 // Const generic parameter P switched between normal (false) and prefix-sum (true) simulation.
-pub fn run_simulation<T: SimulationParams, const P: bool>(network: &Network, simulation_steps: &[AgentJourney], params: &T) -> SimulationResult {
+// Buffers are reused from previous runs with the same scratch.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(num_steps = simulation_steps.len())))]
+pub fn run_simulation_with_scratch<T: SimulationParams, const P: bool>(network: &Network, simulation_steps: &[AgentJourney], params: &T, scratch: &mut SimulationScratch) -> SimulationResult {
+    let query_start = Instant::now();
     // Agent counts need to be stored per trip stop, and signed so they can be temporarily negative.
+    // To allow parallelism, we use an atomic type.
+    scratch.reset(network);
+    let trip_stops_pop = &scratch.trip_stops_pop;
 
-    // Initialise agent counts to zero. To allow parallelism, we use an atomic type.
-    let mut trip_stops_pop = Vec::new();
-    trip_stops_pop.resize_with(network.stop_times.len(), PopulationCountAtomic::default);
+    // Identical queries give identical journeys, so each unique (origin, departure time, destination) is only queried once
+    // with the combined agent count.
+    for journey in simulation_steps {
+        let count = scratch.unique_query_counts.entry((journey.start_stop, journey.start_time, journey.end_stop)).or_insert(0);
+        *count = count.saturating_add(journey.count as PopulationCount);
+    }
+    let unique_queries = &mut scratch.unique_queries;
+    unique_queries.extend(scratch.unique_query_counts.drain());
+    if params.deterministic() {
+        unique_queries.sort_unstable_by_key(|&(query, _)| query);
+    }
+
+    // (route, trip, trip stop) of each atomic count that wrapped around. This only happens with bad data, so a lock is fine.
+    let wrapped_trip_stops = Mutex::new(Vec::new());
 
-    let mut trip_stops_cost = vec![0 as CrowdingCost; network.stop_times.len()];
     // TODO: test just using map instead of atomics?
-    simulation_steps.par_iter().for_each(|journey| {
-        let query = raptor_query(network, journey.start_stop, journey.start_time, journey.end_stop, &trip_stops_cost);
+    unique_queries.par_iter().for_each(|&((start_stop, start_time, end_stop), count)| {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("raptor_query").entered();
+        let query = raptor_query(network, start_stop, start_time, end_stop, &scratch.trip_stops_cost);
         for leg in query.legs {
-            let route = &network.routes[leg.route_idx as usize];
-            let trip = &trip_stops_pop[route.get_trip_range(leg.trip_idx as usize)];
-            let count = journey.count as PopulationCount;
+            let route_idx = leg.route_idx as usize;
+            let trip_idx = leg.trip_idx as usize;
+            let trip_range = network.routes[route_idx].get_trip_range(trip_idx);
+            let trip_start = trip_range.start;
+            let trip = &trip_stops_pop[trip_range];
             let boarded_stop_order = leg.boarded_stop_order as usize;
             let arrival_stop_order = leg.arrival_stop_order as usize;
+            // The atomics return the previous count, so a wrap is detected by redoing the operation checked.
+            let check_wrap = |stop_order: usize, result: Option<PopulationCount>| {
+                if result.is_none() {
+                    wrapped_trip_stops.lock().unwrap().push((route_idx, trip_idx, trip_start + stop_order));
+                }
+            };
             if P {
                 // Add one agent to this span of trip stops.
-                trip[boarded_stop_order].fetch_add(count, Ordering::SeqCst);
+                check_wrap(boarded_stop_order, trip[boarded_stop_order].fetch_add(count, Ordering::SeqCst).checked_add(count));
                 // Remove agent at stop (for inclusive-exclusive range).
-                trip[arrival_stop_order].fetch_sub(count, Ordering::SeqCst);
+                check_wrap(arrival_stop_order, trip[arrival_stop_order].fetch_sub(count, Ordering::SeqCst).checked_sub(count));
             } else {
                 // Iterate over all stops in the trip, adding the agent count.
                 for i in boarded_stop_order..arrival_stop_order {
-                    trip[i].fetch_add(count, Ordering::SeqCst);
+                    check_wrap(i, trip[i].fetch_add(count, Ordering::SeqCst).checked_add(count));
                 }
             }
         }
@@ -91,28 +422,189 @@ pub fn run_simulation<T: SimulationParams, const P: bool>(network: &Network, sim
     // Copy counts from Vec<PopulationCountAtomic> to Vec<PopulationCount>.
     let mut trip_stops_pop = trip_stops_pop.iter().map(|x| x.load(Ordering::SeqCst)).collect::<Vec<PopulationCount>>();
 
+    let mut count_issues = Vec::new();
+    // Trips with a wrapped prefix-sum difference.
+    let mut invalid_trips = HashSet::new();
+    let mut wrapped_trip_stops = wrapped_trip_stops.into_inner().unwrap();
+    if params.deterministic() {
+        wrapped_trip_stops.sort_unstable();
+    }
+    for (route_idx, trip_idx, trip_stop_idx) in wrapped_trip_stops {
+        if P {
+            invalid_trips.insert((route_idx, trip_idx));
+        } else {
+            count_issues.push(CountIssue::Overflow { trip_stop_idx });
+            trip_stops_pop[trip_stop_idx] = PopulationCount::MAX;
+        }
+    }
+
+    let query_duration = query_start.elapsed();
+
     // Build sums of agent counts, and calculate crowding cost.
+    let prefix_sum_start = Instant::now();
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("prefix_sum").entered();
+    let trip_stops_cost = &mut scratch.trip_stops_cost;
     // Note: this ends up running through the trip_pop in order, so it's cache-friendly.
     for route_idx in 0..network.routes.len() {
         let route = &network.routes[route_idx];
-        for trip in 0..route.num_trips as usize {
-            let trip_range = route.get_trip_range(trip);
+        for trip_idx in 0..route.num_trips as usize {
+            let capacity = params.trip_capacity(route_idx, trip_idx);
+            let trip_range = route.get_trip_range(trip_idx);
+            let trip_start = trip_range.start;
             let trip = &mut trip_stops_pop[trip_range.clone()];
             let costs = &mut trip_stops_cost[trip_range];
 
-            costs[0] = params.cost_fn(trip[0]);
-            for i in 0..(trip.len() - 1) {
-                if P {
-                    trip[i + 1] += trip[i];
+            let mut trip_valid = !invalid_trips.contains(&(route_idx, trip_idx));
+            if P {
+                let mut previous: PopulationCount = 0;
+                for count in trip.iter_mut() {
+                    *count = count.checked_add(previous).unwrap_or_else(|| {
+                        trip_valid = false;
+                        count.wrapping_add(previous)
+                    });
+                    previous = *count;
+                }
+            }
+            if !trip_valid {
+                count_issues.push(CountIssue::InvalidTrip { route_idx, trip_idx });
+            }
+
+            // The trip is fully summed, so clamping a negative count here doesn't change the later stops.
+            for (i, (count, cost)) in trip.iter_mut().zip(costs.iter_mut()).enumerate() {
+                if *count < 0 {
+                    count_issues.push(CountIssue::Negative { trip_stop_idx: trip_start + i, count: *count });
+                    *count = 0;
                 }
-                costs[i + 1] = params.cost_fn(trip[i + 1]);
-                assert!(trip[i] >= 0);
+                let costed_count = if trip_valid { *count } else { PopulationCount::MAX };
+                *cost = params.route_cost_fn(route_idx, costed_count, capacity) * params.segment_cost_weight(route_idx, i);
             }
         }
     }
 
     SimulationResult {
         agent_journeys: trip_stops_pop,
+        count_issues,
+        timings: SimulationTimings { query: query_duration, prefix_sum: prefix_sum_start.elapsed() },
+    }
+}
+
+// Runs the simulation on one thread and on num_threads threads, returning whether the counts, count issues and
+// crowding costs are identical. Crowding costs are compared bit for bit.
+pub fn compare_thread_counts<T: SimulationParams + Sync, const P: bool>(network: &Network, simulation_steps: &[AgentJourney], params: &T, num_threads: usize) -> Result<bool, rayon::ThreadPoolBuildError> {
+    let mut scratch = SimulationScratch::new();
+    let single = run_simulation_with_threads::<T, P>(network, simulation_steps, params, &mut scratch, 1)?;
+    let multi = run_simulation_with_threads::<T, P>(network, simulation_steps, params, &mut scratch, num_threads)?;
+    let cost_bits = |simulation_result: &SimulationResult| crowding_costs(network, simulation_result, params).iter().map(|cost| cost.to_bits()).collect::<Vec<_>>();
+    Ok(single.agent_journeys == multi.agent_journeys
+        && single.count_issues == multi.count_issues
+        && cost_bits(&single) == cost_bits(&multi))
+}
+
+// Approximate resources a simulation run will need, from estimate_run.
+#[derive(Debug)]
+pub struct RunEstimate {
+    pub num_unique_queries: usize,
+    pub memory_bytes: usize,
+    pub duration: Duration,
+}
+
+// Estimates the memory and time a simulation run will take before committing to it. Memory comes from the buffer
+// sizes (trip stops and unique queries); time comes from running a calibration sample of at most sample_size steps
+// and scaling the per-query time up to all the unique queries.
+pub fn estimate_run<T: SimulationParams>(network: &Network, simulation_steps: &[AgentJourney], params: &T, sample_size: usize) -> RunEstimate {
+    let unique_queries = simulation_steps.iter()
+        .map(|journey| (journey.start_stop, journey.start_time, journey.end_stop))
+        .collect::<HashSet<_>>();
+    let num_unique_queries = unique_queries.len();
+
+    // Atomic and final counts and costs per trip stop, plus the unique query map and list (a hash map is roughly
+    // twice the size of its entries).
+    let num_trip_stops = network.stop_times.len();
+    let trip_stop_bytes = num_trip_stops * (std::mem::size_of::<PopulationCountAtomic>() + std::mem::size_of::<PopulationCount>() + std::mem::size_of::<CrowdingCost>());
+    let query_bytes = num_unique_queries * 3 * std::mem::size_of::<((StopIndex, Timestamp, StopIndex), PopulationCount)>();
+    let memory_bytes = trip_stop_bytes + query_bytes;
+
+    // Time the fixed cost of a run (resetting buffers and the prefix pass) separately from the queries.
+    let mut scratch = SimulationScratch::new();
+    let fixed_start = Instant::now();
+    run_simulation_with_scratch::<_, true>(network, &[], params, &mut scratch);
+    let fixed_duration = fixed_start.elapsed();
+
+    // Keep each step with the same probability, so the sample is about sample_size steps.
+    let mut rng = SmallRng::seed_from_u64(0);
+    let keep_probability = (sample_size as f64 / simulation_steps.len().max(1) as f64).min(1.);
+    let sample = simulation_steps.iter()
+        .filter(|_| rng.gen_bool(keep_probability))
+        .copied()
+        .collect::<Vec<_>>();
+    let num_sample_queries = sample.iter()
+        .map(|journey| (journey.start_stop, journey.start_time, journey.end_stop))
+        .collect::<HashSet<_>>()
+        .len();
+    let sample_start = Instant::now();
+    run_simulation_with_scratch::<_, true>(network, &sample, params, &mut scratch);
+    let query_duration = sample_start.elapsed().saturating_sub(fixed_duration);
+
+    let duration = if num_sample_queries == 0 {
+        fixed_duration
+    } else {
+        fixed_duration + query_duration.mul_f64(num_unique_queries as f64 / num_sample_queries as f64)
+    };
+
+    RunEstimate { num_unique_queries, memory_bytes, duration }
+}
+
+// Calculates the crowding cost of each trip stop from the agent counts in a simulation result.
+pub fn crowding_costs<T: SimulationParams>(network: &Network, simulation_result: &SimulationResult, params: &T) -> Vec<CrowdingCost> {
+    let mut trip_stops_cost = vec![0 as CrowdingCost; simulation_result.agent_journeys.len()];
+    for (route_idx, route) in network.routes.iter().enumerate() {
+        for trip in 0..route.num_trips as usize {
+            let capacity = params.trip_capacity(route_idx, trip);
+            let trip_range = route.get_trip_range(trip);
+            for (stop_order, (cost, &count)) in trip_stops_cost[trip_range.clone()].iter_mut().zip(&simulation_result.agent_journeys[trip_range]).enumerate() {
+                *cost = params.route_cost_fn(route_idx, count, capacity) * params.segment_cost_weight(route_idx, stop_order);
+            }
+        }
+    }
+    trip_stops_cost
+}
+
+// Runs a single journey query and prints the legs, for sanity-checking odd assignment results.
+// If a simulation result is given, the query uses the crowding costs from that result.
+pub fn debug_journey<T: SimulationParams>(network: &Network, start_stop: StopIndex, start_time: Timestamp, end_stop: StopIndex, simulation_result: Option<&SimulationResult>, params: &T) {
+    let trip_stops_cost = match simulation_result {
+        Some(simulation_result) => crowding_costs(network, simulation_result, params),
+        None => vec![0 as CrowdingCost; network.stop_times.len()],
+    };
+
+    let query = raptor_query(network, start_stop, start_time, end_stop, &trip_stops_cost);
+
+    println!("Journey from {} to {} at {}:", network.stops[start_stop as usize].name, network.stops[end_stop as usize].name, get_time_str(start_time));
+    if query.legs.is_empty() {
+        println!("  No journey found.");
+        return;
+    }
+    for leg in query.legs.iter() {
+        let route_idx = leg.route_idx as usize;
+        let trip_idx = leg.trip_idx as usize;
+        let boarded_stop_order = leg.boarded_stop_order as usize;
+        let arrival_stop_order = leg.arrival_stop_order as usize;
+
+        let route = &network.routes[route_idx];
+        let boarded_stop_idx = network.get_stop_in_route(route_idx, boarded_stop_order) as usize;
+        let arrival_stop_idx = network.get_stop_in_route(route_idx, arrival_stop_order) as usize;
+        let departure_time = network.get_departure_time(route_idx, trip_idx, boarded_stop_order);
+        let arrival_time = network.get_arrival_time(route_idx, trip_idx, arrival_stop_order);
+
+        // Crowding cost over the span of trip stops this leg covers.
+        let trip_range = route.get_trip_range(trip_idx);
+        let leg_cost: CrowdingCost = trip_stops_cost[trip_range][boarded_stop_order..arrival_stop_order].iter().sum();
+
+        println!("  {} ({}): {} {} -> {} {} (crowding cost {leg_cost:.3})",
+                 route.line, route.trip_ids[trip_idx],
+                 network.stops[boarded_stop_idx].name, get_time_str(departure_time),
+                 network.stops[arrival_stop_idx].name, get_time_str(arrival_time));
     }
 }
 
@@ -126,19 +618,21 @@ pub fn simulation_prefix_benchmark<T: SimulationParams>(network: &Network, param
         let num_steps = 1 << i;
         let simulation_steps = gen_simulation_steps(&network, Some(num_steps), Some(0));
 
+        let mut scratch = SimulationScratch::new();
+
         let simulation_start = Instant::now();
-        let mut simulation_result_1 = SimulationResult { agent_journeys: Vec::new() };
+        let mut simulation_result_1 = SimulationResult { agent_journeys: Vec::new(), count_issues: Vec::new(), timings: SimulationTimings::default() };
         for _ in (0..5).tqdm() {
-            simulation_result_1 = run_simulation::<_, true>(&network, &simulation_steps, params);
+            simulation_result_1 = run_simulation_with_scratch::<_, true>(network, &simulation_steps, params, &mut scratch);
         }
         //let simulation_result_1 = run_simulation::<_, true>(&network, &simulation_steps, params);
         let simulation_duration_1 = simulation_start.elapsed() / 10;
         //println!("Simulation duration with prefix sum: {:?} to run {} steps", simulation_duration_1, simulation_steps.len());
 
         let simulation_start = Instant::now();
-        let mut simulation_result_2 = SimulationResult { agent_journeys: Vec::new() };
+        let mut simulation_result_2 = SimulationResult { agent_journeys: Vec::new(), count_issues: Vec::new(), timings: SimulationTimings::default() };
         for _ in (0..5).tqdm() {
-            simulation_result_2 = run_simulation::<_, false>(&network, &simulation_steps, params);
+            simulation_result_2 = run_simulation_with_scratch::<_, false>(network, &simulation_steps, params, &mut scratch);
         }
         let simulation_duration_2 = simulation_start.elapsed() / 10;
         //println!("Simulation duration without prefix sum: {:?} to run {} steps", simulation_duration_2, simulation_steps.len());
@@ -153,4 +647,127 @@ pub fn simulation_prefix_benchmark<T: SimulationParams>(network: &Network, param
     std::fs::write(file, output)?;
     
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{stop, test_network, trip};
+
+    #[test]
+    fn uniform_quantile_is_fraction() {
+        assert_eq!(DepartureProfile::Uniform.quantile(0.25), 0.25);
+        assert_eq!(DepartureProfile::Weighted(vec![1., 1.]).quantile(0.25), 0.25);
+        // Profiles with no weight fall back to uniform.
+        assert_eq!(DepartureProfile::Weighted(Vec::new()).quantile(0.25), 0.25);
+        assert_eq!(DepartureProfile::Weighted(vec![0., 0.]).quantile(0.25), 0.25);
+    }
+
+    #[test]
+    fn weighted_quantile_inverts_distribution() {
+        let profile = DepartureProfile::Weighted(vec![3., 1.]);
+        assert!((profile.quantile(0.5) - 1. / 3.).abs() < 1e-9);
+        assert!((profile.quantile(0.875) - 0.75).abs() < 1e-9);
+        assert_eq!(profile.quantile(1.), 1.);
+        // No one departs in intervals with zero weight.
+        assert_eq!(DepartureProfile::Weighted(vec![0., 1.]).quantile(0.5), 0.75);
+    }
+
+    fn step_tuples(steps: &[AgentJourney]) -> Vec<(Timestamp, StopIndex, StopIndex, AgentCount)> {
+        steps.iter().map(|step| (step.start_time, step.start_stop, step.end_stop, step.count)).collect()
+    }
+
+    #[test]
+    fn consolidate_merges_identical_steps() {
+        let steps = [
+            AgentJourney { start_time: 3650, start_stop: 1, end_stop: 2, count: 3 },
+            AgentJourney { start_time: 3610, start_stop: 1, end_stop: 2, count: 2 },
+            AgentJourney { start_time: 3600, start_stop: 1, end_stop: 3, count: 1 },
+            AgentJourney { start_time: 3650, start_stop: 1, end_stop: 2, count: 4 },
+            AgentJourney { start_time: 3600, start_stop: 0, end_stop: 2, count: 1 },
+        ];
+        // Departure times are kept, so only steps departing at the same second merge.
+        assert_eq!(step_tuples(&consolidate_simulation_steps(&steps)), vec![
+            (3600, 0, 2, 1),
+            (3600, 1, 3, 1),
+            (3610, 1, 2, 2),
+            (3650, 1, 2, 7),
+        ]);
+    }
+
+    #[test]
+    fn consolidate_splits_steps_that_overflow() {
+        let steps = [
+            AgentJourney { start_time: 0, start_stop: 0, end_stop: 1, count: AgentCount::MAX },
+            AgentJourney { start_time: 0, start_stop: 0, end_stop: 1, count: 2 },
+        ];
+        assert_eq!(step_tuples(&consolidate_simulation_steps(&steps)), vec![(0, 0, 1, 2), (0, 0, 1, AgentCount::MAX)]);
+    }
+
+    #[test]
+    fn sample_and_expand_round_trip() {
+        let steps = [
+            AgentJourney { start_time: 0, start_stop: 0, end_stop: 1, count: 10000 },
+            AgentJourney { start_time: 60, start_stop: 1, end_stop: 0, count: 2000 },
+        ];
+        assert_eq!(step_tuples(&sample_simulation_steps(&steps, 1., 0).unwrap()), step_tuples(&steps));
+
+        let sampled_steps = sample_simulation_steps(&steps, 0.25, 7).unwrap();
+        // The same seed gives the same sample.
+        assert_eq!(step_tuples(&sampled_steps), step_tuples(&sample_simulation_steps(&steps, 0.25, 7).unwrap()));
+        let expanded_steps = expand_simulation_steps(&sampled_steps, 0.25).unwrap();
+        for (expanded, original) in expanded_steps.iter().zip(steps.iter()) {
+            assert_eq!((expanded.start_time, expanded.start_stop, expanded.end_stop), (original.start_time, original.start_stop, original.end_stop));
+            assert!((expanded.count as f64 - original.count as f64).abs() < 0.1 * original.count as f64);
+        }
+
+        let mut simulation_result = SimulationResult { agent_journeys: vec![0, 3, 10], count_issues: Vec::new(), timings: SimulationTimings::default() };
+        expand_simulation_result(&mut simulation_result, 0.4).unwrap();
+        assert_eq!(simulation_result.agent_journeys, vec![0, 8, 25]);
+    }
+
+    #[test]
+    fn expand_splits_steps_that_overflow() {
+        let steps = [AgentJourney { start_time: 0, start_stop: 0, end_stop: 1, count: 40000 }];
+        assert_eq!(step_tuples(&expand_simulation_steps(&steps, 0.5).unwrap()), vec![(0, 0, 1, AgentCount::MAX), (0, 0, 1, (80000 - AgentCount::MAX as u32) as AgentCount)]);
+    }
+
+    #[test]
+    fn sample_fraction_must_be_in_range() {
+        assert!(sample_simulation_steps(&[], 0., 0).is_err());
+        assert!(expand_simulation_steps(&[], 1.5).is_err());
+    }
+
+    fn filter_steps(network: &Network, filter: &DemandFilter) -> Vec<(Timestamp, StopIndex, StopIndex, AgentCount)> {
+        let journey = |start_time, start_stop, end_stop| AgentJourney { start_time, start_stop: stop(network, start_stop), end_stop: stop(network, end_stop), count: 1 };
+        let steps = [
+            journey(7 * 3600 + 59 * 60, "West", "East"),
+            journey(8 * 3600, "West", "East"),
+            journey(8 * 3600 + 59 * 60, "Central", "West"),
+            journey(9 * 3600, "West", "East"),
+            journey(8 * 3600, "West", "South"),
+            journey(8 * 3600, "North", "South"),
+        ];
+        step_tuples(&filter_simulation_steps(network, &steps, filter))
+    }
+
+    #[test]
+    fn filter_keeps_departures_in_window() {
+        let (_, network) = test_network();
+        let filter = DemandFilter { departure_window: Some((8 * 3600, 9 * 3600)), lines: None };
+        let kept = filter_steps(&network, &filter);
+        assert_eq!(kept.len(), 4);
+        assert!(kept.iter().all(|&(start_time, ..)| (8 * 3600..9 * 3600).contains(&start_time)));
+        assert_eq!(filter_steps(&network, &DemandFilter::default()).len(), 6);
+    }
+
+    #[test]
+    fn filter_keeps_ods_on_lines() {
+        let (_, network) = test_network();
+        let line_a = network.routes[trip(&network, "A1").0].line.to_string();
+        let filter = DemandFilter { departure_window: Some((8 * 3600, 9 * 3600)), lines: Some(vec![line_a]) };
+        let kept = filter_steps(&network, &filter);
+        assert_eq!(kept, [(8 * 3600, stop(&network, "West"), stop(&network, "East"), 1), (8 * 3600 + 59 * 60, stop(&network, "Central"), stop(&network, "West"), 1)]);
+        assert!(filter_steps(&network, &DemandFilter { departure_window: None, lines: Some(vec!["C".to_owned()]) }).is_empty());
+    }
+}
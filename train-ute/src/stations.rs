@@ -0,0 +1,106 @@
+use std::collections::HashSet;
+
+use gtfs_structures::Gtfs;
+
+use crate::data_export::DataExportError;
+
+// A platform-level stop that was merged into its parent station.
+pub struct StationMapping {
+    pub stop_id: String,
+    pub stop_name: String,
+    pub station_id: String,
+    pub station_name: String,
+}
+
+// Points every stop time at a platform-level stop to the stop's GTFS parent station instead, so loads aren't split
+// across platforms and OD data matched by station name finds a single stop. Must run before the network is built.
+// Merged platforms are removed from the feed. Returns what was merged, for traceability.
+pub fn collapse_to_parent_stations(gtfs: &mut Gtfs) -> Vec<StationMapping> {
+    let mut mappings = Vec::new();
+    let mut merged_stop_ids = HashSet::new();
+
+    for trip in gtfs.trips.values_mut() {
+        for stop_time in trip.stop_times.iter_mut() {
+            let Some(station) = stop_time.stop.parent_station.as_ref().and_then(|station_id| gtfs.stops.get(station_id)) else {
+                continue;
+            };
+            // Only use stations with a location, so the network still has coordinates for every stop.
+            if station.latitude.is_none() || station.longitude.is_none() {
+                continue;
+            }
+            if merged_stop_ids.insert(stop_time.stop.id.clone()) {
+                mappings.push(StationMapping {
+                    stop_id: stop_time.stop.id.clone(),
+                    stop_name: stop_time.stop.name.clone().unwrap_or_default(),
+                    station_id: station.id.clone(),
+                    station_name: station.name.clone().unwrap_or_default(),
+                });
+            }
+            stop_time.stop = station.clone();
+        }
+    }
+
+    gtfs.stops.retain(|stop_id, _| !merged_stop_ids.contains(stop_id));
+    mappings.sort_by(|a, b| (&a.station_id, &a.stop_id).cmp(&(&b.station_id, &b.stop_id)));
+    mappings
+}
+
+pub fn export_station_mappings(path: &str, mappings: &[StationMapping]) -> Result<(), DataExportError> {
+    let mut csv_writer = csv::Writer::from_path(path)?;
+    csv_writer.write_record(["stop_id", "stop_name", "station_id", "station_name"])?;
+    for mapping in mappings {
+        csv_writer.write_record([&mapping.stop_id, &mapping.stop_name, &mapping.station_id, &mapping.station_name])?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::read_feed;
+
+    // Two platforms of Central, and a platform whose station has no location.
+    const STOPS: &str = "stop_id,stop_name,stop_lat,stop_lon,location_type,parent_station
+W,West,-37.80,144.90,0,
+N,North,-37.78,144.92,0,
+CS,Central,-37.80,144.92,1,
+C1,Central Platform 1,-37.80,144.92,0,CS
+C2,Central Platform 2,-37.80,144.92,0,CS
+XS,Unlocated,,,1,
+X1,Unlocated Platform 1,-37.70,144.92,0,XS
+";
+
+    const ROUTES: &str = "route_id,agency_id,route_short_name,route_long_name,route_type
+A,1,A,Line A,2
+B,1,B,Line B,2
+";
+
+    const TRIPS: &str = "route_id,service_id,trip_id
+A,WD,A1
+B,WD,B1
+";
+
+    const STOP_TIMES: &str = "trip_id,arrival_time,departure_time,stop_id,stop_sequence
+A1,08:00:00,08:00:00,W,1
+A1,08:05:00,08:05:00,C1,2
+A1,08:10:00,08:10:00,X1,3
+B1,08:00:00,08:00:00,N,1
+B1,08:05:00,08:05:00,C2,2
+";
+
+    #[test]
+    fn platforms_merge_into_located_stations() {
+        let mut gtfs = read_feed(&[("stops.txt", STOPS), ("routes.txt", ROUTES), ("trips.txt", TRIPS), ("stop_times.txt", STOP_TIMES)]);
+        let mappings = collapse_to_parent_stations(&mut gtfs);
+
+        let mappings = mappings.iter().map(|mapping| (&*mapping.stop_id, &*mapping.stop_name, &*mapping.station_id, &*mapping.station_name)).collect::<Vec<_>>();
+        assert_eq!(mappings, [("C1", "Central Platform 1", "CS", "Central"), ("C2", "Central Platform 2", "CS", "Central")]);
+
+        let stop_ids = |trip_id: &str| gtfs.trips[trip_id].stop_times.iter().map(|stop_time| stop_time.stop.id.clone()).collect::<Vec<_>>();
+        assert_eq!(stop_ids("A1"), ["W", "CS", "X1"]);
+        assert_eq!(stop_ids("B1"), ["N", "CS"]);
+        assert!(!gtfs.stops.contains_key("C1") && !gtfs.stops.contains_key("C2"));
+        assert!(gtfs.stops.contains_key("CS") && gtfs.stops.contains_key("X1"));
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use serde_json::json;
+
+use raptor::Network;
+use raptor::network::StopIndex;
+
+use crate::data_export::DataExportError;
+use crate::simulation::{PopulationCount, SimulationParams, SimulationResult};
+use crate::utils::load_factor_colour;
+
+// Load summary for one segment (pair of consecutive stops) of a line, over every trip that runs along it in the day.
+pub struct SegmentLoadStats {
+    pub line: String,
+    pub departure_stop: StopIndex,
+    pub arrival_stop: StopIndex,
+    pub num_trips: usize,
+    pub p50_load: PopulationCount,
+    pub p90_load: PopulationCount,
+    pub max_load: PopulationCount,
+    pub p50_load_factor: f32,
+    pub p90_load_factor: f32,
+    pub max_load_factor: f32,
+}
+
+// The single busiest trip segment of a line.
+pub struct PeakSegment {
+    pub line: String,
+    pub route_idx: usize,
+    pub trip_idx: usize,
+    pub stop_order: usize,
+    pub load: PopulationCount,
+    pub load_factor: f32,
+}
+
+// Passenger-kilometres and passenger-hours travelled on one trip.
+pub struct TripKpis {
+    pub route_idx: usize,
+    pub trip_idx: usize,
+    pub passenger_km: f64,
+    pub passenger_hours: f64,
+}
+
+// Nearest-rank percentile of sorted values.
+pub fn percentile<T: Copy>(sorted_values: &[T], percent: usize) -> T {
+    let rank = (percent * sorted_values.len()).div_ceil(100).max(1);
+    sorted_values[rank - 1]
+}
+
+// Nearest-rank percentile of sorted (value, weight) pairs, where each value counts weight times.
+pub fn weighted_percentile<T: Copy>(sorted_values: &[(T, u64)], percent: usize) -> T {
+    let total_weight = sorted_values.iter().map(|&(_, weight)| weight).sum::<u64>();
+    let rank = (percent as u64 * total_weight).div_ceil(100).max(1);
+    let mut cumulative_weight = 0;
+    for &(value, weight) in sorted_values {
+        cumulative_weight += weight;
+        if cumulative_weight >= rank {
+            return value;
+        }
+    }
+    sorted_values[sorted_values.len() - 1].0
+}
+
+// (load, load factor) of every trip along each line segment, keyed by (line, departure stop, arrival stop).
+type SegmentLoads<'a> = HashMap<(&'a str, StopIndex, StopIndex), Vec<(PopulationCount, f32)>>;
+
+// Load statistics of each line segment over every trip along it, pooled across the results of runs with different seeds
+// (or a single result).
+pub fn segment_load_stats<T: SimulationParams>(network: &Network, simulation_results: &[SimulationResult], params: &T) -> Vec<SegmentLoadStats> {
+    // Over every result.
+    let mut segment_loads: SegmentLoads = HashMap::new();
+    for simulation_result in simulation_results {
+        for (route_idx, route) in network.routes.iter().enumerate() {
+            let route_stops = route.get_stops(&network.route_stops);
+            for trip_idx in 0..route.num_trips as usize {
+                let capacity = params.trip_capacity(route_idx, trip_idx);
+                let agent_counts = &simulation_result.agent_journeys[route.get_trip_range(trip_idx)];
+                for (stop_order, stops) in route_stops.windows(2).enumerate() {
+                    let load = agent_counts[stop_order];
+                    segment_loads.entry((&*route.line, stops[0], stops[1])).or_default().push((load, load as f32 / capacity as f32));
+                }
+            }
+        }
+    }
+
+    let mut stats = segment_loads.into_iter().map(|((line, departure_stop, arrival_stop), loads)| {
+        let mut counts = loads.iter().map(|&(load, _)| load).collect::<Vec<_>>();
+        let mut load_factors = loads.iter().map(|&(_, load_factor)| load_factor).collect::<Vec<_>>();
+        counts.sort_unstable();
+        load_factors.sort_unstable_by(f32::total_cmp);
+        SegmentLoadStats {
+            line: line.to_owned(),
+            departure_stop,
+            arrival_stop,
+            num_trips: loads.len() / simulation_results.len(),
+            p50_load: percentile(&counts, 50),
+            p90_load: percentile(&counts, 90),
+            max_load: counts[counts.len() - 1],
+            p50_load_factor: percentile(&load_factors, 50),
+            p90_load_factor: percentile(&load_factors, 90),
+            max_load_factor: load_factors[load_factors.len() - 1],
+        }
+    }).collect::<Vec<_>>();
+    stats.sort_by(|a, b| (&a.line, a.departure_stop, a.arrival_stop).cmp(&(&b.line, b.departure_stop, b.arrival_stop)));
+    stats
+}
+
+// Finds the trip segment with the highest load factor on each line.
+pub fn peak_segments<T: SimulationParams>(network: &Network, simulation_result: &SimulationResult, params: &T) -> Vec<PeakSegment> {
+    let mut peaks: HashMap<&str, PeakSegment> = HashMap::new();
+    for (route_idx, route) in network.routes.iter().enumerate() {
+        for trip_idx in 0..route.num_trips as usize {
+            let capacity = params.trip_capacity(route_idx, trip_idx);
+            let agent_counts = &simulation_result.agent_journeys[route.get_trip_range(trip_idx)];
+            // The count at the last stop is always zero, as no one rides past it.
+            for (stop_order, &load) in agent_counts[..agent_counts.len() - 1].iter().enumerate() {
+                let load_factor = load as f32 / capacity as f32;
+                if peaks.get(&*route.line).is_none_or(|peak| load_factor > peak.load_factor) {
+                    peaks.insert(&*route.line, PeakSegment { line: route.line.to_string(), route_idx, trip_idx, stop_order, load, load_factor });
+                }
+            }
+        }
+    }
+
+    let mut peaks = peaks.into_values().collect::<Vec<_>>();
+    peaks.sort_by(|a, b| a.line.cmp(&b.line));
+    peaks
+}
+
+pub fn export_segment_load_stats(path: &str, network: &Network, stats: &[SegmentLoadStats]) -> Result<(), DataExportError> {
+    let mut csv_writer = csv::Writer::from_path(path)?;
+    csv_writer.write_record(["line", "departure", "arrival", "num_trips", "p50_load", "p90_load", "max_load", "p50_load_factor", "p90_load_factor", "max_load_factor"])?;
+    for segment in stats {
+        csv_writer.write_record(&[
+            segment.line.clone(),
+            network.stops[segment.departure_stop as usize].name.to_string(),
+            network.stops[segment.arrival_stop as usize].name.to_string(),
+            segment.num_trips.to_string(),
+            segment.p50_load.to_string(),
+            segment.p90_load.to_string(),
+            segment.max_load.to_string(),
+            format!("{:.3}", segment.p50_load_factor),
+            format!("{:.3}", segment.p90_load_factor),
+            format!("{:.3}", segment.max_load_factor),
+        ])?;
+    }
+
+    Ok(())
+}
+
+pub fn export_peak_segments(path: &str, network: &Network, peaks: &[PeakSegment]) -> Result<(), DataExportError> {
+    let mut csv_writer = csv::Writer::from_path(path)?;
+    csv_writer.write_record(["line", "trip_id", "departure", "arrival", "departure_time", "load", "load_factor"])?;
+    for peak in peaks {
+        let route = &network.routes[peak.route_idx];
+        let departure_stop = network.get_stop_in_route(peak.route_idx, peak.stop_order) as usize;
+        let arrival_stop = network.get_stop_in_route(peak.route_idx, peak.stop_order + 1) as usize;
+        csv_writer.write_record(&[
+            peak.line.clone(),
+            route.trip_ids[peak.trip_idx].to_string(),
+            network.stops[departure_stop].name.to_string(),
+            network.stops[arrival_stop].name.to_string(),
+            raptor::utils::get_time_str(network.get_departure_time(peak.route_idx, peak.trip_idx, peak.stop_order)),
+            peak.load.to_string(),
+            format!("{:.3}", peak.load_factor),
+        ])?;
+    }
+
+    Ok(())
+}
+
+// Escapes text for an XML element.
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+// Exports segment load statistics as KML for Google Earth, one line per segment (straight between the stops), coloured
+// by p90 load factor and wider as the p90 load grows.
+pub fn export_segment_loads_kml(path: &str, network: &Network, stats: &[SegmentLoadStats]) -> Result<(), DataExportError> {
+    const MIN_WIDTH: f32 = 1.;
+    const MAX_WIDTH: f32 = 12.;
+
+    let max_load = stats.iter().map(|segment| segment.p90_load).max().unwrap_or(0).max(1);
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(writer, r#"<kml xmlns="http://www.opengis.net/kml/2.2"><Document><name>Segment loads</name>"#)?;
+    for segment in stats {
+        let departure_name = &*network.stops[segment.departure_stop as usize].name;
+        let arrival_name = &*network.stops[segment.arrival_stop as usize].name;
+        let departure_point = network.stop_points[segment.departure_stop as usize];
+        let arrival_point = network.stop_points[segment.arrival_stop as usize];
+        // KML colours are aabbggrr.
+        let colour = load_factor_colour(segment.p90_load_factor);
+        let width = MIN_WIDTH + (MAX_WIDTH - MIN_WIDTH) * segment.p90_load as f32 / max_load as f32;
+
+        writeln!(writer, "<Placemark><name>{} {} - {}</name>", xml_escape(&segment.line), xml_escape(departure_name), xml_escape(arrival_name))?;
+        writeln!(writer, "<description>{} trips, p90 load {} ({:.2} of capacity), max load {}</description>", segment.num_trips, segment.p90_load, segment.p90_load_factor, segment.max_load)?;
+        writeln!(writer, "<Style><LineStyle><color>ff{:02x}{:02x}{:02x}</color><width>{width:.1}</width></LineStyle></Style>", colour.b, colour.g, colour.r)?;
+        writeln!(writer, "<LineString><coordinates>{},{} {},{}</coordinates></LineString></Placemark>", departure_point.longitude, departure_point.latitude, arrival_point.longitude, arrival_point.latitude)?;
+    }
+    writeln!(writer, "</Document></kml>")?;
+
+    Ok(())
+}
+
+// Exports segment loads as a GeoJSON FeatureCollection of lines between stops, with the load statistics as properties,
+// for GIS tools that don't read KML.
+pub fn export_segment_loads_geojson(path: &str, network: &Network, stats: &[SegmentLoadStats]) -> Result<(), DataExportError> {
+    let features = stats.iter().map(|segment| {
+        let departure_point = network.stop_points[segment.departure_stop as usize];
+        let arrival_point = network.stop_points[segment.arrival_stop as usize];
+        json!({
+            "type": "Feature",
+            "geometry": {
+                "type": "LineString",
+                "coordinates": [[departure_point.longitude, departure_point.latitude], [arrival_point.longitude, arrival_point.latitude]],
+            },
+            "properties": {
+                "line": segment.line,
+                "departure_stop": &*network.stops[segment.departure_stop as usize].name,
+                "arrival_stop": &*network.stops[segment.arrival_stop as usize].name,
+                "num_trips": segment.num_trips,
+                "p50_load": segment.p50_load,
+                "p90_load": segment.p90_load,
+                "max_load": segment.max_load,
+                "p50_load_factor": segment.p50_load_factor,
+                "p90_load_factor": segment.p90_load_factor,
+                "max_load_factor": segment.max_load_factor,
+            },
+        })
+    }).collect::<Vec<_>>();
+    serde_json::to_writer(BufWriter::new(File::create(path)?), &json!({ "type": "FeatureCollection", "features": features }))?;
+
+    Ok(())
+}
+
+// Passenger-km and passenger-hours of every trip, from the load on each segment, its length (see
+// utils::route_segment_lengths) and its running time.
+pub fn trip_kpis(network: &Network, simulation_result: &SimulationResult, segment_lengths: &[Vec<f32>]) -> Vec<TripKpis> {
+    let mut kpis = Vec::new();
+    for (route_idx, route) in network.routes.iter().enumerate() {
+        for trip_idx in 0..route.num_trips as usize {
+            let agent_counts = &simulation_result.agent_journeys[route.get_trip_range(trip_idx)];
+            let mut passenger_km = 0.;
+            let mut passenger_hours = 0.;
+            for (stop_order, &length) in segment_lengths[route_idx].iter().enumerate() {
+                let load = agent_counts[stop_order] as f64;
+                let running_time = network.get_arrival_time(route_idx, trip_idx, stop_order + 1) - network.get_departure_time(route_idx, trip_idx, stop_order);
+                passenger_km += load * length as f64 / 1000.;
+                passenger_hours += load * running_time as f64 / 3600.;
+            }
+            kpis.push(TripKpis { route_idx, trip_idx, passenger_km, passenger_hours });
+        }
+    }
+    kpis
+}
+
+// Exports passenger-km and passenger-hours per trip, per line and for the whole network, with a level column to tell
+// them apart.
+pub fn export_passenger_kpis(path: &str, network: &Network, kpis: &[TripKpis]) -> Result<(), DataExportError> {
+    let mut line_totals: HashMap<&str, (f64, f64)> = HashMap::new();
+    let mut network_total = (0., 0.);
+    for trip in kpis {
+        let line_total = line_totals.entry(&*network.routes[trip.route_idx].line).or_default();
+        line_total.0 += trip.passenger_km;
+        line_total.1 += trip.passenger_hours;
+        network_total.0 += trip.passenger_km;
+        network_total.1 += trip.passenger_hours;
+    }
+    let mut line_totals = line_totals.into_iter().collect::<Vec<_>>();
+    line_totals.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut csv_writer = csv::Writer::from_path(path)?;
+    csv_writer.write_record(["level", "line", "trip_id", "passenger_km", "passenger_hours"])?;
+    csv_writer.write_record(["network", "", "", &format!("{:.1}", network_total.0), &format!("{:.1}", network_total.1)])?;
+    for (line, (passenger_km, passenger_hours)) in line_totals {
+        csv_writer.write_record(["line", line, "", &format!("{passenger_km:.1}"), &format!("{passenger_hours:.1}")])?;
+    }
+    for trip in kpis {
+        let route = &network.routes[trip.route_idx];
+        csv_writer.write_record(["trip", &*route.line, &*route.trip_ids[trip.trip_idx], &format!("{:.1}", trip.passenger_km), &format!("{:.1}", trip.passenger_hours)])?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentile_is_nearest_rank() {
+        let values = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        assert_eq!(percentile(&values, 0), 1);
+        assert_eq!(percentile(&values, 50), 5);
+        assert_eq!(percentile(&values, 90), 9);
+        assert_eq!(percentile(&values, 100), 10);
+    }
+
+    #[test]
+    fn weighted_percentile_counts_weights() {
+        let values = [(10, 4), (20, 0), (30, 5), (40, 1)];
+        assert_eq!(weighted_percentile(&values, 40), 10);
+        assert_eq!(weighted_percentile(&values, 50), 30);
+        assert_eq!(weighted_percentile(&values, 90), 30);
+        assert_eq!(weighted_percentile(&values, 100), 40);
+    }
+}
@@ -0,0 +1,54 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use gtfs_structures::Gtfs;
+
+// Cuts a GTFS feed down to the selected routes (matched by route id, short name or long name), their trips, the stops
+// they call at (and those stops' parent stations) and the transfers between kept stops. Must run before the network is
+// built. Odd behaviour on one line can then be reproduced on a small network in a fraction of the time.
+// Returns the ids of the kept routes.
+pub fn extract_sub_network(gtfs: &mut Gtfs, routes: &[&str]) -> Vec<String> {
+    let is_selected = |name: Option<&str>| name.is_some_and(|name| routes.contains(&name));
+    let mut route_ids = gtfs.routes.values()
+        .filter(|route| routes.contains(&route.id.as_str()) || is_selected(route.short_name.as_deref()) || is_selected(route.long_name.as_deref()))
+        .map(|route| route.id.clone())
+        .collect::<Vec<_>>();
+    route_ids.sort();
+    let route_id_set = route_ids.iter().cloned().collect::<HashSet<_>>();
+
+    gtfs.routes.retain(|route_id, _| route_id_set.contains(route_id));
+    gtfs.trips.retain(|_, trip| route_id_set.contains(&trip.route_id));
+
+    let mut stop_ids = HashSet::new();
+    for trip in gtfs.trips.values() {
+        for stop_time in trip.stop_times.iter() {
+            stop_ids.insert(stop_time.stop.id.clone());
+            if let Some(station_id) = stop_time.stop.parent_station.as_ref() {
+                stop_ids.insert(station_id.clone());
+            }
+        }
+    }
+    gtfs.stops.retain(|stop_id, _| stop_ids.contains(stop_id));
+
+    // Stop times share their stop with the feed, so they are pointed at the trimmed copies.
+    let mut trimmed_stops = HashMap::new();
+    for (stop_id, stop) in gtfs.stops.iter_mut() {
+        let mut trimmed_stop = (**stop).clone();
+        trimmed_stop.transfers.retain(|transfer| stop_ids.contains(&transfer.to_stop_id));
+        *stop = Arc::new(trimmed_stop);
+        trimmed_stops.insert(stop_id.clone(), stop.clone());
+    }
+    for trip in gtfs.trips.values_mut() {
+        for stop_time in trip.stop_times.iter_mut() {
+            stop_time.stop = trimmed_stops[&stop_time.stop.id].clone();
+        }
+    }
+
+    let service_ids = gtfs.trips.values().map(|trip| trip.service_id.clone()).collect::<HashSet<_>>();
+    gtfs.calendar.retain(|service_id, _| service_ids.contains(service_id));
+    gtfs.calendar_dates.retain(|service_id, _| service_ids.contains(service_id));
+    let shape_ids = gtfs.trips.values().filter_map(|trip| trip.shape_id.clone()).collect::<HashSet<_>>();
+    gtfs.shapes.retain(|shape_id, _| shape_ids.contains(shape_id));
+
+    route_ids
+}
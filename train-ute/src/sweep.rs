@@ -0,0 +1,46 @@
+use raptor::Network;
+
+use crate::data_export::DataExportError;
+use crate::simulation::{crowding_costs, CrowdingCost, SimulationParams, SimulationResult};
+
+// Aggregate crowding costs of a run under one parameter value in a sweep.
+pub struct SweepPoint {
+    pub value: f64,
+    // Crowding cost summed over every agent-segment.
+    pub total_crowding_cost: f64,
+    // Crowding cost averaged over every agent-segment.
+    pub mean_crowding_cost: CrowdingCost,
+    // Highest crowding cost of any trip stop.
+    pub max_crowding_cost: CrowdingCost,
+}
+
+// Works out the crowding costs of a simulation result under each parameter value, with the parameters built for each
+// value by make_params. This is a cost-only sweep: route choice in a single simulation pass uses zero crowding cost,
+// so loads don't respond to crowding parameters and the run's loads are used for every value.
+pub fn run_cost_sweep<T: SimulationParams>(network: &Network, simulation_result: &SimulationResult, values: &[f64], make_params: impl Fn(f64) -> T) -> Vec<SweepPoint> {
+    let total_load = simulation_result.agent_journeys.iter().map(|&count| count as i64).sum::<i64>();
+    values.iter().map(|&value| {
+        let params = make_params(value);
+        let trip_stops_cost = crowding_costs(network, simulation_result, &params);
+
+        let mut total_crowding_cost = 0f64;
+        let mut max_crowding_cost = 0 as CrowdingCost;
+        for (&count, &cost) in simulation_result.agent_journeys.iter().zip(trip_stops_cost.iter()) {
+            total_crowding_cost += count as f64 * cost as f64;
+            max_crowding_cost = max_crowding_cost.max(cost);
+        }
+        let mean_crowding_cost = if total_load > 0 { (total_crowding_cost / total_load as f64) as CrowdingCost } else { 0. };
+
+        SweepPoint { value, total_crowding_cost, mean_crowding_cost, max_crowding_cost }
+    }).collect()
+}
+
+pub fn export_sweep_csv(path: &str, parameter_name: &str, sweep: &[SweepPoint]) -> Result<(), DataExportError> {
+    let mut csv_writer = csv::Writer::from_path(path)?;
+    csv_writer.write_record([parameter_name, "total_crowding_cost", "mean_crowding_cost", "max_crowding_cost"])?;
+    for point in sweep {
+        csv_writer.write_record([point.value.to_string(), point.total_crowding_cost.to_string(), point.mean_crowding_cost.to_string(), point.max_crowding_cost.to_string()])?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,106 @@
+// A small network for unit tests, built from a GTFS feed written to a temporary directory.
+// Two lines cross at Central, with 5 minutes between stops:
+// - Line A runs West, Central, East, with trips A1 at 08:00 and A2 at 08:10 from West.
+// - Line B runs North, Central, South, with trip B1 at 08:12 from North (dwelling a minute at Central).
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use chrono::NaiveDate;
+use gtfs_structures::{Gtfs, GtfsReader};
+
+use raptor::Network;
+use raptor::network::StopIndex;
+
+use crate::simulation::JourneyLeg;
+
+// A Monday, within the calendar of the test feed.
+pub const TEST_DATE: NaiveDate = NaiveDate::from_ymd_opt(2024, 6, 3).unwrap();
+
+const AGENCY: &str = "agency_id,agency_name,agency_url,agency_timezone
+1,Test,https://example.com,Australia/Melbourne
+";
+
+const CALENDAR: &str = "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date
+WD,1,1,1,1,1,1,1,20240101,20241231
+";
+
+const STOPS: &str = "stop_id,stop_name,stop_lat,stop_lon
+W,West,-37.80,144.90
+C,Central,-37.80,144.92
+E,East,-37.80,144.94
+N,North,-37.78,144.92
+S,South,-37.82,144.92
+";
+
+const ROUTES: &str = "route_id,agency_id,route_short_name,route_long_name,route_type
+A,1,A,Line A,2
+B,1,B,Line B,2
+";
+
+const TRIPS: &str = "route_id,service_id,trip_id
+A,WD,A1
+A,WD,A2
+B,WD,B1
+";
+
+const STOP_TIMES: &str = "trip_id,arrival_time,departure_time,stop_id,stop_sequence
+A1,08:00:00,08:00:00,W,1
+A1,08:05:00,08:05:00,C,2
+A1,08:10:00,08:10:00,E,3
+A2,08:10:00,08:10:00,W,1
+A2,08:15:00,08:15:00,C,2
+A2,08:20:00,08:20:00,E,3
+B1,08:12:00,08:12:00,N,1
+B1,08:17:00,08:18:00,C,2
+B1,08:23:00,08:23:00,S,3
+";
+
+// A new, empty temporary directory. Tests run in parallel, so each call gets its own directory.
+pub fn temp_dir() -> PathBuf {
+    static NUM_DIRS: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!("train-ute-test-{}-{}", std::process::id(), NUM_DIRS.fetch_add(1, Ordering::Relaxed)));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Reads a feed from the given files (name, contents), with the agency and calendar of the test feed added.
+pub fn read_feed(files: &[(&str, &str)]) -> Gtfs {
+    let dir = temp_dir();
+    for (name, contents) in [("agency.txt", AGENCY), ("calendar.txt", CALENDAR)].iter().chain(files) {
+        std::fs::write(dir.join(name), contents).unwrap();
+    }
+    let gtfs = GtfsReader::default().read(dir.to_str().unwrap());
+    std::fs::remove_dir_all(&dir).unwrap();
+    gtfs.unwrap()
+}
+
+pub fn test_gtfs() -> Gtfs {
+    read_feed(&[("stops.txt", STOPS), ("routes.txt", ROUTES), ("trips.txt", TRIPS), ("stop_times.txt", STOP_TIMES)])
+}
+
+pub fn test_network() -> (Gtfs, Network) {
+    let gtfs = test_gtfs();
+    let mut network = Network::new(&gtfs, TEST_DATE, 3 * 60);
+    network.build_connections();
+    (gtfs, network)
+}
+
+pub fn stop(network: &Network, name: &str) -> StopIndex {
+    network.get_stop_idx_from_name(name).unwrap()
+}
+
+// The (route, trip) index of a trip in the network.
+pub fn trip(network: &Network, trip_id: &str) -> (usize, usize) {
+    network.routes.iter().enumerate().find_map(|(route_idx, route)| {
+        route.trip_ids.iter().position(|id| &**id == trip_id).map(|trip_idx| (route_idx, trip_idx))
+    }).unwrap()
+}
+
+// A leg riding a trip between two named stops.
+pub fn leg(network: &Network, trip_id: &str, board_stop: &str, alight_stop: &str) -> JourneyLeg {
+    let (route_idx, trip_idx) = trip(network, trip_id);
+    let route_stops = network.routes[route_idx].get_stops(&network.route_stops);
+    let stop_order = |name| route_stops.iter().position(|&stop_idx| stop_idx == stop(network, name)).unwrap();
+    JourneyLeg { route_idx, trip_idx, boarded_stop_order: stop_order(board_stop), arrival_stop_order: stop_order(alight_stop) }
+}
@@ -1,5 +1,9 @@
+use gtfs_structures::Gtfs;
 use rgb::RGB8;
 
+use raptor::Network;
+use raptor::network::{NetworkPoint, Route};
+
 pub fn mix_rgb(a: RGB8, b: RGB8, t: f32) -> RGB8 {
     RGB8 {
         r: (a.r as f32 * (1. - t) + b.r as f32 * t) as u8,
@@ -8,6 +12,25 @@ pub fn mix_rgb(a: RGB8, b: RGB8, t: f32) -> RGB8 {
     }
 }
 
+// Load factors (agents / capacity) at which all seats are taken, and at which the vehicle is at crush load.
+// Capacities are crush capacities, so crush load is a load factor of one.
+pub const SEATED_LOAD_FACTOR: f32 = 1. / 3.;
+pub const CRUSH_LOAD_FACTOR: f32 = 1.;
+
+// Colour for a load factor: blue when empty, green at seated load and red at crush load (and above).
+pub fn load_factor_colour(load_factor: f32) -> RGB8 {
+    const EMPTY_COLOUR: RGB8 = RGB8 { r: 0, g: 0, b: 255 };
+    const SEATED_COLOUR: RGB8 = RGB8 { r: 0, g: 255, b: 0 };
+    const CRUSH_COLOUR: RGB8 = RGB8 { r: 255, g: 0, b: 0 };
+
+    if load_factor < SEATED_LOAD_FACTOR {
+        mix_rgb(EMPTY_COLOUR, SEATED_COLOUR, load_factor.max(0.) / SEATED_LOAD_FACTOR)
+    } else {
+        let t = (load_factor - SEATED_LOAD_FACTOR) / (CRUSH_LOAD_FACTOR - SEATED_LOAD_FACTOR);
+        mix_rgb(SEATED_COLOUR, CRUSH_COLOUR, t.min(1.))
+    }
+}
+
 // Simple quadratic easing.
 pub fn quadratic_ease_in_out(t: f32) -> f32 {
     if t < 0.5 {
@@ -26,3 +49,366 @@ pub fn quadratic_inv_ease_in_out(t: f32) -> f32 {
     }
 }
 
+
+// Gives routes with no shape an approximate one made of straight lines between their stops, so every route has a shape.
+// Returns whether each route was given an approximate shape.
+pub fn gen_fallback_shapes(network: &mut Network) -> Vec<bool> {
+    let approximate_shapes = network.routes.iter_mut().map(|route| {
+        let approximate = route.shape.is_empty();
+        if approximate {
+            route.shape = route.get_stops(&network.route_stops).iter().map(|&stop_idx| network.stop_points[stop_idx as usize]).collect();
+        }
+        approximate
+    }).collect();
+    network.has_shapes = true;
+    approximate_shapes
+}
+
+// Converts a colour from HSV (hue in degrees, saturation and value from 0 to 1) to RGB.
+pub fn hsv_to_rgb(hue: f32, saturation: f32, value: f32) -> RGB8 {
+    let chroma = value * saturation;
+    let hue_sector = hue.rem_euclid(360.) / 60.;
+    let x = chroma * (1. - (hue_sector % 2. - 1.).abs());
+    let (r, g, b) = match hue_sector as u32 {
+        0 => (chroma, x, 0.),
+        1 => (x, chroma, 0.),
+        2 => (0., chroma, x),
+        3 => (0., x, chroma),
+        4 => (x, 0., chroma),
+        _ => (chroma, 0., x),
+    };
+    let m = value - chroma;
+    RGB8 {
+        r: ((r + m) * 255.).round() as u8,
+        g: ((g + m) * 255.).round() as u8,
+        b: ((b + m) * 255.).round() as u8,
+    }
+}
+
+// A distinguishable colour derived from an id, the same every run.
+pub fn id_colour(id: &str) -> RGB8 {
+    // FNV-1a, as std's hasher isn't guaranteed to be stable between releases.
+    let hash = id.bytes().fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+    // Space hues out by the golden angle, with a few saturation/value levels so neighbouring hues still differ.
+    const NUM_HUES: u64 = 16;
+    let hue = (hash % NUM_HUES) as f32 * 137.508;
+    let (saturation, value) = [(0.75, 0.9), (0.55, 0.75), (0.9, 0.65)][((hash / NUM_HUES) % 3) as usize];
+    hsv_to_rgb(hue, saturation, value)
+}
+
+// Gives routes with no GTFS route_color a generated colour, so lines can be told apart.
+// Returns the number of routes that were given a generated colour.
+pub fn gen_route_colours(network: &mut Network, gtfs: &Gtfs) -> usize {
+    // GTFS defaults route_color to white when it's missing.
+    const DEFAULT_COLOUR: RGB8 = RGB8 { r: 255, g: 255, b: 255 };
+
+    let mut num_generated = 0;
+    for route in network.routes.iter_mut() {
+        let Some(gtfs_route) = route.trip_ids.first()
+            .and_then(|trip_id| gtfs.trips.get::<str>(trip_id))
+            .and_then(|gtfs_trip| gtfs.routes.get(&gtfs_trip.route_id)) else {
+            continue;
+        };
+        if gtfs_route.color == DEFAULT_COLOUR {
+            route.colour = id_colour(&gtfs_route.id);
+            num_generated += 1;
+        }
+    }
+    num_generated
+}
+
+// Projects points onto a local plane in metres around an origin, which is accurate enough over the length of a route.
+pub fn project_to_plane(points: &[NetworkPoint], origin: NetworkPoint) -> Vec<(f32, f32)> {
+    const METRES_PER_DEGREE: f32 = 111_320.;
+    let longitude_scale = METRES_PER_DEGREE * origin.latitude.to_radians().cos();
+    points.iter().map(|point| {
+        ((point.longitude - origin.longitude) * longitude_scale, (point.latitude - origin.latitude) * METRES_PER_DEGREE)
+    }).collect()
+}
+
+// Distance from a point to the segment between a and b, in a plane.
+pub fn point_segment_distance(point: (f32, f32), a: (f32, f32), b: (f32, f32)) -> f32 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let length_sq = dx * dx + dy * dy;
+    let t = if length_sq > 0. {
+        (((point.0 - a.0) * dx + (point.1 - a.1) * dy) / length_sq).clamp(0., 1.)
+    } else {
+        0.
+    };
+    let (px, py) = (a.0 + t * dx - point.0, a.1 + t * dy - point.1);
+    (px * px + py * py).sqrt()
+}
+
+// Douglas-Peucker line simplification. Returns which points to keep so the simplified line stays within
+// the tolerance (in metres) of the original. The first and last points are always kept.
+pub fn douglas_peucker(points: &[NetworkPoint], tolerance: f32) -> Vec<bool> {
+    let mut keep = vec![true; points.len()];
+    if points.len() <= 2 {
+        return keep;
+    }
+    keep[1..points.len() - 1].fill(false);
+
+    let planar = project_to_plane(points, points[0]);
+
+    // Recursion is done with an explicit stack, as shapes can have many thousands of points.
+    let mut stack = vec![(0, points.len() - 1)];
+    while let Some((start, end)) = stack.pop() {
+        let mut max_distance = 0.;
+        let mut max_idx = start;
+        for i in start + 1..end {
+            let distance = point_segment_distance(planar[i], planar[start], planar[end]);
+            if distance > max_distance {
+                max_distance = distance;
+                max_idx = i;
+            }
+        }
+
+        if max_distance > tolerance {
+            keep[max_idx] = true;
+            stack.push((start, max_idx));
+            stack.push((max_idx, end));
+        }
+    }
+
+    keep
+}
+
+// Where a stop lies along a shape.
+pub struct ShapeMatch {
+    // The shape point nearest to where the stop projects onto the shape.
+    pub shape_idx: usize,
+    // Distance from the stop to the shape (metres).
+    pub distance: f32,
+}
+
+// Projects each stop in order onto the nearest segment of the shape at or after the last match within the tolerance.
+// So that a stop isn't matched to a later pass of the shape (e.g. on a loop), the search for each stop ends
+// once the shape has come within the tolerance of the stop and moved away again. A stop that's off the shape doesn't
+// move the search on, so it can't drag later stops past where they are.
+pub fn match_stops_to_shape(shape: &[NetworkPoint], stop_points: &[NetworkPoint], tolerance: f32) -> Vec<ShapeMatch> {
+    if shape.is_empty() {
+        return stop_points.iter().map(|_| ShapeMatch { shape_idx: 0, distance: f32::INFINITY }).collect();
+    }
+
+    let planar_shape = project_to_plane(shape, shape[0]);
+    let planar_stops = project_to_plane(stop_points, shape[0]);
+
+    let mut matches = Vec::with_capacity(stop_points.len());
+    let mut start_idx = 0;
+    for &stop in planar_stops.iter() {
+        let mut best = ShapeMatch { shape_idx: start_idx, distance: f32::INFINITY };
+        let mut best_segment_idx = start_idx;
+        // A single point shape has no segments, so treat it as a zero-length segment.
+        let last_segment = planar_shape.len().saturating_sub(2);
+        for segment_idx in start_idx..=last_segment {
+            let a = planar_shape[segment_idx];
+            let b = planar_shape[(segment_idx + 1).min(planar_shape.len() - 1)];
+            let distance = point_segment_distance(stop, a, b);
+            if distance < best.distance {
+                // Snap to whichever end of the segment is closer.
+                let distance_a = point_segment_distance(stop, a, a);
+                let distance_b = point_segment_distance(stop, b, b);
+                let shape_idx = if distance_b < distance_a { (segment_idx + 1).min(planar_shape.len() - 1) } else { segment_idx };
+                best = ShapeMatch { shape_idx, distance };
+                best_segment_idx = segment_idx;
+            } else if best.distance <= tolerance && distance > tolerance {
+                break;
+            }
+        }
+        // The next stop can still be on the segment this stop was projected onto.
+        if best.distance <= tolerance {
+            start_idx = best_segment_idx;
+        }
+        matches.push(best);
+    }
+    matches
+}
+
+// Matches stops to a shape by GTFS shape_dist_traveled, taking the shape point whose distance along the shape is
+// nearest each stop's. Both sets of distances must be increasing, and the shape can't be empty.
+fn match_stops_by_distance(shape: &[NetworkPoint], shape_distances: &[f32], stop_points: &[NetworkPoint], stop_distances: &[f32]) -> Vec<ShapeMatch> {
+    stop_points.iter().zip(stop_distances).map(|(&stop_point, &stop_distance)| {
+        let next_idx = shape_distances.partition_point(|&distance| distance < stop_distance).min(shape.len() - 1);
+        let shape_idx = if next_idx > 0 && stop_distance - shape_distances[next_idx - 1] < shape_distances[next_idx] - stop_distance {
+            next_idx - 1
+        } else {
+            next_idx
+        };
+        ShapeMatch { shape_idx, distance: shape[shape_idx].distance(stop_point) }
+    }).collect()
+}
+
+// The shape_dist_traveled of each point of a route's shape and of each of its stops, from the route's first trip.
+// None unless the feed gives every distance and the route's shape is that trip's GTFS shape.
+fn gtfs_shape_distances(gtfs: &Gtfs, route: &Route) -> Option<(Vec<f32>, Vec<f32>)> {
+    let gtfs_trip = gtfs.trips.get::<str>(route.trip_ids.first()?)?;
+    let gtfs_shape = gtfs.shapes.get(gtfs_trip.shape_id.as_ref()?)?;
+    if route.shape.is_empty() || gtfs_shape.len() != route.shape.len() || gtfs_trip.stop_times.len() != route.num_stops as usize {
+        return None;
+    }
+    let shape_distances = gtfs_shape.iter().map(|point| point.dist_traveled).collect::<Option<Vec<_>>>()?;
+    let stop_distances = gtfs_trip.stop_times.iter().map(|stop_time| stop_time.shape_dist_traveled).collect::<Option<Vec<_>>>()?;
+    Some((shape_distances, stop_distances))
+}
+
+// Where each route's stops lie along its shape. This uses the feed's shape_dist_traveled where it has it, as
+// projection can pick the wrong pass of a shape that doubles back, otherwise it projects the stops onto the shape.
+pub fn route_shape_matches(network: &Network, gtfs: &Gtfs, tolerance: f32) -> Vec<Vec<ShapeMatch>> {
+    network.routes.iter().map(|route| {
+        let route_stop_points = route.get_stops(&network.route_stops).iter().map(|&stop_idx| network.stop_points[stop_idx as usize]).collect::<Vec<_>>();
+        match gtfs_shape_distances(gtfs, route) {
+            Some((shape_distances, stop_distances)) => match_stops_by_distance(&route.shape, &shape_distances, &route_stop_points, &stop_distances),
+            None => match_stops_to_shape(&route.shape, &route_stop_points, tolerance),
+        }
+    }).collect()
+}
+
+// Matches text against a glob pattern, where * matches any run of characters and ? matches a single character.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+
+    let (mut p, mut t) = (0, 0);
+    // Position of the last * seen, and the text position it was matched up to, for backtracking.
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            // Let the last * match one more character.
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    // Any remaining pattern must be *s.
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// Length in metres of each segment (stop to next stop) of each route, along the route's shape where the stops match it,
+// otherwise in a straight line.
+pub fn route_segment_lengths(network: &Network, gtfs: &Gtfs, tolerance: f32) -> Vec<Vec<f32>> {
+    network.routes.iter().zip(route_shape_matches(network, gtfs, tolerance)).map(|(route, shape_matches)| {
+        let route_stop_points = route.get_stops(&network.route_stops).iter().map(|&stop_idx| network.stop_points[stop_idx as usize]).collect::<Vec<_>>();
+        route_stop_points.windows(2).zip(shape_matches.windows(2)).map(|(stop_points, shape_matches)| {
+            let start_shape_idx = shape_matches[0].shape_idx;
+            let end_shape_idx = shape_matches[1].shape_idx;
+            if start_shape_idx < end_shape_idx {
+                route.shape[start_shape_idx..=end_shape_idx].windows(2).map(|points| points[0].distance(points[1])).sum::<f32>()
+            } else {
+                stop_points[0].distance(stop_points[1])
+            }
+        }).collect()
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A point offset in degrees from a base in Melbourne.
+    fn point(latitude_offset: f32, longitude_offset: f32) -> NetworkPoint {
+        NetworkPoint { latitude: -37.8 + latitude_offset, longitude: 144.9 + longitude_offset, height: 0. }
+    }
+
+    #[test]
+    fn douglas_peucker_keeps_short_lines() {
+        assert_eq!(douglas_peucker(&[], 10.), Vec::<bool>::new());
+        assert_eq!(douglas_peucker(&[point(0., 0.), point(0., 0.001)], 10.), vec![true, true]);
+    }
+
+    #[test]
+    fn douglas_peucker_drops_collinear_points() {
+        let points = [point(0., 0.), point(0., 0.001), point(0., 0.002), point(0., 0.003)];
+        assert_eq!(douglas_peucker(&points, 1.), vec![true, false, false, true]);
+    }
+
+    #[test]
+    fn douglas_peucker_keeps_corners_outside_tolerance() {
+        // An L shape, with the corner about 140 m from the line between the ends.
+        let points = [point(0., 0.), point(0., 0.001), point(0., 0.002), point(0.001, 0.002), point(0.002, 0.002)];
+        assert_eq!(douglas_peucker(&points, 1.), vec![true, false, true, false, true]);
+        assert_eq!(douglas_peucker(&points, 1000.), vec![true, false, false, false, true]);
+    }
+
+    fn shape_indices(matches: &[ShapeMatch]) -> Vec<usize> {
+        matches.iter().map(|shape_match| shape_match.shape_idx).collect()
+    }
+
+    #[test]
+    fn match_stops_to_shape_snaps_to_nearest_point() {
+        // Shape points are about 90 m apart, and the stops about 10 m off the shape.
+        let shape = (0..5).map(|i| point(0., i as f32 * 0.001)).collect::<Vec<_>>();
+        let stops = [point(0.0001, 0.0009), point(-0.0001, 0.0031)];
+        let matches = match_stops_to_shape(&shape, &stops, 50.);
+        assert_eq!(shape_indices(&matches), vec![1, 3]);
+        assert!(matches.iter().all(|shape_match| (shape_match.distance - 11.).abs() < 1.));
+    }
+
+    #[test]
+    fn match_stops_to_shape_follows_loops_in_order() {
+        // Out and back along the same street, so the last stop must match the return pass.
+        let shape = [0., 1., 2., 3., 2., 1., 0.].map(|i| point(0., i * 0.001));
+        let stops = [point(0., 0.001), point(0., 0.003), point(0., 0.001)];
+        assert_eq!(shape_indices(&match_stops_to_shape(&shape, &stops, 50.)), vec![1, 3, 5]);
+    }
+
+    #[test]
+    fn match_stops_to_shape_skips_stops_off_the_shape() {
+        let shape = (0..5).map(|i| point(0., i as f32 * 0.001)).collect::<Vec<_>>();
+        let stops = [point(0., 0.001), point(0.01, 0.004), point(0., 0.002)];
+        let matches = match_stops_to_shape(&shape, &stops, 50.);
+        assert!(matches[1].distance > 50.);
+        assert_eq!(matches[2].shape_idx, 2);
+        assert!(matches[2].distance < 1.);
+    }
+
+    #[test]
+    fn match_stops_to_shape_without_shape() {
+        let matches = match_stops_to_shape(&[], &[point(0., 0.)], 50.);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].distance, f32::INFINITY);
+    }
+
+    #[test]
+    fn glob_match_wildcards() {
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "a"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("Craigieburn*", "Craigieburn Line"));
+        assert!(glob_match("*Line", "Craigieburn Line"));
+        assert!(!glob_match("*Line", "Craigieburn Line 2"));
+        assert!(glob_match("a?c", "abc"));
+        assert!(!glob_match("a?c", "ac"));
+        assert!(glob_match("a**", "a"));
+    }
+
+    #[test]
+    fn glob_match_backtracks() {
+        assert!(glob_match("*a*b", "xaxxb"));
+        assert!(glob_match("*ab", "aab"));
+        assert!(!glob_match("*a*b", "xaxxbc"));
+    }
+
+    #[test]
+    fn hsv_to_rgb_primaries() {
+        assert_eq!(hsv_to_rgb(0., 1., 1.), RGB8 { r: 255, g: 0, b: 0 });
+        assert_eq!(hsv_to_rgb(60., 1., 1.), RGB8 { r: 255, g: 255, b: 0 });
+        assert_eq!(hsv_to_rgb(120., 1., 1.), RGB8 { r: 0, g: 255, b: 0 });
+        assert_eq!(hsv_to_rgb(240., 1., 1.), RGB8 { r: 0, g: 0, b: 255 });
+        assert_eq!(hsv_to_rgb(300., 1., 1.), RGB8 { r: 255, g: 0, b: 255 });
+    }
+
+    #[test]
+    fn hsv_to_rgb_wraps_hue_and_handles_grey() {
+        assert_eq!(hsv_to_rgb(360., 1., 1.), hsv_to_rgb(0., 1., 1.));
+        assert_eq!(hsv_to_rgb(-120., 1., 1.), hsv_to_rgb(240., 1., 1.));
+        assert_eq!(hsv_to_rgb(200., 0., 0.5), RGB8 { r: 128, g: 128, b: 128 });
+    }
+}